@@ -1,11 +1,24 @@
 use std::{
     env::args,
+    path::PathBuf,
     time::{Duration, Instant},
 };
 
-use neatworks::{pbft::PublicParameters, workload::events::Invoke};
-use tokio::{select, time::sleep};
-use workload::util::run_until;
+use neatworks::{
+    event::{
+        combinators::journal::DEFAULT_CAPACITY,
+        task::{
+            metrics::{serve, Metrics},
+            Shutdown,
+        },
+    },
+    pbft::PublicParameters,
+    workload::events::Invoke,
+};
+use rand::random;
+use tokio::{net::TcpListener, time::sleep, try_join};
+use tracing::{error, info, info_span, Instrument as _};
+use workload::util::{init_logging, run_until};
 
 pub mod workload {
     pub mod clients;
@@ -29,7 +42,7 @@ impl workload::clients::InvokeTask for InvokeTask {
             sender.send(Invoke(Default::default()))?;
             let recv = receiver.recv().await;
             anyhow::ensure!(recv.is_some());
-            println!("{:?}", start.elapsed())
+            info!(latency = ?start.elapsed(), "invoke ok")
         }
         anyhow::Ok(())
     }
@@ -37,43 +50,183 @@ impl workload::clients::InvokeTask for InvokeTask {
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> anyhow::Result<()> {
+    init_logging()?;
+    let shutdown = Shutdown::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move { shutdown.on_signal().await }
+    });
     let mode = args().nth(1);
     match mode.as_deref().unwrap_or("unreplicated") {
         "unreplicated" => {
-            let server_task = workload::servers::unreplicated();
-            let client_task = workload::clients::unreplicated(InvokeTask);
-            run_until(client_task, server_task).await
+            let server_task =
+                workload::servers::unreplicated(shutdown.clone()).instrument(info_span!("server"));
+            let client_id = random();
+            let client_task =
+                workload::clients::unreplicated(InvokeTask, client_id, shutdown.clone())
+                    .instrument(info_span!("client", id = client_id));
+            run_until(client_task, server_task, &shutdown).await?
         }
         "pbft" => {
-            let config = PublicParameters {
-                num_replica: 4,
-                num_faulty: 1,
-                num_concurrent: 1,
-                max_batch_size: 1,
-                ..PublicParameters::durations(if cfg!(debug_assertions) {
-                    Duration::from_millis(300)
-                } else {
-                    Duration::from_millis(100)
-                })
+            // the receiving sockets (and threads) and the crypto workers per replica
+            let num_receiver = env_or("NEATWORKS_NUM_RECEIVER", 1)?;
+            let num_crypto_worker = env_or("NEATWORKS_NUM_CRYPTO_WORKER", 1)?;
+            // the bound of every replica's event channels and crypto workers' queue
+            let queue = workload::servers::QueueConfig {
+                capacity: env_or(
+                    "NEATWORKS_QUEUE_CAPACITY",
+                    workload::servers::QueueConfig::default().capacity,
+                )?,
+                ..Default::default()
             };
-            let addrs = (0..4)
-                .map(|index| ([127, 0, 0, 1 + index], 3000).into())
-                .collect::<Vec<_>>();
-            let server_task0 = workload::servers::pbft(config.clone(), 0, addrs.clone());
-            let server_task1 = workload::servers::pbft(config.clone(), 1, addrs.clone());
-            let server_task2 = workload::servers::pbft(config.clone(), 2, addrs.clone());
-            let server_task3 = workload::servers::pbft(config.clone(), 3, addrs.clone());
-            let client_task = workload::clients::pbft(InvokeTask, config, addrs);
-            run_until(client_task, async {
-                select! {
-                    result = server_task0 => result,
-                    result = server_task1 => result,
-                    result = server_task2 => result,
-                    result = server_task3 => result,
-                }
-            })
-            .await
+            // replica journals are written into this directory on failure. they keep the latest
+            // `NEATWORKS_JOURNAL_CAPACITY` events, or all of them with `unbounded` so that the
+            // failure can be replayed
+            let journal_dir = std::env::var_os("NEATWORKS_JOURNAL_DIR").map(PathBuf::from);
+            let journal_capacity = match std::env::var("NEATWORKS_JOURNAL_CAPACITY") {
+                Ok(value) if value == "unbounded" => None,
+                _ => Some(env_or("NEATWORKS_JOURNAL_CAPACITY", DEFAULT_CAPACITY)?),
+            };
+            // e.g. `NEATWORKS_METRICS=localhost:9000`, then `curl localhost:9000/metrics`
+            let metrics = if let Ok(addr) = std::env::var("NEATWORKS_METRICS") {
+                let metrics = Metrics::new();
+                let listener = TcpListener::bind(addr).await?;
+                tokio::spawn({
+                    let metrics = metrics.clone();
+                    async move {
+                        if let Err(err) = serve(metrics, listener).await {
+                            error!("metrics endpoint failed: {err}")
+                        }
+                    }
+                });
+                Some(metrics)
+            } else {
+                None
+            };
+            pbft(
+                random(),
+                num_receiver,
+                num_crypto_worker,
+                journal_dir.map(|dir| (dir, journal_capacity)),
+                queue,
+                metrics,
+                shutdown,
+            )
+            .await?
         }
         _ => anyhow::bail!("unimplemented"),
     }
+    info!("clean exit");
+    Ok(())
+}
+
+fn env_or(name: &str, default: usize) -> anyhow::Result<usize> {
+    match std::env::var(name) {
+        Ok(value) => value
+            .parse()
+            .map_err(|err| anyhow::format_err!("invalid {name}={value}: {err}")),
+        Err(_) => Ok(default),
+    }
+}
+
+async fn pbft(
+    client_id: u32,
+    num_receiver: usize,
+    num_crypto_worker: usize,
+    journal: Option<(PathBuf, Option<usize>)>,
+    queue: workload::servers::QueueConfig,
+    metrics: Option<Metrics>,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let config = PublicParameters {
+        num_replica: 4,
+        num_faulty: 1,
+        num_concurrent: 1,
+        max_batch_size: 1,
+        ..PublicParameters::durations(if cfg!(debug_assertions) {
+            Duration::from_millis(300)
+        } else {
+            Duration::from_millis(100)
+        })
+    };
+    let addrs = (0..4)
+        .map(|index| ([127, 0, 0, 1 + index], 3000).into())
+        .collect::<Vec<_>>();
+    let journal = |index| {
+        journal
+            .as_ref()
+            .map(|(dir, capacity)| workload::servers::JournalConfig {
+                path: dir.join(format!("replica-{index}.journal")),
+                capacity: *capacity,
+            })
+    };
+    let server_task = |index| {
+        workload::servers::pbft(
+            config.clone(),
+            index,
+            addrs.clone(),
+            num_receiver,
+            num_crypto_worker,
+            journal(index),
+            queue.clone(),
+            metrics.clone(),
+            shutdown.clone(),
+        )
+        .instrument(info_span!("replica", id = index))
+    };
+    let (server_task0, server_task1, server_task2, server_task3) = (
+        server_task(0),
+        server_task(1),
+        server_task(2),
+        server_task(3),
+    );
+    let client_task = workload::clients::pbft(
+        InvokeTask,
+        client_id,
+        config.clone(),
+        addrs.clone(),
+        shutdown.clone(),
+    )
+    .instrument(info_span!("client", id = client_id));
+    run_until(
+        client_task,
+        async {
+            try_join!(server_task0, server_task1, server_task2, server_task3)?;
+            Ok(())
+        },
+        &shutdown,
+    )
+    .await
+}
+
+// the whole deployment above on the simulated network, run with e.g.
+// `cargo test --features simulate --bin workload-standalone`
+#[cfg(all(test, feature = "simulate"))]
+mod tests {
+    use neatworks::net::task::sim::{Config, Delivery, Network};
+
+    use super::*;
+
+    fn simulate_pbft(seed: u64) -> anyhow::Result<Vec<Delivery>> {
+        let network = Network::new(seed, Config::default());
+        network.block_on(pbft(
+            1,
+            1,
+            1,
+            None,
+            Default::default(),
+            None,
+            Shutdown::new(),
+        ))??;
+        Ok(network.trace())
+    }
+
+    #[test]
+    fn pbft_reproducible() -> anyhow::Result<()> {
+        let trace = simulate_pbft(42)?;
+        anyhow::ensure!(!trace.is_empty());
+        anyhow::ensure!(simulate_pbft(42)? == trace);
+        anyhow::ensure!(simulate_pbft(43)? != trace);
+        Ok(())
+    }
 }
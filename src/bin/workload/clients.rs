@@ -1,28 +1,29 @@
-use std::{future::Future, net::SocketAddr, sync::Arc};
+use std::{future::Future, net::SocketAddr};
 
 use bytes::Bytes;
 use neatworks::{
-    codec::Encode,
+    codec::{envelope, on_malformed, Encode, ErrorPolicy},
     event::{
-        task::{self, run_with_schedule, ScheduleState},
+        task::{self, run_with_schedule, ScheduleState, Shutdown},
         Erase, SendEvent, Untyped,
     },
     net::{
         combinators::{Forward, IndexNet},
-        task::udp,
+        fragment::{self, Fragment},
     },
     pbft::{self, PublicParameters},
     unreplicated,
     workload::events::{Invoke, InvokeOk},
 };
-use rand::random;
 use tokio::{
-    net::UdpSocket,
     select,
     sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
 };
 
-use super::util::run_until;
+use super::util::{
+    run_until,
+    transport::{self, UdpSocket},
+};
 
 pub trait InvokeTask {
     fn run(
@@ -32,15 +33,20 @@ pub trait InvokeTask {
     ) -> impl Future<Output = anyhow::Result<()>>;
 }
 
-pub async fn unreplicated(invoke_task: impl InvokeTask) -> anyhow::Result<()> {
-    let socket = Arc::new(UdpSocket::bind("localhost:0").await?);
+pub async fn unreplicated(
+    invoke_task: impl InvokeTask,
+    id: u32,
+    shutdown: Shutdown,
+) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
     let addr = socket.local_addr()?;
+    let (socket_sender, mut socket_receiver) = transport::Sender::new();
     let (upcall_sender, upcall_receiver) = unbounded_channel::<InvokeOk<_>>();
-    let (schedule_sender, mut schedule_receiver) = unbounded_channel();
     let (sender, mut receiver) = unbounded_channel();
 
     type S = unreplicated::ClientState<SocketAddr>;
-    type Net = Encode<unreplicated::Request<SocketAddr>, Forward<SocketAddr, Arc<UdpSocket>>>;
+    type Net =
+        Encode<unreplicated::Request<SocketAddr>, Forward<SocketAddr, Fragment<transport::Sender>>>;
     type Upcall = UnboundedSender<InvokeOk<Bytes>>;
     type Schedule = task::erase::ScheduleState<S, Context>;
     struct Context {
@@ -63,51 +69,70 @@ pub async fn unreplicated(invoke_task: impl InvokeTask) -> anyhow::Result<()> {
         }
     }
     let mut context = Context {
-        net: unreplicated::codec::client_encode(Forward(
+        net: unreplicated::codec::client_encode::<envelope::Bincode, _, _>(Forward(
             ([127, 0, 0, 1], 3000).into(),
-            socket.clone(),
+            Fragment::new(Default::default(), socket_sender),
         )),
         upcall: upcall_sender,
-        schedule: Erase::new(ScheduleState::new(schedule_sender)),
+        schedule: Erase::new(ScheduleState::new()),
     };
     let client_task = run_with_schedule(
-        Untyped::new(unreplicated::ClientState::new(random(), addr)),
+        Untyped::new(unreplicated::ClientState::new(id, addr)),
         &mut context,
         &mut receiver,
-        &mut schedule_receiver,
         |context| &mut *context.schedule,
+        &shutdown,
     );
-    let net_task = udp::run(
+    let net_task = transport::run(
         &socket,
-        unreplicated::codec::client_decode(Erase::new(sender.clone())),
+        on_malformed(
+            ErrorPolicy::Drop,
+            Default::default(),
+            fragment::reassemble(
+                Default::default(),
+                unreplicated::codec::client_decode::<envelope::Bincode, _>(
+                    Default::default(),
+                    Erase::new(sender.clone()),
+                ),
+            ),
+        ),
     );
+    let net_send_task = transport::run_sender(&socket, &mut socket_receiver);
 
     run_until(
         invoke_task.run(Erase::new(sender), upcall_receiver),
         async {
             select! {
+                biased;
                 result = net_task => result,
+                result = net_send_task => result,
                 result = client_task => result,
             }
         },
+        &shutdown,
     )
     .await
 }
 
 pub async fn pbft(
     invoke_task: impl InvokeTask,
+    id: u32,
     config: PublicParameters,
     replica_addrs: Vec<SocketAddr>,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
-    let socket = Arc::new(UdpSocket::bind("localhost:0").await?);
+    let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
     let addr = socket.local_addr()?;
+    let (socket_sender, mut socket_receiver) = transport::Sender::new();
     let (upcall_sender, upcall_receiver) = unbounded_channel::<InvokeOk<_>>();
-    let (schedule_sender, mut schedule_receiver) = unbounded_channel();
     let (sender, mut receiver) = unbounded_channel();
+    let limits = pbft::messages::codec::Limits::new(&config);
 
     type S = pbft::client::State<SocketAddr>;
-    type Net =
-        Encode<pbft::messages::codec::ToReplica<SocketAddr>, IndexNet<SocketAddr, Arc<UdpSocket>>>;
+    type Net = Encode<
+        pbft::messages::codec::ToReplica<SocketAddr>,
+        IndexNet<SocketAddr, Fragment<transport::Sender>>,
+    >;
     type Upcall = UnboundedSender<InvokeOk<Bytes>>;
     type Schedule = task::erase::ScheduleState<S, Context>;
     struct Context {
@@ -133,31 +158,42 @@ pub async fn pbft(
         net: pbft::messages::codec::to_replica_encode(IndexNet::new(
             replica_addrs,
             None,
-            socket.clone(),
+            Fragment::new(Default::default(), socket_sender),
         )),
         upcall: upcall_sender,
-        schedule: Erase::new(ScheduleState::new(schedule_sender)),
+        schedule: Erase::new(ScheduleState::new()),
     };
     let client_task = run_with_schedule(
-        Untyped::new(pbft::client::State::new(random(), addr, config)),
+        Untyped::new(pbft::client::State::new(id, addr, config)),
         &mut context,
         &mut receiver,
-        &mut schedule_receiver,
         |context| &mut *context.schedule,
+        &shutdown,
     );
-    let net_task = udp::run(
+    let net_task = transport::run(
         &socket,
-        pbft::messages::codec::to_client_decode(Erase::new(sender.clone())),
+        on_malformed(
+            ErrorPolicy::Drop,
+            Default::default(),
+            fragment::reassemble(
+                Default::default(),
+                pbft::messages::codec::to_client_decode(limits, Erase::new(sender.clone())),
+            ),
+        ),
     );
+    let net_send_task = transport::run_sender(&socket, &mut socket_receiver);
 
     run_until(
         invoke_task.run(Erase::new(sender), upcall_receiver),
         async {
             select! {
+                biased;
                 result = net_task => result,
+                result = net_send_task => result,
                 result = client_task => result,
             }
         },
+        &shutdown,
     )
     .await
 }
@@ -1,23 +1,43 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{atomic::Ordering::SeqCst, Arc},
+};
 
 use neatworks::{
-    codec::Encode,
+    codec::{bincode, envelope, on_malformed, DecodeStats, Encode, ErrorPolicy},
     crypto::{Crypto, CryptoFlavor},
     event::{
-        task::{self, run, run_with_schedule, run_worker, ScheduleState},
+        combinators::journal::{Record, Recorder},
+        task::{
+            self,
+            bounded::{self, Overflow, Stats},
+            metrics::{Instrumented, Metrics},
+            pool,
+            priority::Prioritized,
+            run, run_with_schedule, ScheduleState, Shutdown,
+        },
+        typed::{Dispatched, Typed},
         Erase, Untyped,
     },
-    net::{combinators::IndexNet, task::udp},
-    pbft, unreplicated,
+    net::{
+        combinators::IndexNet,
+        fragment::{self, Fragment},
+    },
+    pbft, timer, unreplicated,
     workload::Null,
 };
-use tokio::{net::UdpSocket, select, sync::mpsc::unbounded_channel};
+use tokio::{select, sync::mpsc::unbounded_channel, try_join};
+use tracing::{error, info};
+
+use super::util::transport::{self, UdpSocket};
 
-pub async fn unreplicated() -> anyhow::Result<()> {
-    let socket = Arc::new(UdpSocket::bind("localhost:3000").await?);
+pub async fn unreplicated(shutdown: Shutdown) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 3000))).await?;
+    let (socket_sender, mut socket_receiver) = transport::Sender::new();
     let (sender, mut receiver) = unbounded_channel();
 
-    type Net = Encode<unreplicated::Reply, Arc<UdpSocket>>;
+    type Net = Encode<unreplicated::Reply, Fragment<transport::Sender>>;
     struct Context(Net);
     impl unreplicated::ServerContext<SocketAddr> for Context {
         type Net = Net;
@@ -25,41 +45,134 @@ pub async fn unreplicated() -> anyhow::Result<()> {
             &mut self.0
         }
     }
-    let mut context = Context(unreplicated::codec::server_encode(socket.clone()));
+    let mut context = Context(unreplicated::codec::server_encode::<envelope::Bincode, _>(
+        Fragment::new(Default::default(), socket_sender),
+    ));
+    // the server's events are typed, so no allocation per request on the hot path
     let server_task = run(
-        Untyped::new(unreplicated::ServerState::new(Null)),
+        Dispatched::<_, unreplicated::ServerEvent<_>>::new(unreplicated::ServerState::new(Null)),
         &mut context,
         &mut receiver,
+        &shutdown,
     );
-    let net_task = udp::run(
+    let decode_stats = Arc::<DecodeStats>::default();
+    let net_task = transport::run(
         &socket,
-        unreplicated::codec::server_decode(Erase::new(sender)),
+        on_malformed(
+            ErrorPolicy::Drop,
+            decode_stats.clone(),
+            fragment::reassemble(
+                Default::default(),
+                unreplicated::codec::server_decode::<envelope::Bincode, _>(
+                    Default::default(),
+                    Typed::<unreplicated::ServerEvent<_>, _>::new(sender),
+                ),
+            ),
+        ),
     );
 
+    let net_send_task = transport::run_sender(&socket, &mut socket_receiver);
+
     select! {
+        biased;
         result = net_task => result?,
-        result = server_task => result?,
+        result = net_send_task => result?,
+        // the only task that ends, on shutdown
+        result = server_task => {
+            log_decode_stats(&decode_stats);
+            return result
+        }
     }
     anyhow::bail!("unexpected termination of infinite task")
 }
 
+// where the replica's journal is written on failure, and how many of the latest events it keeps,
+// all of them if `None`
+#[derive(Debug, Clone)]
+pub struct JournalConfig {
+    pub path: PathBuf,
+    pub capacity: Option<usize>,
+}
+
+// the bounds of the replica's event channels and its crypto workers' queue, and what each of them
+// does when it is full
+#[derive(Debug, Clone)]
+pub struct QueueConfig {
+    pub capacity: usize,
+    // consensus messages and crypto results. dropping the oldest ones is no worse than losing them
+    // in the network, and the latest ones are the most likely to be still useful
+    pub control: Overflow,
+    // client requests, which are rejected at the door so that the clients retry later
+    pub request: Overflow,
+    pub crypto: Overflow,
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 4096,
+            control: Overflow::DropOldest,
+            request: Overflow::Reject,
+            crypto: Overflow::DropOldest,
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
 pub async fn pbft(
     config: pbft::PublicParameters,
     index: usize,
     addrs: Vec<SocketAddr>,
+    num_receiver: usize,
+    num_crypto_worker: usize,
+    journal: Option<JournalConfig>,
+    queue: QueueConfig,
+    metrics: Option<Metrics>,
+    shutdown: Shutdown,
 ) -> anyhow::Result<()> {
-    let socket = Arc::new(UdpSocket::bind(addrs[index]).await?);
+    // with multiple receivers, messages are decoded on `num_receiver` dedicated threads, and the
+    // sending socket is a duplication of the first receiving one
+    let (socket, receive_sockets) = if num_receiver == 1 {
+        (UdpSocket::bind(addrs[index]).await?, Vec::new())
+    } else {
+        let receive_sockets = transport::bind_reuse_port(addrs[index], num_receiver)?;
+        (
+            UdpSocket::from_std(receive_sockets[0].try_clone()?)?,
+            receive_sockets,
+        )
+    };
+    let (socket_sender, mut socket_receiver) = transport::Sender::new();
 
-    let (crypto_sender, mut crypto_receiver) = unbounded_channel();
-    let (schedule_sender, mut schedule_receiver) = unbounded_channel();
-    let (sender, mut receiver) = unbounded_channel();
+    let (crypto_sender, crypto_receiver) = pool::bounded_channel(queue.capacity, queue.crypto);
+    // consensus messages and crypto results go through the control lane, which is prioritized over
+    // the client requests on the data lane
+    let (control_sender, control_receiver) = bounded::channel(queue.capacity, queue.control);
+    let (sender, receiver) = bounded::channel(queue.capacity, queue.request);
+    let queue_stats = [
+        ("control", control_sender.stats()),
+        ("request", sender.stats()),
+        ("crypto", crypto_sender.stats()),
+    ];
+    // the replica's loop and its crypto workers are instrumented separately
+    let replica_metrics = metrics
+        .as_ref()
+        .map(|metrics| metrics.scope(format!("replica-{index}")));
+    let crypto_metrics = metrics.map(|metrics| metrics.scope(format!("crypto-{index}")));
+    let mut receiver = Instrumented::optional(
+        Prioritized::new(control_receiver, receiver, 16),
+        replica_metrics.clone(),
+    );
 
-    type S = pbft::replica::State<Null, SocketAddr>;
-    type PeerNet =
-        Encode<pbft::messages::codec::ToReplica<SocketAddr>, IndexNet<SocketAddr, Arc<UdpSocket>>>;
-    type DownlinkNet = Encode<pbft::messages::codec::ToClient, Arc<UdpSocket>>;
-    type CryptoWorker = task::work::Sender<Crypto, CryptoContext>;
-    type CryptoContext = task::erase::Sender<S, Context>;
+    type R = pbft::replica::State<Null, SocketAddr>;
+    // the replica is always wrapped for recording, which is only enabled with a journal path
+    type S = Record<R>;
+    type PeerNet = Encode<
+        pbft::messages::codec::ToReplica<SocketAddr>,
+        IndexNet<SocketAddr, Fragment<transport::Sender>>,
+    >;
+    type DownlinkNet = Encode<pbft::messages::codec::ToClient, Fragment<transport::Sender>>;
+    type CryptoWorker = Instrumented<pool::Sender<Crypto, CryptoContext>>;
+    type CryptoContext = task::erase::BoundedSender<S, Context>;
     type Schedule = task::erase::ScheduleState<S, Context>;
     struct Context {
         peer_net: PeerNet,
@@ -67,11 +180,12 @@ pub async fn pbft(
         crypto_worker: CryptoWorker,
         schedule: Schedule,
     }
-    impl pbft::replica::Context<S, SocketAddr> for Context {
+    impl pbft::replica::Context<R, SocketAddr> for Context {
         type PeerNet = PeerNet;
         type DownlinkNet = DownlinkNet;
         type CryptoWorker = CryptoWorker;
         type CryptoContext = CryptoContext;
+        type ScheduleKind = timer::Erased<S, Self>;
         type Schedule = Schedule;
         fn peer_net(&mut self) -> &mut Self::PeerNet {
             &mut self.peer_net
@@ -90,33 +204,127 @@ pub async fn pbft(
         peer_net: pbft::messages::codec::to_replica_encode(IndexNet::new(
             addrs,
             index,
-            socket.clone(),
+            Fragment::new(Default::default(), socket_sender.clone()),
         )),
-        downlink_net: pbft::messages::codec::to_client_encode(socket.clone()),
-        crypto_worker: crypto_sender,
-        schedule: Erase::new(ScheduleState::new(schedule_sender)),
+        downlink_net: pbft::messages::codec::to_client_encode(Fragment::new(
+            Default::default(),
+            socket_sender,
+        )),
+        crypto_worker: Instrumented::optional(crypto_sender, crypto_metrics),
+        schedule: Erase::new(ScheduleState::new()),
     };
+    if let Some(scope) = &replica_metrics {
+        context.schedule.instrument(scope.clone())
+    }
+    let limits = pbft::messages::codec::Limits::new(&config);
+    let replica = pbft::replica::State::new(index as _, Null, config.clone());
+    let mut state = Instrumented::optional(
+        Untyped::new(if let Some(journal) = &journal {
+            let mut recorder = Recorder::new();
+            pbft::replica::register_events::<_, _, Context>(&mut recorder);
+            match journal.capacity {
+                Some(capacity) => Record::with_capacity(replica, recorder, capacity),
+                None => Record::unbounded(replica, recorder),
+            }
+        } else {
+            Record::disabled(replica)
+        }),
+        replica_metrics.clone(),
+    );
+    // the replica is shut down after the crypto workers, so it drains their results as well
+    let replica_shutdown = Shutdown::new();
     let server_task = run_with_schedule(
-        Untyped::new(pbft::replica::State::new(index as _, Null, config.clone())),
+        &mut state,
         &mut context,
         &mut receiver,
-        &mut schedule_receiver,
         |context| &mut context.schedule,
+        &replica_shutdown,
     );
-    let net_task = udp::run(
-        &socket,
-        pbft::messages::codec::to_replica_decode(Erase::new(sender.clone())),
-    );
-    let crypto_task = run_worker(
-        Crypto::new_hardcoded(config.num_replica, index, CryptoFlavor::Schnorrkel)?,
-        Erase::new(sender),
-        &mut crypto_receiver,
-    );
+    let decode_stats = Arc::new(DecodeStats::optional(replica_metrics));
+    let new_decode = || {
+        on_malformed(
+            ErrorPolicy::Drop,
+            decode_stats.clone(),
+            fragment::reassemble(
+                Default::default(),
+                pbft::messages::codec::to_replica_decode_lanes(
+                    limits,
+                    Erase::new(control_sender.clone()),
+                    Erase::new(sender.clone()),
+                ),
+            ),
+        )
+    };
+    // stop receiving right on shutdown, so no more work is submitted from incoming messages
+    let net_task = async {
+        if receive_sockets.is_empty() {
+            select! {
+                biased;
+                () = shutdown.triggered() => Ok(()),
+                result = transport::run(&socket, new_decode()) => result.and(Err(anyhow::format_err!("unexpected termination of infinite task"))),
+            }
+        } else {
+            // the receiving threads are joined on shutdown
+            transport::run_sharded(receive_sockets, new_decode, &shutdown).await
+        }
+    };
+    // signing and verifying run on `num_crypto_worker` threads besides the replica's one
+    let crypto_task = async {
+        pool::run(
+            Crypto::new_hardcoded(config.num_replica, index, CryptoFlavor::Schnorrkel)?,
+            Erase::new(control_sender.clone()),
+            crypto_receiver,
+            num_crypto_worker,
+            &shutdown,
+        )
+        .await?;
+        // every submitted work has finished and its result is queued for the replica
+        replica_shutdown.trigger();
+        anyhow::Ok(())
+    };
 
-    select! {
-        result = server_task => result?,
-        result = net_task => result?,
-        result = crypto_task => result?,
+    let net_send_task = transport::run_sender(&socket, &mut socket_receiver);
+
+    let result = select! {
+        biased;
+        // the tasks that end on shutdown, in the order of receiving, crypto workers and replica.
+        // the sending keeps going until the replica's final flush
+        result = async { try_join!(net_task, crypto_task, server_task) } => result.map(|_| ()),
+        result = net_send_task => result.and(Err(anyhow::format_err!("unexpected termination of infinite task"))),
+    };
+    log_decode_stats(&decode_stats);
+    for (queue, stats) in &queue_stats {
+        log_queue_stats(queue, stats)
     }
-    anyhow::bail!("unexpected termination of infinite task")
+    // the journal reproduces the failure with `journal::Replay` and `register_events`, if it is
+    // recorded unbounded. a truncated one still shows the latest events before the failure
+    if let (Err(err), Some(JournalConfig { path, .. })) = (&result, journal) {
+        std::fs::write(&path, bincode::encode(&state.journal)?)?;
+        error!(
+            id = index,
+            journal = %path.display(),
+            "replica failed: {err}"
+        )
+    }
+    result
+}
+
+fn log_decode_stats(stats: &DecodeStats) {
+    info!(
+        malformed = stats.malformed.load(SeqCst),
+        rejected = stats.rejected.load(SeqCst),
+        quarantined = stats.quarantined.load(SeqCst),
+        "dropped messages"
+    )
+}
+
+fn log_queue_stats(queue: &str, stats: &Stats) {
+    info!(
+        queue,
+        sent = stats.sent.load(SeqCst),
+        rejected = stats.rejected.load(SeqCst),
+        dropped = stats.dropped.load(SeqCst),
+        blocked = stats.blocked.load(SeqCst),
+        "event queue"
+    )
 }
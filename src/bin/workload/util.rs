@@ -1,14 +1,55 @@
-use std::future::Future;
+use std::{future::Future, io::IsTerminal as _};
 
-use tokio::select;
+use neatworks::event::task::Shutdown;
+use tokio::{pin, select};
+use tracing_subscriber::EnvFilter;
 
+// the datagram transport that the workloads are wired with, which is the in-memory network of `sim`
+// in the tests of the whole deployment. the binaries always run on UDP, with the `simulate` feature
+// or not
+#[cfg(all(test, feature = "simulate"))]
+pub use neatworks::net::task::sim as transport;
+#[cfg(not(all(test, feature = "simulate")))]
+pub use neatworks::net::task::udp as transport;
+
+// the filter and the format of logs are chosen by the environment variables, e.g.
+//   NEATWORKS_LOG=info,neatworks::pbft=debug NEATWORKS_LOG_FORMAT=json
+// which default to `info` and human-readable lines on stderr respectively
+pub fn init_logging() -> anyhow::Result<()> {
+    let filter = match std::env::var("NEATWORKS_LOG") {
+        Ok(directives) => EnvFilter::try_new(directives)?,
+        Err(_) => EnvFilter::new("info"),
+    };
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    match std::env::var("NEATWORKS_LOG_FORMAT").as_deref() {
+        Ok("json") => builder.json().try_init(),
+        Ok("text") | Err(_) => builder.try_init(),
+        Ok(format) => anyhow::bail!("unknown log format {format}"),
+    }
+    .map_err(|err| anyhow::format_err!("{err}"))
+}
+
+// run `task` to completion alongside the forever `background_task`, then shut the latter down
+// gracefully. the background task may also end earlier on a shutdown triggered by someone else
+// e.g. Ctrl-C, in which case `task` is abandoned
 pub async fn run_until(
     task: impl Future<Output = anyhow::Result<()>>,
     background_task: impl Future<Output = anyhow::Result<()>>,
+    shutdown: &Shutdown,
 ) -> anyhow::Result<()> {
+    pin!(background_task);
     select! {
-        result = background_task => result?,
-        result = task => return result,
+        biased;
+        result = &mut background_task => {
+            result?;
+            anyhow::ensure!(shutdown.is_triggered(), "unexpected termination of forever task");
+            return Ok(());
+        }
+        result = task => result?,
     }
-    anyhow::bail!("unexpected termination of forever task")
+    shutdown.trigger();
+    background_task.await
 }
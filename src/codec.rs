@@ -1,10 +1,32 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::Hash,
+    sync::{
+        atomic::{AtomicU64, Ordering::SeqCst},
+        Arc,
+    },
+    time::Duration,
+};
+
 use bytes::Bytes;
-use derive_more::Deref;
+use derive_more::{Deref, Display, Error};
 use derive_where::derive_where;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{
+    de::{self, DeserializeOwned},
+    Deserialize, Deserializer, Serialize,
+};
+use tokio::time::Instant;
+use tracing::debug;
 
 use crate::{
-    event::SendEvent,
+    event::{
+        task::{
+            bounded,
+            metrics::{self, Scope},
+        },
+        SendEvent,
+    },
     net::events::Cast,
     workload::{
         events::{Invoke, InvokeOk},
@@ -16,6 +38,12 @@ use crate::{
 #[derive_where(Debug, Clone, PartialEq, Eq, Hash; T)]
 pub struct Encode<M, T>(fn(&M) -> anyhow::Result<Bytes>, #[deref] T);
 
+impl<M, T> Encode<M, T> {
+    pub fn new(encode: fn(&M) -> anyhow::Result<Bytes>, inner: T) -> Self {
+        Self(encode, inner)
+    }
+}
+
 impl<M: Into<L>, L, N: SendEvent<Cast<A, Bytes>>, A> SendEvent<Cast<A, M>> for Encode<L, N> {
     fn send(&mut self, Cast(remote, message): Cast<A, M>) -> anyhow::Result<()> {
         let encoded = (self.0)(&message.into())?;
@@ -101,13 +129,377 @@ impl<W: Workload> Workload for Decode<W::Result, W> {
 }
 
 // TODO proper Debug impl
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deref, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Deref, Serialize)]
 pub struct Payload(pub Bytes);
 
+// same wire format as `Bytes`. the plain decoding always copies, and the `Payload` is sliced from
+// the buffer being decoded instead with `seed::Slice`
+impl<'de> Deserialize<'de> for Payload {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_newtype_struct("Payload", PayloadVisitor(None))
+    }
+}
+
+struct PayloadVisitor<'a>(Option<&'a Bytes>);
+
+impl<'de> de::Visitor<'de> for PayloadVisitor<'_> {
+    type Value = Payload;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "payload bytes")
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_bytes(self)
+    }
+
+    fn visit_borrowed_bytes<E: de::Error>(self, v: &'de [u8]) -> Result<Self::Value, E> {
+        match self.0 {
+            Some(source) if source.as_ptr_range().contains(&v.as_ptr()) => {
+                Ok(Payload(source.slice_ref(v)))
+            }
+            _ => self.visit_bytes(v),
+        }
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Self::Value, E> {
+        Ok(Payload(Bytes::copy_from_slice(v)))
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Self::Value, E> {
+        Ok(Payload(v.into()))
+    }
+
+    // the formats that encode bytes as sequence e.g. json
+    fn visit_seq<A: de::SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let mut buf = Vec::with_capacity(seq.size_hint().unwrap_or_default().min(4096));
+        while let Some(byte) = seq.next_element()? {
+            buf.push(byte)
+        }
+        Ok(Payload(buf.into()))
+    }
+}
+
+// the message that fails to decode, which is handled according to `ErrorPolicy` instead of failing
+// the receiving
+#[derive(Debug, Display, Error)]
+#[display(fmt = "malformed message: {_0}")]
+pub struct Malformed(#[error(not(source))] pub String);
+
+// the message that is well formed but not for us, either of another protocol or of an unsupported
+// version
+#[derive(Debug, Display, Error)]
+pub enum Rejected {
+    #[display(fmt = "protocol {_0:#06x} expected {_1:#06x}")]
+    Protocol(u16, u16),
+    #[display(fmt = "version {_0} outside supported {_1}..={_2}")]
+    Version(u16, u16, u16),
+}
+
+// the bounds on decoding untrusted input. `max_size` bounds the length of one message, which also
+// bounds the allocations: bincode fails a byte string length prefix that runs past the input before
+// allocating for it, and preallocates other collections cautiously, so every allocated element is
+// backed by input bytes. the collections of unbounded protocol semantics e.g. logs are bounded by
+// `max_collection_len`, which the protocol's decode path applies along with the tighter protocol
+// specific bounds through the `seed`s, while decoding
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Limits {
+    pub max_size: u64,
+    pub max_collection_len: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            // same as the default maximum reassembled message length
+            max_size: 16 << 20,
+            max_collection_len: 1 << 16,
+        }
+    }
+}
+
+pub fn check_len(what: &str, len: usize, max: usize) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        len <= max,
+        Malformed(format!("{what} of length {len} over limit {max}"))
+    );
+    Ok(())
+}
+
+// the `DeserializeSeed`s that bound the collections while decoding, so an over-long collection
+// fails on its length prefix (or on the first element past the limit) rather than after all of it
+// is decoded. `PhantomData<T>` is the seed of plain `T: Deserialize`. the protocol messages are
+// decoded by composing these with the seeds of the message structs, which only take the sequence
+// form that bincode encodes structs into
+pub mod seed {
+    use std::{collections::BTreeMap, fmt, marker::PhantomData};
+
+    use bytes::Bytes;
+    use serde::de::{self, DeserializeSeed, Deserializer, Expected, MapAccess, SeqAccess, Visitor};
+
+    use super::{Payload, PayloadVisitor};
+
+    // the next field of a struct (or tuple) that is decoded from a sequence
+    pub fn next<'de, A: SeqAccess<'de>, S: DeserializeSeed<'de>>(
+        seq: &mut A,
+        index: usize,
+        seed: S,
+        expected: &dyn Expected,
+    ) -> Result<S::Value, A::Error> {
+        seq.next_element_seed(seed)?
+            .ok_or_else(|| de::Error::invalid_length(index, expected))
+    }
+
+    fn check_len<E: de::Error>(what: &str, len: usize, max: usize) -> Result<(), E> {
+        if len > max {
+            return Err(E::custom(format!(
+                "{what} of length {len} over limit {max}"
+            )));
+        }
+        Ok(())
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Seq<S> {
+        pub what: &'static str,
+        pub max: usize,
+        pub element: S,
+    }
+
+    impl<'de, S: DeserializeSeed<'de> + Clone> DeserializeSeed<'de> for Seq<S> {
+        type Value = Vec<S::Value>;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_seq(self)
+        }
+    }
+
+    impl<'de, S: DeserializeSeed<'de> + Clone> Visitor<'de> for Seq<S> {
+        type Value = Vec<S::Value>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "{} of at most {} elements", self.what, self.max)
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let len = seq.size_hint().unwrap_or_default();
+            check_len(self.what, len, self.max)?;
+            let mut values = Vec::with_capacity(len.min(4096));
+            while let Some(value) = seq.next_element_seed(self.element.clone())? {
+                check_len(self.what, values.len() + 1, self.max)?;
+                values.push(value)
+            }
+            Ok(values)
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Map<K, S> {
+        pub what: &'static str,
+        pub max: usize,
+        pub value: S,
+        pub _k: PhantomData<K>,
+    }
+
+    impl<K, S> Map<K, S> {
+        pub fn new(what: &'static str, max: usize, value: S) -> Self {
+            Self {
+                what,
+                max,
+                value,
+                _k: PhantomData,
+            }
+        }
+    }
+
+    impl<'de, K: de::Deserialize<'de> + Ord, S: DeserializeSeed<'de> + Clone> DeserializeSeed<'de>
+        for Map<K, S>
+    {
+        type Value = BTreeMap<K, S::Value>;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_map(self)
+        }
+    }
+
+    impl<'de, K: de::Deserialize<'de> + Ord, S: DeserializeSeed<'de> + Clone> Visitor<'de>
+        for Map<K, S>
+    {
+        type Value = BTreeMap<K, S::Value>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "{} of at most {} entries", self.what, self.max)
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<Self::Value, A::Error> {
+            check_len(self.what, map.size_hint().unwrap_or_default(), self.max)?;
+            let mut values = BTreeMap::new();
+            while let Some(key) = map.next_key()? {
+                let value = map.next_value_seed(self.value.clone())?;
+                values.insert(key, value);
+                check_len(self.what, values.len(), self.max)?
+            }
+            Ok(values)
+        }
+    }
+
+    // the `Payload` that is sliced from `source` if it is borrowed from there i.e. `source` is the
+    // buffer being decoded in bincode, which saves a copy of every request and reply of large
+    // values. the `source` is then kept alive as long as the `Payload` is
+    #[derive(Debug, Clone, Copy)]
+    pub struct Slice<'a>(pub &'a Bytes);
+
+    impl<'de> DeserializeSeed<'de> for Slice<'_> {
+        type Value = Payload;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_newtype_struct("Payload", PayloadVisitor(Some(self.0)))
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Pair<S, T>(pub S, pub T);
+
+    impl<'de, S: DeserializeSeed<'de>, T: DeserializeSeed<'de>> DeserializeSeed<'de> for Pair<S, T> {
+        type Value = (S::Value, T::Value);
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_tuple(2, self)
+        }
+    }
+
+    impl<'de, S: DeserializeSeed<'de>, T: DeserializeSeed<'de>> Visitor<'de> for Pair<S, T> {
+        type Value = (S::Value, T::Value);
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "a pair")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let Self(first, second) = self;
+            let first = next(&mut seq, 0, first, &"a pair")?;
+            Ok((first, next(&mut seq, 1, second, &"a pair")?))
+        }
+    }
+}
+
+// what the receive path does to a message that fails to decode
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum ErrorPolicy {
+    // a stray or corrupted datagram is as if lost on the network
+    #[default]
+    Drop,
+    // additionally drop everything from the source of the message for the duration, without
+    // decoding. the source address is not authenticated, so this is against misbehaving peers
+    // rather than attackers
+    Quarantine(Duration),
+    // fail the receive loop, for tests and debugging
+    Fail,
+}
+
+#[derive(Debug, Default)]
+pub struct DecodeStats {
+    pub malformed: AtomicU64,
+    pub rejected: AtomicU64,
+    pub quarantined: AtomicU64,
+    // the counts are recorded into the receiving loop's metrics as well, if instrumented
+    scope: Option<Scope>,
+}
+
+impl DecodeStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn instrumented(scope: Scope) -> Self {
+        Self::optional(Some(scope))
+    }
+
+    pub fn optional(scope: Option<Scope>) -> Self {
+        Self {
+            scope,
+            ..Self::default()
+        }
+    }
+
+    fn count(&self, count: impl Fn(&Self) -> &AtomicU64, name: &'static str) {
+        count(self).fetch_add(1, SeqCst);
+        if let Some(scope) = &self.scope {
+            scope.add(name, None, 1)
+        }
+    }
+}
+
+// applies `policy` to the decoding failures below `on_buf` and counts them. the messages that are
+// rejected by a full bounded event channel are shed silently, since the channel counts them
+// already. other failures e.g. closed event channel always fail the receive loop
+pub fn on_malformed<A: Clone + Eq + Hash>(
+    policy: ErrorPolicy,
+    stats: Arc<DecodeStats>,
+    mut on_buf: impl FnMut(A, Bytes) -> anyhow::Result<()>,
+) -> impl FnMut(A, Bytes) -> anyhow::Result<()> {
+    let mut quarantine = HashMap::<A, Instant>::new();
+    move |remote, buf| {
+        if let Some(until) = quarantine.get(&remote) {
+            if Instant::now() < *until {
+                stats.count(|stats| &stats.quarantined, metrics::MESSAGES_QUARANTINED);
+                return Ok(());
+            }
+            quarantine.remove(&remote);
+        }
+        let Err(err) = on_buf(remote.clone(), buf) else {
+            return Ok(());
+        };
+        if err.is::<bounded::Rejected>() {
+            return Ok(());
+        }
+        if err.is::<Malformed>() {
+            stats.count(|stats| &stats.malformed, metrics::MESSAGES_MALFORMED);
+        } else if err.is::<Rejected>() {
+            stats.count(|stats| &stats.rejected, metrics::MESSAGES_REJECTED);
+        } else {
+            return Err(err);
+        }
+        match policy {
+            ErrorPolicy::Drop => debug!("drop message: {err}"),
+            ErrorPolicy::Quarantine(duration) => {
+                debug!("drop message and quarantine source for {duration:?}: {err}");
+                let now = Instant::now();
+                quarantine.retain(|_, until| *until > now);
+                quarantine.insert(remote, now + duration);
+            }
+            ErrorPolicy::Fail => return Err(err),
+        }
+        Ok(())
+    }
+}
+
 pub mod bincode {
+    use std::marker::PhantomData;
+
     use bincode::Options as _;
     use bytes::Bytes;
-    use serde::{de::DeserializeOwned, Serialize};
+    use serde::{
+        de::{DeserializeOwned, DeserializeSeed},
+        Serialize,
+    };
+
+    use super::{Limits, Malformed};
 
     pub fn encode<M: Serialize>(message: &M) -> anyhow::Result<Bytes> {
         bincode::options()
@@ -117,10 +509,246 @@ pub mod bincode {
     }
 
     pub fn decode<M: DeserializeOwned>(buf: &[u8]) -> anyhow::Result<M> {
+        decode_with(&Limits::default(), buf)
+    }
+
+    // the whole `buf` must be the message, trailing bytes are rejected
+    pub fn decode_with<M: DeserializeOwned>(limits: &Limits, buf: &[u8]) -> anyhow::Result<M> {
+        decode_seed(limits, buf, PhantomData)
+    }
+
+    pub fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        limits: &Limits,
+        buf: &'de [u8],
+        seed: S,
+    ) -> anyhow::Result<S::Value> {
+        // bincode ignores the limit option when decoding from slice
+        anyhow::ensure!(
+            buf.len() as u64 <= limits.max_size,
+            Malformed(format!(
+                "message of {} bytes over limit {}",
+                buf.len(),
+                limits.max_size
+            ))
+        );
         bincode::options()
-            .allow_trailing_bytes()
-            .deserialize(buf)
-            .map_err(Into::into)
+            .deserialize_seed(seed, buf)
+            .map_err(|err| Malformed(err.to_string()).into())
+    }
+}
+
+// the framing of protocol messages on the wire, so that stray packets, messages of other protocols
+// and of incompatible versions are told apart and rejected instead of decoded into garbage
+//
+//   magic: [u8; 4] | protocol: u16 | version: u16 | len: u32 | message: [u8; len]
+//
+// in little endian. every protocol (direction) has its own id. the sender always uses its own
+// version, and the receiver accepts a range of versions it knows how to decode, so a version bump
+// is rolled out by first widening the accepted range on all nodes
+//
+// the message is in one of the `Format`s, bincode by default. the format is told by the top 4 bits
+// of the protocol id on the wire, so a receiver rejects the messages in the formats it does not
+// decode as of another protocol
+pub mod envelope {
+    use bincode::Options as _;
+    use bytes::Bytes;
+    use serde::{
+        de::{DeserializeOwned, DeserializeSeed},
+        Serialize,
+    };
+
+    use super::{check_len, Limits, Malformed, Rejected};
+
+    pub const MAGIC: [u8; 4] = *b"NWKS";
+    pub const HEADER_LEN: usize = 12;
+
+    // the formats are types rather than values, so the encoding functions of every format are
+    // still plain functions that `codec::Encode` takes
+    pub trait Format {
+        const ID: u16;
+
+        fn encode_into<M: Serialize>(buf: &mut Vec<u8>, message: &M) -> anyhow::Result<()>;
+
+        // `buf` is the whole message
+        fn decode<M: DeserializeOwned>(limits: &Limits, buf: &Bytes) -> anyhow::Result<M>;
+    }
+
+    #[derive(Debug)]
+    pub struct Bincode;
+
+    #[derive(Debug)]
+    pub struct Json;
+
+    #[derive(Debug)]
+    pub struct Cbor;
+
+    #[derive(Debug)]
+    pub struct Msgpack;
+
+    impl Format for Bincode {
+        const ID: u16 = 0;
+
+        fn encode_into<M: Serialize>(buf: &mut Vec<u8>, message: &M) -> anyhow::Result<()> {
+            let options = bincode::options();
+            buf.reserve(options.serialized_size(message)? as _);
+            options.serialize_into(buf, message).map_err(Into::into)
+        }
+
+        fn decode<M: DeserializeOwned>(limits: &Limits, buf: &Bytes) -> anyhow::Result<M> {
+            super::bincode::decode_with(limits, buf)
+        }
+    }
+
+    // the other formats do not take the limits, so the whole message is checked up front
+    fn check_size(limits: &Limits, buf: &[u8]) -> anyhow::Result<()> {
+        check_len(
+            "message",
+            buf.len(),
+            limits.max_size.try_into().unwrap_or(usize::MAX),
+        )
+    }
+
+    impl Format for Json {
+        const ID: u16 = 1;
+
+        fn encode_into<M: Serialize>(buf: &mut Vec<u8>, message: &M) -> anyhow::Result<()> {
+            serde_json::to_writer(buf, message).map_err(Into::into)
+        }
+
+        fn decode<M: DeserializeOwned>(limits: &Limits, buf: &Bytes) -> anyhow::Result<M> {
+            check_size(limits, buf)?;
+            serde_json::from_slice(buf).map_err(|err| Malformed(err.to_string()).into())
+        }
+    }
+
+    impl Format for Cbor {
+        const ID: u16 = 2;
+
+        fn encode_into<M: Serialize>(buf: &mut Vec<u8>, message: &M) -> anyhow::Result<()> {
+            ciborium::into_writer(message, buf).map_err(Into::into)
+        }
+
+        fn decode<M: DeserializeOwned>(limits: &Limits, buf: &Bytes) -> anyhow::Result<M> {
+            check_size(limits, buf)?;
+            super::cbor::decode(buf)
+        }
+    }
+
+    impl Format for Msgpack {
+        const ID: u16 = 3;
+
+        fn encode_into<M: Serialize>(buf: &mut Vec<u8>, message: &M) -> anyhow::Result<()> {
+            rmp_serde::encode::write_named(buf, message).map_err(Into::into)
+        }
+
+        fn decode<M: DeserializeOwned>(limits: &Limits, buf: &Bytes) -> anyhow::Result<M> {
+            check_size(limits, buf)?;
+            super::msgpack::decode(buf)
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Protocol {
+        pub id: u16,
+        pub version: u16,
+        pub min_version: u16,
+    }
+
+    impl Protocol {
+        pub const fn new(id: u16, version: u16) -> Self {
+            assert!(
+                id < 1 << 12,
+                "top 4 bits of protocol id are reserved for format"
+            );
+            Self {
+                id,
+                version,
+                min_version: version,
+            }
+        }
+
+        pub const fn wire_id<F: Format>(&self) -> u16 {
+            self.id | F::ID << 12
+        }
+    }
+
+    pub fn encode<M: Serialize>(protocol: &Protocol, message: &M) -> anyhow::Result<Bytes> {
+        encode_as::<Bincode, _>(protocol, message)
+    }
+
+    pub fn encode_as<F: Format, M: Serialize>(
+        protocol: &Protocol,
+        message: &M,
+    ) -> anyhow::Result<Bytes> {
+        let mut buf = Vec::with_capacity(HEADER_LEN);
+        buf.extend_from_slice(&MAGIC);
+        buf.extend_from_slice(&protocol.wire_id::<F>().to_le_bytes());
+        buf.extend_from_slice(&protocol.version.to_le_bytes());
+        // the length is filled after encoding the message
+        buf.extend_from_slice(&[0; 4]);
+        F::encode_into(&mut buf, message)?;
+        let len = u32::try_from(buf.len() - HEADER_LEN)?;
+        buf[HEADER_LEN - 4..HEADER_LEN].copy_from_slice(&len.to_le_bytes());
+        Ok(buf.into())
+    }
+
+    // the message part of `buf` after checking the header
+    pub fn open<'a, F: Format>(protocol: &Protocol, buf: &'a [u8]) -> anyhow::Result<&'a [u8]> {
+        let Some((header, message)) = buf.split_first_chunk::<HEADER_LEN>() else {
+            anyhow::bail!(Malformed("truncated envelope header".into()))
+        };
+        let [m0, m1, m2, m3, p0, p1, v0, v1, l0, l1, l2, l3] = *header;
+        if [m0, m1, m2, m3] != MAGIC {
+            anyhow::bail!(Malformed("bad magic".into()))
+        }
+        let id = u16::from_le_bytes([p0, p1]);
+        if id != protocol.wire_id::<F>() {
+            anyhow::bail!(Rejected::Protocol(id, protocol.wire_id::<F>()))
+        }
+        let version = u16::from_le_bytes([v0, v1]);
+        if !(protocol.min_version..=protocol.version).contains(&version) {
+            anyhow::bail!(Rejected::Version(
+                version,
+                protocol.min_version,
+                protocol.version
+            ))
+        }
+        let len = u32::from_le_bytes([l0, l1, l2, l3]) as usize;
+        if len != message.len() {
+            anyhow::bail!(Malformed(format!(
+                "length {len} but {} bytes",
+                message.len()
+            )))
+        }
+        Ok(message)
+    }
+
+    pub fn decode<M: DeserializeOwned>(
+        protocol: &Protocol,
+        limits: &Limits,
+        buf: &Bytes,
+    ) -> anyhow::Result<M> {
+        decode_as::<Bincode, _>(protocol, limits, buf)
+    }
+
+    pub fn decode_as<F: Format, M: DeserializeOwned>(
+        protocol: &Protocol,
+        limits: &Limits,
+        buf: &Bytes,
+    ) -> anyhow::Result<M> {
+        let message = buf.slice_ref(open::<F>(protocol, buf)?);
+        F::decode(limits, &message)
+    }
+
+    // the message decoded with `seed` e.g. the ones of `codec::seed`, in bincode only. the
+    // `seed::Slice`s of the seed slice from `buf`, the whole envelope
+    pub fn decode_seed<'de, S: DeserializeSeed<'de>>(
+        protocol: &Protocol,
+        limits: &Limits,
+        buf: &'de Bytes,
+        seed: S,
+    ) -> anyhow::Result<S::Value> {
+        super::bincode::decode_seed(limits, open::<Bincode>(protocol, buf)?, seed)
     }
 }
 
@@ -139,6 +767,44 @@ pub mod json {
     }
 }
 
+// the self-describing compact binary formats, for talking to tools that are not written in Rust
+// without reimplementing bincode's layout
+pub mod cbor {
+    use bytes::Bytes;
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use super::Malformed;
+
+    pub fn encode<M: Serialize>(message: &M) -> anyhow::Result<Bytes> {
+        let mut buf = Vec::new();
+        ciborium::into_writer(message, &mut buf)?;
+        Ok(buf.into())
+    }
+
+    pub fn decode<M: DeserializeOwned>(buf: &[u8]) -> anyhow::Result<M> {
+        ciborium::from_reader(buf).map_err(|err| Malformed(err.to_string()).into())
+    }
+}
+
+pub mod msgpack {
+    use bytes::Bytes;
+    use serde::{de::DeserializeOwned, Serialize};
+
+    use super::Malformed;
+
+    // structs are encoded as maps with field names rather than arrays, so the messages are readable
+    // without the schema
+    pub fn encode<M: Serialize>(message: &M) -> anyhow::Result<Bytes> {
+        rmp_serde::to_vec_named(message)
+            .map(Into::into)
+            .map_err(Into::into)
+    }
+
+    pub fn decode<M: DeserializeOwned>(buf: &[u8]) -> anyhow::Result<M> {
+        rmp_serde::from_slice(buf).map_err(|err| Malformed(err.to_string()).into())
+    }
+}
+
 impl<M: Serialize, T> Encode<M, T> {
     pub fn bincode(inner: T) -> Self {
         Self(bincode::encode, inner)
@@ -147,6 +813,14 @@ impl<M: Serialize, T> Encode<M, T> {
     pub fn json(inner: T) -> Self {
         Self(json::encode, inner)
     }
+
+    pub fn cbor(inner: T) -> Self {
+        Self(cbor::encode, inner)
+    }
+
+    pub fn msgpack(inner: T) -> Self {
+        Self(msgpack::encode, inner)
+    }
 }
 
 impl<M: DeserializeOwned, T> Decode<M, T> {
@@ -157,4 +831,188 @@ impl<M: DeserializeOwned, T> Decode<M, T> {
     pub fn json(inner: T) -> Self {
         Self(json::decode, inner)
     }
+
+    pub fn cbor(inner: T) -> Self {
+        Self(cbor::decode, inner)
+    }
+
+    pub fn msgpack(inner: T) -> Self {
+        Self(msgpack::decode, inner)
+    }
+}
+
+// the building blocks of decoder fuzzing harnesses
+#[cfg(test)]
+pub mod fuzz {
+    use arbtest::arbitrary::{self, Unstructured};
+    use bytes::Bytes;
+
+    use crate::event::SendEvent;
+
+    use super::envelope::{self, Protocol};
+
+    // anything a peer on the network could send to a receiver of `protocol`: noise, a valid header
+    // followed by noise, or a corrupted variant of the `valid` encoded message
+    pub fn input(
+        u: &mut Unstructured,
+        protocol: &Protocol,
+        valid: &[u8],
+    ) -> arbitrary::Result<Bytes> {
+        let buf = match u.int_in_range(0..=3)? {
+            0 => u.arbitrary()?,
+            1 => {
+                let body = u.arbitrary::<Vec<u8>>()?;
+                let mut buf = envelope::MAGIC.to_vec();
+                buf.extend_from_slice(&protocol.id.to_le_bytes());
+                buf.extend_from_slice(&protocol.version.to_le_bytes());
+                buf.extend_from_slice(&(body.len() as u32).to_le_bytes());
+                buf.extend(body);
+                buf
+            }
+            2 => {
+                let mut buf = valid.to_vec();
+                for _ in 0..u.int_in_range(1..=4)? {
+                    let index = u.choose_index(buf.len())?;
+                    buf[index] = u.arbitrary()?
+                }
+                buf
+            }
+            _ => {
+                let mut buf = valid.to_vec();
+                buf.truncate(u.choose_index(buf.len())?);
+                buf.extend(u.arbitrary::<Vec<u8>>()?);
+                buf
+            }
+        };
+        Ok(buf.into())
+    }
+
+    // counts the decoded messages
+    #[derive(Debug, Default)]
+    pub struct Sink(pub usize);
+
+    impl<M> SendEvent<M> for Sink {
+        fn send(&mut self, _: M) -> anyhow::Result<()> {
+            self.0 += 1;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{envelope::Protocol, *};
+
+    #[test]
+    fn payload_zero_copy() -> anyhow::Result<()> {
+        let protocol = Protocol::new(0x0001, 1);
+        let payload = Payload(vec![42; 1000].into());
+        let buf = envelope::encode(&protocol, &(1u32, payload.clone()))?;
+        let seed = seed::Pair(std::marker::PhantomData::<u32>, seed::Slice(&buf));
+        let (_, decoded) = envelope::decode_seed(&protocol, &Default::default(), &buf, seed)?;
+        anyhow::ensure!(decoded == payload);
+        anyhow::ensure!(buf.as_ptr_range().contains(&decoded.as_ptr()));
+        // the other decoding paths copy
+        let (_, decoded) =
+            envelope::decode::<(u32, Payload)>(&protocol, &Default::default(), &buf)?;
+        anyhow::ensure!(decoded == payload);
+        anyhow::ensure!(!buf.as_ptr_range().contains(&decoded.as_ptr()));
+        let (_, decoded) = bincode::decode::<(u32, Payload)>(&bincode::encode(&(1u32, &payload))?)?;
+        anyhow::ensure!(decoded == payload);
+        let decoded = json::decode::<Payload>(&json::encode(&payload)?)?;
+        anyhow::ensure!(decoded == payload);
+        // sliced only from the buffer being decoded
+        let other = Bytes::from_static(b"other");
+        let seed = seed::Pair(std::marker::PhantomData::<u32>, seed::Slice(&other));
+        let (_, decoded) = envelope::decode_seed(&protocol, &Default::default(), &buf, seed)?;
+        anyhow::ensure!(decoded == payload);
+        anyhow::ensure!(!buf.as_ptr_range().contains(&decoded.as_ptr()));
+        Ok(())
+    }
+
+    #[test]
+    fn formats() -> anyhow::Result<()> {
+        use crate::workload::app::kvstore::Op;
+
+        let op = Op::Append("key".into(), "value".into());
+        anyhow::ensure!(bincode::decode::<Op>(&bincode::encode(&op)?)? == op);
+        anyhow::ensure!(json::decode::<Op>(&json::encode(&op)?)? == op);
+        anyhow::ensure!(cbor::decode::<Op>(&cbor::encode(&op)?)? == op);
+        anyhow::ensure!(msgpack::decode::<Op>(&msgpack::encode(&op)?)? == op);
+        let payload = Payload(Bytes::from_static(b"payload"));
+        anyhow::ensure!(cbor::decode::<Payload>(&cbor::encode(&payload)?)? == payload);
+        anyhow::ensure!(msgpack::decode::<Payload>(&msgpack::encode(&payload)?)? == payload);
+        anyhow::ensure!(cbor::decode::<Op>(b"garbage").is_err_and(|err| err.is::<Malformed>()));
+        anyhow::ensure!(msgpack::decode::<Op>(b"garbage").is_err_and(|err| err.is::<Malformed>()));
+        Ok(())
+    }
+
+    #[test]
+    fn envelope() -> anyhow::Result<()> {
+        let protocol = Protocol::new(0x0001, 2);
+        let limits = Limits::default();
+        let garbage = Bytes::from_static(b"garbage");
+        let buf = envelope::encode(&protocol, &(42u32, String::from("hello")))?;
+        anyhow::ensure!(envelope::decode::<(u32, String)>(&protocol, &limits, &buf)?.0 == 42);
+
+        let err = envelope::decode::<(u32, String)>(&Protocol::new(0x0002, 2), &limits, &buf)
+            .unwrap_err();
+        anyhow::ensure!(err.is::<Rejected>());
+        let err = envelope::decode::<(u32, String)>(&Protocol::new(0x0001, 3), &limits, &buf)
+            .unwrap_err();
+        anyhow::ensure!(err.is::<Rejected>());
+        // an upgraded receiver that still accepts the previous version
+        let upgraded = Protocol {
+            version: 3,
+            ..protocol
+        };
+        envelope::decode::<(u32, String)>(&upgraded, &limits, &buf)?;
+
+        let mut trailing = buf.to_vec();
+        trailing.push(0);
+        let err =
+            envelope::decode::<(u32, String)>(&protocol, &limits, &trailing.into()).unwrap_err();
+        anyhow::ensure!(err.is::<Malformed>());
+        let err = envelope::decode::<(u32, String)>(&protocol, &limits, &garbage).unwrap_err();
+        anyhow::ensure!(err.is::<Malformed>());
+
+        let small = Limits {
+            max_size: 4,
+            ..limits
+        };
+        let err = envelope::decode::<(u32, String)>(&protocol, &small, &buf).unwrap_err();
+        anyhow::ensure!(err.is::<Malformed>());
+
+        let decode = |(), buf: Bytes| {
+            envelope::decode::<(u32, String)>(&protocol, &limits, &buf)?;
+            anyhow::bail!("unexpected")
+        };
+        let metrics = metrics::Metrics::new();
+        let stats = Arc::new(DecodeStats::instrumented(metrics.scope("receive")));
+        let mut on_buf = on_malformed(ErrorPolicy::Drop, stats.clone(), decode);
+        on_buf((), garbage.clone())?;
+        anyhow::ensure!(on_buf((), buf.clone()).is_err());
+        anyhow::ensure!(stats.malformed.load(SeqCst) == 1);
+        // recorded as it happens, rather than summarized on exit
+        anyhow::ensure!(metrics
+            .snapshot()
+            .counters
+            .iter()
+            .any(|sample| sample.name == metrics::MESSAGES_MALFORMED && sample.value == 1));
+
+        let stats = Arc::<DecodeStats>::default();
+        let mut on_buf = on_malformed(
+            ErrorPolicy::Quarantine(Duration::from_secs(60)),
+            stats.clone(),
+            decode,
+        );
+        on_buf((), garbage.clone())?;
+        // the following well formed message is not even decoded
+        on_buf((), buf.clone())?;
+        anyhow::ensure!(stats.quarantined.load(SeqCst) == 1);
+
+        let mut on_buf = on_malformed(ErrorPolicy::Fail, Default::default(), decode);
+        anyhow::ensure!(on_buf((), garbage.clone()).is_err_and(|err| err.is::<Malformed>()));
+        Ok(())
+    }
 }
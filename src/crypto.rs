@@ -1,12 +1,21 @@
-use std::hash::{Hash, Hasher};
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+};
 
 use blake2::Blake2b;
 use derive_more::Deref;
 use derive_where::derive_where;
 use rand::{CryptoRng, RngCore};
-use serde::{Deserialize, Serialize};
+use serde::{
+    de::{DeserializeSeed, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize,
+};
 use sha2::{Digest, Sha256};
 
+use crate::codec::seed;
+
 // Hashed based digest deriving solution
 // There's no well known solution for deriving digest methods for general
 // structural data i.e. structs and enums (as far as I know), which means to
@@ -122,11 +131,39 @@ impl<M, S> Verifiable<M, S> {
     }
 }
 
+// decodes `Verifiable` with the seed of the inner message, see `codec::seed`
+#[derive(Debug, Clone)]
+pub struct VerifiableSeed<T>(pub T);
+
+impl<'de, T: DeserializeSeed<'de>> DeserializeSeed<'de> for VerifiableSeed<T> {
+    type Value = Verifiable<T::Value>;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<Self::Value, D::Error> {
+        deserializer.deserialize_struct("Verifiable", &["inner", "signature"], self)
+    }
+}
+
+impl<'de, T: DeserializeSeed<'de>> Visitor<'de> for VerifiableSeed<T> {
+    type Value = Verifiable<T::Value>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "struct Verifiable")
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+        let inner = seed::next(&mut seq, 0, self.0, &"struct Verifiable")?;
+        let signature = seed::next(&mut seq, 1, PhantomData, &"struct Verifiable")?;
+        Ok(Verifiable { inner, signature })
+    }
+}
+
 pub mod events {
-    #[derive(Debug, Clone)]
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Signed<M, S = super::Signature>(pub super::Verifiable<M, S>);
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct Verified<M, S = super::Signature>(pub super::Verifiable<M, S>);
 }
 
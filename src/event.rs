@@ -1,10 +1,20 @@
-use std::{any::type_name, fmt::Debug, marker::PhantomData, time::Duration};
+use std::{
+    any::type_name,
+    cell::Cell,
+    cmp::Ordering,
+    fmt::Debug,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    sync::{Arc, Weak},
+    time::Duration,
+};
 
 use derive_more::{Deref, DerefMut, Display, Error};
 use derive_where::derive_where;
 
 pub mod combinators;
 pub mod task;
+pub mod typed;
 
 pub trait SendEvent<M> {
     fn send(&mut self, event: M) -> anyhow::Result<()>;
@@ -20,6 +30,12 @@ pub trait OnEvent<C> {
     type Event;
 
     fn on_event(&mut self, event: Self::Event, context: &mut C) -> anyhow::Result<()>;
+
+    // called once by the event loop after it stops, with the remaining events drained, for the
+    // final flush e.g. sending out buffered messages. no more event is delivered after this
+    fn on_shutdown(&mut self, _context: &mut C) -> anyhow::Result<()> {
+        Ok(())
+    }
 }
 
 impl<S: OnEvent<C>, C> OnEvent<C> for &mut S {
@@ -28,11 +44,21 @@ impl<S: OnEvent<C>, C> OnEvent<C> for &mut S {
     fn on_event(&mut self, event: Self::Event, context: &mut C) -> anyhow::Result<()> {
         S::on_event(self, event, context)
     }
+
+    fn on_shutdown(&mut self, context: &mut C) -> anyhow::Result<()> {
+        S::on_shutdown(self, context)
+    }
 }
 
+// returned by event handlers to stop the event loop gracefully, i.e. the runners in `task` end with
+// `Ok(())` after the final flush, instead of failing
 #[derive(Debug, Display, Error)]
 pub struct Exit;
 
+// the event that `Untyped` states created with `Untyped::with_flush` receive on shutdown
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Flush;
+
 // the abstraction of *activated timer as a resource*, though leaky
 // as long as an instance of ActiveTimer is around and owned by someone, the
 // context that allocated the instance will keep scheduling the timer i.e.
@@ -52,9 +78,69 @@ pub struct Exit;
 // don't want the ActiveTimer itself to be `Clone` but any state that contains
 // it to be so. no such expressiveness in Rust as far as i know
 //
-// for the drop thing i just feel lazy to code for it, maybe later
-#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
-pub struct ActiveTimer(pub u32);
+// the drop thing is detected instead of prevented. every ActiveTimer, along with
+// all its clones, holds a shared guard, and the scheduler keeps the weak side of
+// it. a timer that is due with no live guard has been leaked, and the scheduler
+// reports it as a `GhostTimer` instead of firing it. the guard is ignored when
+// comparing and hashing, so the checked states still tell timers apart by id
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ActiveTimer(pub u32, TimerGuard);
+
+impl ActiveTimer {
+    // for ScheduleEvent implementations. the returned liveness is expected to be
+    // checked before firing the timer
+    pub fn new(id: u32) -> (Self, TimerLiveness) {
+        let guard = Arc::new(());
+        let liveness = TimerLiveness(Arc::downgrade(&guard));
+        (Self(id, TimerGuard(guard)), liveness)
+    }
+}
+
+impl Debug for ActiveTimer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("ActiveTimer").field(&self.0).finish()
+    }
+}
+
+#[derive(Clone)]
+struct TimerGuard(#[allow(unused)] Arc<()>);
+
+impl PartialEq for TimerGuard {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for TimerGuard {}
+
+impl PartialOrd for TimerGuard {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TimerGuard {
+    fn cmp(&self, _: &Self) -> Ordering {
+        Ordering::Equal
+    }
+}
+
+impl Hash for TimerGuard {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+#[derive(Debug, Clone)]
+pub struct TimerLiveness(Weak<()>);
+
+impl TimerLiveness {
+    pub fn is_leaked(&self) -> bool {
+        self.0.strong_count() == 0
+    }
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "ghost timer {_0} is dropped without unset")]
+pub struct GhostTimer(#[error(not(source))] pub u32);
 
 pub trait ScheduleEvent<M> {
     // the actual "user facing" interface. `OnEvent` implementations should always play with this
@@ -78,7 +164,29 @@ pub trait ScheduleEvent<M> {
         anyhow::bail!("unimplemented")
     }
 
+    // one-shot timer that fires once after `delay`, and is unset automatically by then. unsetting
+    // it after firing is an error, so the owner should forget the `ActiveTimer` once `is_set` tells
+    // it has fired, as `timer::Timer` does
+    fn set_once(&mut self, delay: Duration, event: M) -> anyhow::Result<ActiveTimer>
+    where
+        M: Send + Clone + 'static,
+    {
+        self.set_internal_once(delay, move || event)
+    }
+
+    #[allow(unused)]
+    fn set_internal_once(
+        &mut self,
+        delay: Duration,
+        event: impl FnOnce() -> M + Send + 'static,
+    ) -> anyhow::Result<ActiveTimer> {
+        anyhow::bail!("unimplemented")
+    }
+
     fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()>;
+
+    // whether the timer is still scheduled, i.e. neither unset nor, for one-shot timer, fired
+    fn is_set(&self, id: &ActiveTimer) -> bool;
 }
 
 impl<T: ScheduleEvent<M>, M> ScheduleEvent<M> for &mut T {
@@ -97,9 +205,28 @@ impl<T: ScheduleEvent<M>, M> ScheduleEvent<M> for &mut T {
         T::set_internal(self, period, event)
     }
 
+    fn set_once(&mut self, delay: Duration, event: M) -> anyhow::Result<ActiveTimer>
+    where
+        M: Clone + Send + 'static,
+    {
+        T::set_once(self, delay, event)
+    }
+
+    fn set_internal_once(
+        &mut self,
+        delay: Duration,
+        event: impl FnOnce() -> M + Send + 'static,
+    ) -> anyhow::Result<ActiveTimer> {
+        T::set_internal_once(self, delay, event)
+    }
+
     fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()> {
         T::unset(self, id)
     }
+
+    fn is_set(&self, id: &ActiveTimer) -> bool {
+        T::is_set(self, id)
+    }
 }
 
 #[derive_where(Debug, Clone; S)]
@@ -109,11 +236,27 @@ pub struct Untyped<C, S>(
     #[deref_mut]
     S,
     PhantomData<C>,
+    Option<FlushFn<S, C>>,
 );
 
+type FlushFn<S, C> = fn(&mut S, Flush, &mut C) -> anyhow::Result<()>;
+
 impl<C, S> Untyped<C, S> {
     pub fn new(state: S) -> Self {
-        Self(state, Default::default())
+        Self(state, Default::default(), None)
+    }
+
+    // the erased state has no `OnEvent::on_shutdown` to override, so it opts into the final flush
+    // by handling `Flush` instead
+    pub fn with_flush(state: S) -> Self
+    where
+        S: OnErasedEvent<Flush, C>,
+    {
+        Self(
+            state,
+            Default::default(),
+            Some(<S as OnErasedEvent<Flush, C>>::on_event),
+        )
     }
 }
 
@@ -138,6 +281,28 @@ impl<S, C> OnEvent<C> for Untyped<C, S> {
     ) -> anyhow::Result<()> {
         event(&mut self.0, context)
     }
+
+    fn on_shutdown(&mut self, context: &mut C) -> anyhow::Result<()> {
+        if let Some(flush) = self.2 {
+            flush(&mut self.0, Flush, context)?
+        }
+        Ok(())
+    }
+}
+
+thread_local! {
+    static HANDLING: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+// the type of an erased (or enum-dispatched) event is only known inside of `UntypedEvent` (or
+// `Dispatch`), which marks it right before handling, so instrumentation around the event loop can
+// tell what has been handled by taking the mark afterward
+pub fn mark_handling<M>() {
+    HANDLING.with(|handling| handling.set(Some(type_name::<M>())))
+}
+
+pub fn take_handling() -> Option<&'static str> {
+    HANDLING.with(Cell::take)
 }
 
 pub trait OnErasedEvent<M, C: ?Sized> {
@@ -172,6 +337,7 @@ impl<E: SendEvent<UntypedEvent<S, C>>, S: OnErasedEvent<M, C>, C: ?Sized, M: Sen
 {
     fn send(&mut self, event: M) -> anyhow::Result<()> {
         self.0.send(UntypedEvent(Box::new(move |state, context| {
+            mark_handling::<M>();
             state.on_event(event, context)
         })))
     }
@@ -192,6 +358,21 @@ impl<
         self.0.set_internal(period, move || {
             let event = event();
             UntypedEvent(Box::new(move |state, context| {
+                mark_handling::<M>();
+                state.on_event(event, context)
+            }))
+        })
+    }
+
+    fn set_internal_once(
+        &mut self,
+        delay: Duration,
+        event: impl FnOnce() -> M + Send + 'static,
+    ) -> anyhow::Result<ActiveTimer> {
+        self.0.set_internal_once(delay, move || {
+            let event = event();
+            UntypedEvent(Box::new(move |state, context| {
+                mark_handling::<M>();
                 state.on_event(event, context)
             }))
         })
@@ -200,6 +381,10 @@ impl<
     fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()> {
         self.0.unset(id)
     }
+
+    fn is_set(&self, id: &ActiveTimer) -> bool {
+        self.0.is_set(id)
+    }
 }
 
 pub type Work<S, C> = Box<dyn FnOnce(&mut S, &mut C) -> anyhow::Result<()> + Send>;
@@ -240,7 +425,17 @@ pub trait ScheduleEventFor<S, C> {
     where
         S: OnErasedEvent<M, C>;
 
+    fn set_once<M: Clone + Send + 'static>(
+        &mut self,
+        delay: Duration,
+        event: M,
+    ) -> anyhow::Result<ActiveTimer>
+    where
+        S: OnErasedEvent<M, C>;
+
     fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()>;
+
+    fn is_set(&self, id: &ActiveTimer) -> bool;
 }
 
 impl<T: ScheduleEvent<UntypedEvent<S, C>>, S, C> ScheduleEventFor<S, C> for Erase<S, C, T> {
@@ -255,10 +450,25 @@ impl<T: ScheduleEvent<UntypedEvent<S, C>>, S, C> ScheduleEventFor<S, C> for Eras
         ScheduleEvent::set(self, period, event)
     }
 
+    fn set_once<M: Clone + Send + 'static>(
+        &mut self,
+        delay: Duration,
+        event: M,
+    ) -> anyhow::Result<ActiveTimer>
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        ScheduleEvent::set_once(self, delay, event)
+    }
+
     fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()> {
         // cannot just forward from `self`, because that `ScheduleEvent` is bounded on
         // `S: OnErasedEvent<..>` as a whole, though that is unnecessary for `unset`
         // consider switch to opposite, implement `set` and `unset` here and forward to there
         ScheduleEvent::unset(&mut self.0, id)
     }
+
+    fn is_set(&self, id: &ActiveTimer) -> bool {
+        ScheduleEvent::is_set(&self.0, id)
+    }
 }
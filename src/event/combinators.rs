@@ -3,6 +3,9 @@ use derive_where::derive_where;
 
 use super::{OnEvent, SendEvent, Submit};
 
+pub mod batch;
+pub mod journal;
+
 pub mod erase {
     use crate::event::{Erase, UntypedEvent};
 
@@ -79,9 +82,48 @@ impl<F: FnMut(M) -> N, M, N, E: SendEvent<N>> SendEvent<M> for Map<F, E> {
     }
 }
 
+// send every event to both sinks, e.g. to observe the messages sent into a net
+#[derive(Debug, Clone)]
+pub struct Tee<A, B>(pub A, pub B);
+
+impl<M: Clone, A: SendEvent<M>, B: SendEvent<M>> SendEvent<M> for Tee<A, B> {
+    fn send(&mut self, event: M) -> anyhow::Result<()> {
+        self.0.send(event.clone())?;
+        self.1.send(event)
+    }
+}
+
+// drop the events that the predicate rejects, e.g. the `Cast`s into a partitioned peer
+#[derive(Debug, Clone)]
+pub struct Filter<F, E>(pub F, pub E);
+
+impl<F: FnMut(&M) -> bool, M, E: SendEvent<M>> SendEvent<M> for Filter<F, E> {
+    fn send(&mut self, event: M) -> anyhow::Result<()> {
+        if (self.0)(&event) {
+            self.1.send(event)?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FilterMap<F, E>(pub F, pub E);
+
+impl<F: FnMut(M) -> Option<N>, M, N, E: SendEvent<N>> SendEvent<M> for FilterMap<F, E> {
+    fn send(&mut self, event: M) -> anyhow::Result<()> {
+        if let Some(event) = (self.0)(event) {
+            self.1.send(event)?
+        }
+        Ok(())
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::event::Submit as _;
+    use crate::{
+        event::Submit as _,
+        net::{events::Cast, SendMessage},
+    };
 
     use super::*;
 
@@ -101,4 +143,50 @@ mod tests {
         anyhow::ensure!(context == 55);
         Ok(())
     }
+
+    #[test]
+    fn tee_filter() -> anyhow::Result<()> {
+        let mut all = Transient::<u32>::new();
+        let mut even = Transient::<u32>::new();
+        let mut halves = Transient::<u32>::new();
+        let mut sender = Tee(
+            &mut all,
+            Tee(
+                Filter(|n: &u32| n.is_multiple_of(2), &mut even),
+                FilterMap(|n: u32| n.is_multiple_of(2).then_some(n / 2), &mut halves),
+            ),
+        );
+        for n in 0..6 {
+            sender.send(n)?
+        }
+        anyhow::ensure!(all[..] == [0, 1, 2, 3, 4, 5]);
+        anyhow::ensure!(even[..] == [0, 2, 4]);
+        anyhow::ensure!(halves[..] == [0, 1, 2]);
+        Ok(())
+    }
+
+    #[test]
+    fn tee_cast() -> anyhow::Result<()> {
+        let mut net = Transient::<Cast<u8, u32>>::new();
+        let mut observed = Transient::<Cast<u8, u32>>::new();
+        let mut sender = Tee(
+            &mut net,
+            Filter(
+                |Cast(remote, _): &Cast<u8, u32>| *remote == 1,
+                &mut observed,
+            ),
+        );
+        for (remote, message) in [(0, 10), (1, 11), (2, 12), (1, 13)] {
+            SendMessage::send(&mut sender, remote, message)?
+        }
+        let casts = |casts: &[Cast<u8, u32>]| {
+            casts
+                .iter()
+                .map(|Cast(remote, message)| (*remote, *message))
+                .collect::<Vec<_>>()
+        };
+        anyhow::ensure!(casts(&net) == [(0, 10), (1, 11), (2, 12), (1, 13)]);
+        anyhow::ensure!(casts(&observed) == [(1, 11), (1, 13)]);
+        Ok(())
+    }
 }
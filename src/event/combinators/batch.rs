@@ -0,0 +1,152 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use crate::{
+    event::{ActiveTimer, OnErasedEvent, ScheduleEvent, SendEvent},
+    net::{events::Cast, Addr},
+};
+
+// coalesce the events sent to the same destination into batches. a batch is flushed when it gets
+// `max_size` events, or `max_delay` after its first event, whichever comes first
+//
+// the flush timer has to come back to the batching side, so unlike the other combinators this is a
+// state machine that runs in an event loop on its own (or inline with the owner's one), as
+// `net::reliable` does. its `Erase` sender implements `SendEvent<Cast<A, M>>`, and the batches go
+// into the `Net` of the context as `Cast<A, Vec<M>>`. non-net events may be batched with `()` as
+// the destination
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Config {
+    pub max_size: usize,
+    pub max_delay: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct State<A, M> {
+    config: Config,
+    batches: BTreeMap<A, (Vec<M>, ActiveTimer)>,
+}
+
+impl<A, M> State<A, M> {
+    pub fn new(config: Config) -> Self {
+        assert!(config.max_size > 0);
+        Self {
+            config,
+            batches: Default::default(),
+        }
+    }
+}
+
+pub mod events {
+    #[derive(Debug, Clone)]
+    pub struct Flush<A>(pub A);
+}
+
+pub trait Context<A, M> {
+    type Net: SendEvent<Cast<A, Vec<M>>>;
+    type Schedule: ScheduleEvent<events::Flush<A>>;
+    fn net(&mut self) -> &mut Self::Net;
+    fn schedule(&mut self) -> &mut Self::Schedule;
+}
+
+impl<A: Addr, M, C: Context<A, M>> OnErasedEvent<Cast<A, M>, C> for State<A, M> {
+    fn on_event(&mut self, Cast(remote, event): Cast<A, M>, context: &mut C) -> anyhow::Result<()> {
+        let (batch, _) = match self.batches.get_mut(&remote) {
+            Some(batch) => batch,
+            None => {
+                let timer = context
+                    .schedule()
+                    .set_once(self.config.max_delay, events::Flush(remote.clone()))?;
+                self.batches
+                    .entry(remote.clone())
+                    .or_insert((Vec::new(), timer))
+            }
+        };
+        batch.push(event);
+        if batch.len() < self.config.max_size {
+            return Ok(());
+        }
+        let Some((batch, timer)) = self.batches.remove(&remote) else {
+            unreachable!()
+        };
+        context.schedule().unset(timer)?;
+        context.net().send(Cast(remote, batch))
+    }
+}
+
+impl<A: Addr, M, C: Context<A, M>> OnErasedEvent<events::Flush<A>, C> for State<A, M> {
+    fn on_event(
+        &mut self,
+        events::Flush(remote): events::Flush<A>,
+        context: &mut C,
+    ) -> anyhow::Result<()> {
+        // the one-shot timer is gone with its firing
+        let Some((batch, _)) = self.batches.remove(&remote) else {
+            anyhow::bail!("missing batch for {remote:?}")
+        };
+        context.net().send(Cast(remote, batch))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        event::combinators::Transient,
+        model::search::state::{Schedule, TimerId},
+    };
+
+    use super::*;
+
+    struct TestContext {
+        net: Transient<Cast<u8, Vec<u32>>>,
+        schedule: Schedule<events::Flush<u8>>,
+    }
+
+    impl Context<u8, u32> for TestContext {
+        type Net = Transient<Cast<u8, Vec<u32>>>;
+        type Schedule = Schedule<events::Flush<u8>>;
+        fn net(&mut self) -> &mut Self::Net {
+            &mut self.net
+        }
+        fn schedule(&mut self) -> &mut Self::Schedule {
+            &mut self.schedule
+        }
+    }
+
+    impl TestContext {
+        fn timers(&self) -> Vec<TimerId> {
+            self.schedule.events().map(|(id, _)| id).collect()
+        }
+
+        fn sent(&mut self) -> Vec<(u8, Vec<u32>)> {
+            self.net
+                .drain(..)
+                .map(|Cast(remote, batch)| (remote, batch))
+                .collect()
+        }
+    }
+
+    #[test]
+    fn size_and_delay() -> anyhow::Result<()> {
+        let mut state = State::new(Config {
+            max_size: 3,
+            max_delay: Duration::from_millis(10),
+        });
+        let mut context = TestContext {
+            net: Transient::new(),
+            schedule: Schedule::new(),
+        };
+        for (remote, n) in [(1, 0), (2, 1), (1, 2), (1, 3)] {
+            state.on_event(Cast(remote, n), &mut context)?
+        }
+        // the full batch is flushed on size, and its timer is gone
+        anyhow::ensure!(context.sent() == [(1, vec![0, 2, 3])]);
+        let [id] = context.timers()[..] else {
+            anyhow::bail!("expected exactly one flush timer")
+        };
+        context.schedule.tick(id)?;
+        state.on_event(events::Flush(2), &mut context)?;
+        anyhow::ensure!(context.sent() == [(2, vec![1])]);
+        anyhow::ensure!(context.timers().is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,477 @@
+use std::{
+    any::{type_name, Any, TypeId},
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use derive_more::{Deref, DerefMut};
+use derive_where::derive_where;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    codec::bincode,
+    event::{
+        ActiveTimer, Erase, OnErasedEvent, OnEvent, ScheduleEvent, ScheduleEventFor, SendEvent,
+        SendEventFor, UntypedEvent,
+    },
+};
+
+// recording of the events delivered to a state, so the exact event sequence of e.g. a failed
+// replica can be reproduced offline, by feeding the journal into a fresh state with a context that
+// has no network, timer or worker behind it
+//
+// received messages, timer firings and worker results all reach the state as events, so recording
+// on the state side covers all of them, in the order they are handled. the events of an erased
+// state may be of any type, so each type to be recorded is registered once on both the recording
+// and the replaying side, usually through a per-protocol function that is generic over `Register`.
+// every registered type is given a tag, which is what the entries are keyed by, so a journal stays
+// replayable across builds as long as the tags stay the same, while type names may not
+//
+// only the latest `capacity` entries are kept, unless recording with `Record::unbounded`. a
+// truncated journal still tells what happened lately, but it cannot be replayed from a fresh state,
+// only from the state as of its earliest retained entry with `Replay::run_suffix`. a loaded replica
+// handles the default capacity of events within seconds, so reproducing its failure from a fresh
+// state takes the unbounded recording
+
+pub const DEFAULT_CAPACITY: usize = 1 << 16;
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Journal {
+    pub entries: VecDeque<Entry>,
+    // the number of the earliest entries that have been dropped
+    pub num_truncated: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Entry {
+    // since the recording started
+    pub at: Duration,
+    // the registered tag, or the type name of unregistered event type and typed state's event
+    pub event: String,
+    // `None` for unregistered event type. the journal still tells what happened, but cannot be
+    // replayed past such entry
+    pub payload: Option<Vec<u8>>,
+}
+
+pub trait Register<S, C> {
+    fn register<M: Serialize + DeserializeOwned + 'static>(&mut self, tag: &'static str)
+    where
+        S: OnErasedEvent<M, C>;
+}
+
+impl Journal {
+    fn ensure_complete(&self) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.num_truncated == 0,
+            "journal truncated by {} entries",
+            self.num_truncated
+        );
+        Ok(())
+    }
+}
+
+type EncodeFn = fn(&dyn Any) -> anyhow::Result<Vec<u8>>;
+
+#[derive(Debug, Default)]
+pub struct Recorder(HashMap<TypeId, (&'static str, EncodeFn)>);
+
+impl Recorder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl<S, C> Register<S, C> for Recorder {
+    fn register<M: Serialize + DeserializeOwned + 'static>(&mut self, tag: &'static str)
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        assert!(
+            self.0.values().all(|(registered, _)| *registered != tag),
+            "duplicated event tag {tag}"
+        );
+        let encode: EncodeFn = |event| {
+            let Some(event) = event.downcast_ref::<M>() else {
+                unreachable!()
+            };
+            Ok(bincode::encode(event)?.to_vec())
+        };
+        self.0.insert(TypeId::of::<M>(), (tag, encode));
+    }
+}
+
+// recording is optional, so deployments can always wrap the state, and only pay for the journal
+// when asked for
+#[derive(Debug, Deref, DerefMut)]
+pub struct Record<S> {
+    #[deref]
+    #[deref_mut]
+    pub state: S,
+    pub journal: Journal,
+    recorder: Option<Recorder>,
+    // `None` for unbounded
+    capacity: Option<usize>,
+    start: Instant,
+}
+
+impl<S> Record<S> {
+    pub fn new(state: S, recorder: Recorder) -> Self {
+        Self::with_capacity(state, recorder, DEFAULT_CAPACITY)
+    }
+
+    pub fn with_capacity(state: S, recorder: Recorder, capacity: usize) -> Self {
+        assert!(capacity > 0);
+        Self {
+            capacity: Some(capacity),
+            ..Self::unbounded(state, recorder)
+        }
+    }
+
+    // the memory grows with every handled event, for the runs that are short or expected to fail
+    // early
+    pub fn unbounded(state: S, recorder: Recorder) -> Self {
+        Self {
+            state,
+            journal: Default::default(),
+            recorder: Some(recorder),
+            capacity: None,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn disabled(state: S) -> Self {
+        Self {
+            recorder: None,
+            ..Self::new(state, Recorder::new())
+        }
+    }
+
+    fn record<M: 'static>(&mut self, event: &M) -> anyhow::Result<()> {
+        let Some(recorder) = &self.recorder else {
+            return Ok(());
+        };
+        let (event, payload) = match recorder.0.get(&TypeId::of::<M>()) {
+            Some((tag, encode)) => (*tag, Some(encode(event)?)),
+            None => (type_name::<M>(), None),
+        };
+        self.push(event, payload);
+        Ok(())
+    }
+
+    fn push(&mut self, event: &str, payload: Option<Vec<u8>>) {
+        if Some(self.journal.entries.len()) == self.capacity {
+            self.journal.entries.pop_front();
+            self.journal.num_truncated += 1
+        }
+        self.journal.entries.push_back(Entry {
+            at: self.start.elapsed(),
+            event: event.into(),
+            payload,
+        })
+    }
+}
+
+impl<S: OnErasedEvent<M, C>, M: 'static, C> OnErasedEvent<M, C> for Record<S> {
+    fn on_event(&mut self, event: M, context: &mut C) -> anyhow::Result<()> {
+        self.record(&event)?;
+        self.state.on_event(event, context)
+    }
+}
+
+// typed states are recorded without registration
+impl<S: OnEvent<C>, C> OnEvent<C> for Record<S>
+where
+    S::Event: Serialize,
+{
+    type Event = S::Event;
+
+    fn on_event(&mut self, event: Self::Event, context: &mut C) -> anyhow::Result<()> {
+        if self.recorder.is_some() {
+            self.push(
+                type_name::<S::Event>(),
+                Some(bincode::encode(&event)?.to_vec()),
+            )
+        }
+        self.state.on_event(event, context)
+    }
+
+    fn on_shutdown(&mut self, context: &mut C) -> anyhow::Result<()> {
+        self.state.on_shutdown(context)
+    }
+}
+
+// the protocol states are generic over contexts that send and schedule events for themselves, so a
+// recorded state must be able to stand in for the inner one in the erased senders and schedules
+impl<E: SendEvent<UntypedEvent<Record<S>, C>>, S, C> SendEventFor<S, C> for Erase<Record<S>, C, E> {
+    fn send<M: Send + 'static>(&mut self, event: M) -> anyhow::Result<()>
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        SendEvent::send(self, event)
+    }
+}
+
+impl<T: ScheduleEvent<UntypedEvent<Record<S>, C>>, S, C> ScheduleEventFor<S, C>
+    for Erase<Record<S>, C, T>
+{
+    fn set<M: Clone + Send + 'static>(
+        &mut self,
+        period: Duration,
+        event: M,
+    ) -> anyhow::Result<ActiveTimer>
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        ScheduleEvent::set(self, period, event)
+    }
+
+    fn set_once<M: Clone + Send + 'static>(
+        &mut self,
+        delay: Duration,
+        event: M,
+    ) -> anyhow::Result<ActiveTimer>
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        ScheduleEvent::set_once(self, delay, event)
+    }
+
+    fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()> {
+        ScheduleEvent::<UntypedEvent<Record<S>, C>>::unset(&mut **self, id)
+    }
+
+    fn is_set(&self, id: &ActiveTimer) -> bool {
+        ScheduleEvent::<UntypedEvent<Record<S>, C>>::is_set(&**self, id)
+    }
+}
+
+type DispatchFn<S, C> = fn(&mut S, &[u8], &mut C) -> anyhow::Result<()>;
+
+// the replay driver. the events are fed in order regardless of the timestamps, and whatever the
+// state sends or schedules in response goes into the given context, which is expected to be inert
+// e.g. `Transient`s and a `model::search::state::Schedule` that nobody ticks
+#[derive_where(Debug)]
+pub struct Replay<S, C>(#[derive_where(skip)] HashMap<&'static str, DispatchFn<S, C>>);
+
+impl<S, C> Default for Replay<S, C> {
+    fn default() -> Self {
+        Self(Default::default())
+    }
+}
+
+impl<S, C> Replay<S, C> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn run(&self, journal: &Journal, state: &mut S, context: &mut C) -> anyhow::Result<()> {
+        journal.ensure_complete()?;
+        self.run_suffix(journal, state, context)
+    }
+
+    // replay the retained entries of a possibly truncated journal, into the `state` as of right
+    // before the earliest of them e.g. restored from a snapshot of the recorded state
+    pub fn run_suffix(
+        &self,
+        journal: &Journal,
+        state: &mut S,
+        context: &mut C,
+    ) -> anyhow::Result<()> {
+        for (index, entry) in journal.entries.iter().enumerate() {
+            let Some(payload) = &entry.payload else {
+                anyhow::bail!("entry {index} of unregistered event {}", entry.event)
+            };
+            let Some(dispatch) = self.0.get(&*entry.event) else {
+                anyhow::bail!("entry {index} of unknown event {}", entry.event)
+            };
+            dispatch(state, payload, context)
+                .map_err(|err| err.context(format!("replay entry {index} of {}", entry.event)))?
+        }
+        Ok(())
+    }
+}
+
+impl<S, C> Register<S, C> for Replay<S, C> {
+    fn register<M: Serialize + DeserializeOwned + 'static>(&mut self, tag: &'static str)
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        let replaced = self.0.insert(tag, |state, payload, context| {
+            state.on_event(bincode::decode::<M>(payload)?, context)
+        });
+        assert!(replaced.is_none(), "duplicated event tag {tag}")
+    }
+}
+
+// typed states are replayed without registration
+pub fn replay<S: OnEvent<C>, C>(
+    journal: &Journal,
+    mut state: S,
+    context: &mut C,
+) -> anyhow::Result<()>
+where
+    S::Event: DeserializeOwned,
+{
+    journal.ensure_complete()?;
+    for (index, entry) in journal.entries.iter().enumerate() {
+        let Some(payload) = &entry.payload else {
+            anyhow::bail!("entry {index} of unregistered event {}", entry.event)
+        };
+        state.on_event(bincode::decode(payload)?, context)?
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::{combinators::erase::Transient, Untyped};
+
+    use super::*;
+
+    #[derive(Debug, Default)]
+    struct Sum(u32);
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Add(u32);
+
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Double;
+
+    trait Context: Sized {
+        type Loopback: SendEventFor<Sum, Self>;
+        fn loopback(&mut self) -> &mut Self::Loopback;
+    }
+
+    impl<C: Context> OnErasedEvent<Add, C> for Sum {
+        fn on_event(&mut self, Add(n): Add, context: &mut C) -> anyhow::Result<()> {
+            self.0 += n;
+            anyhow::ensure!(self.0 < 100, "overflow");
+            if n % 2 == 1 {
+                context.loopback().send(Double)?
+            }
+            Ok(())
+        }
+    }
+
+    impl<C: Context> OnErasedEvent<Double, C> for Sum {
+        fn on_event(&mut self, Double: Double, _: &mut C) -> anyhow::Result<()> {
+            self.0 *= 2;
+            anyhow::ensure!(self.0 < 100, "overflow");
+            Ok(())
+        }
+    }
+
+    fn register<C: Context>(registry: &mut impl Register<Sum, C>) {
+        registry.register::<Add>("add");
+        registry.register::<Double>("double");
+    }
+
+    struct RecordContext(Transient<Record<Sum>, Self>);
+
+    impl Context for RecordContext {
+        type Loopback = Transient<Record<Sum>, Self>;
+        fn loopback(&mut self) -> &mut Self::Loopback {
+            &mut self.0
+        }
+    }
+
+    // the replaying context drops whatever sent, since the sent events are in the journal already
+    struct ReplayContext(Transient<Sum, Self>);
+
+    impl Context for ReplayContext {
+        type Loopback = Transient<Sum, Self>;
+        fn loopback(&mut self) -> &mut Self::Loopback {
+            &mut self.0
+        }
+    }
+
+    #[test]
+    fn record_replay() -> anyhow::Result<()> {
+        let mut recorder = Recorder::new();
+        register::<RecordContext>(&mut recorder);
+        let mut state = Untyped::new(Record::new(Sum::default(), recorder));
+        let mut context = RecordContext(Erase::new(Default::default()));
+        // the loopback events interleave with the incoming ones
+        let err = 'outer: {
+            for n in 1..10 {
+                SendEvent::send(&mut context.0, Add(n))?;
+                while let Some(event) = context.0.pop() {
+                    if let Err(err) = state.on_event(event, &mut context) {
+                        break 'outer err;
+                    }
+                }
+            }
+            anyhow::bail!("expected overflow")
+        };
+        let journal = bincode::decode::<Journal>(&bincode::encode(&state.journal)?)?;
+        anyhow::ensure!(journal.entries.iter().any(|entry| entry.event == "double"));
+
+        let mut replay = Replay::new();
+        register::<ReplayContext>(&mut replay);
+        let mut replayed = Sum::default();
+        let mut context = ReplayContext(Erase::new(Default::default()));
+        let Err(replay_err) = replay.run(&journal, &mut replayed, &mut context) else {
+            anyhow::bail!("expected overflow on replay")
+        };
+        anyhow::ensure!(replay_err.root_cause().to_string() == err.to_string());
+        anyhow::ensure!(replayed.0 == state.state.0);
+        Ok(())
+    }
+
+    #[test]
+    fn truncated() -> anyhow::Result<()> {
+        let mut recorder = Recorder::new();
+        register::<RecordContext>(&mut recorder);
+        let mut state = Untyped::new(Record::with_capacity(Sum::default(), recorder, 4));
+        let mut context = RecordContext(Erase::new(Default::default()));
+        for _ in 0..6 {
+            SendEvent::send(&mut context.0, Add(2))?;
+            while let Some(event) = context.0.pop() {
+                state.on_event(event, &mut context)?
+            }
+        }
+        anyhow::ensure!(state.journal.entries.len() == 4 && state.journal.num_truncated == 2);
+
+        let mut replay = Replay::new();
+        register::<ReplayContext>(&mut replay);
+        let mut context = ReplayContext(Erase::new(Default::default()));
+        anyhow::ensure!(replay
+            .run(&state.journal, &mut Sum::default(), &mut context)
+            .is_err());
+        // the two truncated `Add(2)`s are where the retained suffix starts from
+        let mut replayed = Sum(4);
+        replay.run_suffix(&state.journal, &mut replayed, &mut context)?;
+        anyhow::ensure!(replayed.0 == state.state.0);
+        Ok(())
+    }
+
+    #[test]
+    fn unbounded() -> anyhow::Result<()> {
+        let mut recorder = Recorder::new();
+        register::<RecordContext>(&mut recorder);
+        let mut state = Untyped::new(Record::unbounded(Sum::default(), recorder));
+        let mut context = RecordContext(Erase::new(Default::default()));
+        // past the default capacity, then to the overflow
+        for n in (0..DEFAULT_CAPACITY).map(|_| 0).chain([40; 3]) {
+            SendEvent::send(&mut context.0, Add(n))?;
+            while let Some(event) = context.0.pop() {
+                if let Err(err) = state.on_event(event, &mut context) {
+                    anyhow::ensure!(err.to_string() == "overflow")
+                }
+            }
+        }
+        anyhow::ensure!(state.journal.num_truncated == 0);
+        anyhow::ensure!(state.journal.entries.len() == DEFAULT_CAPACITY + 3);
+
+        let mut replay = Replay::new();
+        register::<ReplayContext>(&mut replay);
+        let mut context = ReplayContext(Erase::new(Default::default()));
+        let mut replayed = Sum::default();
+        let err = replay
+            .run(&state.journal, &mut replayed, &mut context)
+            .unwrap_err();
+        anyhow::ensure!(err.root_cause().to_string() == "overflow");
+        anyhow::ensure!(replayed.0 == state.state.0);
+        Ok(())
+    }
+}
@@ -1,20 +1,42 @@
-use std::collections::HashMap;
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap},
+    future::Future,
+    sync::Arc,
+    time::Duration,
+};
 
 use derive_where::derive_where;
 use tokio::{
-    select, spawn,
-    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-    task::{AbortHandle, JoinSet},
-    time::interval,
+    pin, select,
+    signal::ctrl_c,
+    sync::{
+        mpsc::{UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    task::JoinSet,
+    time::{sleep_until, Instant},
+};
+use tracing::error;
+
+use super::{
+    ActiveTimer, Exit, GhostTimer, OnEvent, ScheduleEvent, SendEvent, TimerLiveness, UntypedEvent,
 };
 
-use super::{ActiveTimer, OnEvent, ScheduleEvent, SendEvent, UntypedEvent};
+pub mod bounded;
+pub mod metrics;
+pub mod pool;
+pub mod priority;
 
 pub mod erase {
     use crate::event::{Erase, UntypedEvent};
 
     pub type Sender<S, C> = Erase<S, C, super::UnboundedSender<UntypedEvent<S, C>>>;
 
+    pub type BoundedSender<S, C> = Erase<S, C, super::bounded::Sender<UntypedEvent<S, C>>>;
+
+    pub type BlockingSender<S, C> = Erase<S, C, super::bounded::BlockingSender<UntypedEvent<S, C>>>;
+
     pub type ScheduleState<S, C> = Erase<S, C, super::ScheduleState<UntypedEvent<S, C>>>;
 }
 
@@ -37,125 +59,388 @@ pub mod work {
     }
 }
 
-async fn must_recv<M>(receiver: &mut UnboundedReceiver<M>) -> anyhow::Result<M> {
+// the receiving side of event channels, so the runners below work with both unbounded and bounded
+// channels
+pub trait Receive<M> {
+    fn recv(&mut self) -> impl Future<Output = Option<M>> + Send;
+
+    // the already queued event if any, for draining on shutdown
+    fn try_recv(&mut self) -> Option<M>;
+
+    // the number of queued events
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<M: Send> Receive<M> for UnboundedReceiver<M> {
+    fn recv(&mut self) -> impl Future<Output = Option<M>> + Send {
+        UnboundedReceiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Option<M> {
+        UnboundedReceiver::try_recv(self).ok()
+    }
+
+    fn len(&self) -> usize {
+        UnboundedReceiver::len(self)
+    }
+}
+
+impl<M: Send> Receive<M> for bounded::Receiver<M> {
+    fn recv(&mut self) -> impl Future<Output = Option<M>> + Send {
+        bounded::Receiver::recv(self)
+    }
+
+    fn try_recv(&mut self) -> Option<M> {
+        bounded::Receiver::try_recv(self)
+    }
+
+    fn len(&self) -> usize {
+        bounded::Receiver::len(self)
+    }
+}
+
+// cooperative cancellation of event loops, usually shared by all loops of a process. once it is
+// triggered the runners below stop receiving, cancel the pending timers, handle the events that are
+// already queued, call `OnEvent::on_shutdown` and return `Ok(())`
+//
+// an event handler may also stop its own loop the same way (except the draining) by failing with
+// `Exit`
+#[derive(Debug, Clone)]
+pub struct Shutdown(Arc<watch::Sender<bool>>);
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(false).0))
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn trigger(&self) {
+        self.0.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.0.borrow()
+    }
+
+    pub async fn triggered(&self) {
+        // the sender is owned by `self` so waiting never fails
+        let _ = self.0.subscribe().wait_for(|triggered| *triggered).await;
+    }
+
+    // trigger on Ctrl-C, or SIGTERM on unix
+    pub async fn on_signal(&self) -> anyhow::Result<()> {
+        #[cfg(unix)]
+        {
+            use tokio::signal::unix::{signal, SignalKind};
+            let mut terminate = signal(SignalKind::terminate())?;
+            select! {
+                result = ctrl_c() => result?,
+                _ = terminate.recv() => {}
+            }
+        }
+        #[cfg(not(unix))]
+        ctrl_c().await?;
+        self.trigger();
+        Ok(())
+    }
+}
+
+// whether the tasks on this thread run deterministically, which is turned on by the simulated
+// runtime i.e. `net::task::sim::Network::block_on` and off everywhere else, even when the
+// `simulate` feature is enabled
+thread_local! {
+    static DETERMINISTIC: Cell<bool> = const { Cell::new(false) };
+}
+
+fn deterministic() -> bool {
+    DETERMINISTIC.get()
+}
+
+#[cfg(feature = "simulate")]
+pub(crate) fn set_deterministic(deterministic: bool) {
+    DETERMINISTIC.set(deterministic)
+}
+
+// the `select!` of the event loops below. the branches are polled in fixed order when running
+// deterministically, and in random order otherwise, so that e.g. a burst of due timers does not
+// starve the receiving
+macro_rules! select_loop {
+    ($($branches:tt)*) => {
+        if deterministic() {
+            select! { biased; $($branches)* }
+        } else {
+            select! { $($branches)* }
+        }
+    };
+}
+
+// whether the handler asks to stop the event loop with `Exit`
+fn exited(result: anyhow::Result<()>) -> anyhow::Result<bool> {
+    match result {
+        Ok(()) => Ok(false),
+        Err(err) if err.is::<Exit>() => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+// the pending timers are not drained but cancelled (by just being left behind), since they are not
+// due yet and would never be if the shutdown came a moment earlier
+fn drain<M, C>(
+    mut state: impl OnEvent<C, Event = M>,
+    context: &mut C,
+    receiver: &mut impl Receive<M>,
+) -> anyhow::Result<()> {
+    while let Some(event) = receiver.try_recv() {
+        if exited(state.on_event(event, context))? {
+            break;
+        }
+    }
+    state.on_shutdown(context)
+}
+
+async fn must_recv<M>(receiver: &mut impl Receive<M>) -> anyhow::Result<M> {
     receiver
         .recv()
         .await
         .ok_or(anyhow::format_err!("unexpected receive channel closed"))
 }
 
+// all timers of an event loop share a single sleep in `run_with_schedule`, which always sleeps
+// until the earliest deadline. setting and unsetting timer are O(log n) without spawning any task
 #[derive_where(Debug)]
 pub struct ScheduleState<M> {
     count: u32,
     #[derive_where(skip)]
     events: HashMap<u32, ScheduleEventState<M>>,
-    sender: UnboundedSender<u32>,
+    deadlines: BTreeSet<(Instant, u32)>,
+    metrics: Option<metrics::Scope>,
+}
+
+struct ScheduleEventState<M> {
+    deadline: Instant,
+    event: ScheduleEventFn<M>,
+    liveness: TimerLiveness,
 }
 
-type ScheduleEventState<M> = (AbortHandle, Box<dyn FnMut() -> M + Send>);
+enum ScheduleEventFn<M> {
+    Periodic(Duration, Box<dyn FnMut() -> M + Send>),
+    Once(Box<dyn FnOnce() -> M + Send>),
+}
 
-impl<M> ScheduleState<M> {
-    pub fn new(sender: UnboundedSender<u32>) -> Self {
+impl<M> Default for ScheduleState<M> {
+    fn default() -> Self {
         Self {
-            sender,
             count: 0,
             events: Default::default(),
+            deadlines: Default::default(),
+            metrics: None,
+        }
+    }
+}
+
+impl<M> ScheduleState<M> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // count the fired timers and track the number of active ones
+    pub fn instrument(&mut self, scope: metrics::Scope) {
+        self.metrics = Some(scope)
+    }
+
+    fn record_active(&self) {
+        if let Some(scope) = &self.metrics {
+            scope.set(metrics::TIMERS_ACTIVE, self.events.len() as _)
+        }
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+
+    // reschedule the earliest timer one period later (or remove it if it is one-shot), and produce
+    // its event
+    // a leaked timer is reported and removed instead, and the event loop goes on. it is not worth
+    // failing a running replica, while the model checking fails on it
+    fn fire(&mut self) -> Option<M> {
+        let (deadline, id) = self.deadlines.pop_first()?;
+        let Some(state) = self.events.get_mut(&id) else {
+            unreachable!("deadline of missing timer of id {id}")
+        };
+        if state.liveness.is_leaked() {
+            self.events.remove(&id);
+            error!("{}", GhostTimer(id));
+            self.record_active();
+            return None;
         }
+        let event = match &mut state.event {
+            ScheduleEventFn::Periodic(period, event) => {
+                // same to the default `MissedTickBehavior::Burst` of tokio's `interval`, which was
+                // used previously
+                state.deadline = deadline + *period;
+                self.deadlines.insert((state.deadline, id));
+                event()
+            }
+            ScheduleEventFn::Once(_) => {
+                let Some(ScheduleEventState {
+                    event: ScheduleEventFn::Once(event),
+                    ..
+                }) = self.events.remove(&id)
+                else {
+                    unreachable!()
+                };
+                event()
+            }
+        };
+        if let Some(scope) = &self.metrics {
+            scope.add(metrics::TIMERS_FIRED, None, 1)
+        }
+        self.record_active();
+        Some(event)
+    }
+
+    fn insert(&mut self, delay: Duration, event: ScheduleEventFn<M>) -> ActiveTimer {
+        self.count += 1;
+        let id = self.count;
+        let deadline = Instant::now() + delay;
+        self.deadlines.insert((deadline, id));
+        let (timer, liveness) = ActiveTimer::new(id);
+        let state = ScheduleEventState {
+            deadline,
+            event,
+            liveness,
+        };
+        self.events.insert(id, state);
+        self.record_active();
+        timer
     }
 }
 
 impl<M: Into<N> + Send + 'static, N> ScheduleEvent<M> for ScheduleState<N> {
     fn set_internal(
         &mut self,
-        period: std::time::Duration,
+        period: Duration,
         mut event: impl FnMut() -> M + Send + 'static,
     ) -> anyhow::Result<ActiveTimer> {
-        self.count += 1;
-        let id = self.count;
-        let sender = self.sender.clone();
-        let handle = spawn(async move {
-            let mut delay = interval(period);
-            delay.tick().await;
-            loop {
-                delay.tick().await;
-                if sender.send(id).is_err() {
-                    // log
-                    return;
-                }
-            }
-        })
-        .abort_handle();
-        self.events
-            .insert(id, (handle, Box::new(move || event().into())));
-        Ok(ActiveTimer(id))
+        anyhow::ensure!(!period.is_zero(), "zero timer period");
+        let event = ScheduleEventFn::Periodic(period, Box::new(move || event().into()));
+        Ok(self.insert(period, event))
     }
 
-    fn unset(&mut self, ActiveTimer(id): ActiveTimer) -> anyhow::Result<()> {
-        let Some((handle, _)) = self.events.remove(&id) else {
-            anyhow::bail!("missing event for {:?}", ActiveTimer(id))
+    fn set_internal_once(
+        &mut self,
+        delay: Duration,
+        event: impl FnOnce() -> M + Send + 'static,
+    ) -> anyhow::Result<ActiveTimer> {
+        let event = ScheduleEventFn::Once(Box::new(move || event().into()));
+        Ok(self.insert(delay, event))
+    }
+
+    fn unset(&mut self, timer: ActiveTimer) -> anyhow::Result<()> {
+        let id = timer.0;
+        let Some(state) = self.events.remove(&id) else {
+            anyhow::bail!("missing event for {timer:?}")
         };
-        handle.abort();
+        self.deadlines.remove(&(state.deadline, id));
+        self.record_active();
         Ok(())
     }
+
+    fn is_set(&self, timer: &ActiveTimer) -> bool {
+        self.events.contains_key(&timer.0)
+    }
 }
 
 pub async fn run_with_schedule<M, C>(
     mut state: impl OnEvent<C, Event = M>,
     context: &mut C,
-    receiver: &mut UnboundedReceiver<M>,
-    schedule_receiver: &mut UnboundedReceiver<u32>,
+    receiver: &mut impl Receive<M>,
     schedule_mut: impl Fn(&mut C) -> &mut ScheduleState<M>,
+    shutdown: &Shutdown,
 ) -> anyhow::Result<()> {
+    let sleep = sleep_until(Instant::now());
+    pin!(sleep);
+    let triggered = shutdown.triggered();
+    pin!(triggered);
     loop {
+        let deadline = schedule_mut(context).next_deadline();
+        if let Some(deadline) = deadline {
+            if deadline != sleep.deadline() {
+                sleep.as_mut().reset(deadline)
+            }
+        }
         enum Select<M> {
             Recv(M),
-            ScheduleRecv(u32),
+            Timeout,
+            Shutdown,
         }
-        match select! {
+        let result = match select_loop! {
+            () = &mut triggered => Select::Shutdown,
+            () = &mut sleep, if deadline.is_some() => Select::Timeout,
             recv = must_recv(receiver) => Select::Recv(recv?),
-            recv = must_recv(schedule_receiver) => Select::ScheduleRecv(recv?),
         } {
-            Select::Recv(event) => state.on_event(event, context)?,
-            Select::ScheduleRecv(id) => {
-                let Some((_, event)) = schedule_mut(context).events.get_mut(&id) else {
+            Select::Recv(event) => state.on_event(event, context),
+            Select::Timeout => {
+                let Some(event) = schedule_mut(context).fire() else {
                     continue;
                 };
-                state.on_event(event(), context)?
+                state.on_event(event, context)
             }
+            Select::Shutdown => return drain(state, context, receiver),
+        };
+        if exited(result)? {
+            return state.on_shutdown(context);
         }
     }
 }
 
 pub async fn run<M, C>(
-    state: impl OnEvent<C, Event = M>,
+    mut state: impl OnEvent<C, Event = M>,
     context: &mut C,
-    receiver: &mut UnboundedReceiver<M>,
+    receiver: &mut impl Receive<M>,
+    shutdown: &Shutdown,
 ) -> anyhow::Result<()> {
-    let (_sender, mut schedule_receiver) = unbounded_channel();
-    run_with_schedule(
-        state,
-        context,
-        receiver,
-        &mut schedule_receiver,
-        |_| unreachable!(),
-    )
-    .await
+    let triggered = shutdown.triggered();
+    pin!(triggered);
+    loop {
+        let event = select_loop! {
+            () = &mut triggered => return drain(state, context, receiver),
+            recv = must_recv(receiver) => recv?,
+        };
+        if exited(state.on_event(event, context))? {
+            return state.on_shutdown(context);
+        }
+    }
 }
 
 pub async fn run_worker<S: Clone + Send + 'static, C: Clone + Send + 'static>(
     state: S,
     context: C,
-    receiver: &mut UnboundedReceiver<UntypedEvent<S, C>>,
+    receiver: &mut impl Receive<UntypedEvent<S, C>>,
+    shutdown: &Shutdown,
 ) -> anyhow::Result<()> {
     let mut tasks = JoinSet::new();
+    let triggered = shutdown.triggered();
+    pin!(triggered);
     loop {
         enum Select<M> {
             Recv(M),
             JoinNext(()),
+            Shutdown,
         }
-        match select! {
+        match select_loop! {
+            () = &mut triggered => Select::Shutdown,
+            Some(result) = tasks.join_next() => Select::JoinNext(result??),
             recv = must_recv(receiver) => Select::Recv(recv?),
-            Some(result) = tasks.join_next() => Select::JoinNext(result??)
         } {
             Select::Recv(UntypedEvent(event)) => {
                 let mut state = state.clone();
@@ -163,6 +448,143 @@ pub async fn run_worker<S: Clone + Send + 'static, C: Clone + Send + 'static>(
                 tasks.spawn(async move { event(&mut state, &mut context) });
             }
             Select::JoinNext(()) => {}
+            Select::Shutdown => break,
         }
     }
+    // the queued works are spawned as well, and all of them finish before returning, same as `pool`
+    while let Some(UntypedEvent(event)) = receiver.try_recv() {
+        let mut state = state.clone();
+        let mut context = context.clone();
+        tasks.spawn(async move { event(&mut state, &mut context) });
+    }
+    while let Some(result) = tasks.join_next().await {
+        result??
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::event::{Exit, OnEvent, ScheduleEvent, SendEvent, Submit};
+
+    use super::{run, run_with_schedule, run_worker, ScheduleState, Shutdown};
+
+    struct Count(usize);
+
+    impl OnEvent<ScheduleState<()>> for Count {
+        type Event = ();
+
+        fn on_event(&mut self, (): (), context: &mut ScheduleState<()>) -> anyhow::Result<()> {
+            self.0 += 1;
+            if self.0 == 3 {
+                // leak a timer by dropping its handle
+                let _ = context.set(Duration::from_millis(1), ())?;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn ghost_timer() -> anyhow::Result<()> {
+        let mut schedule = ScheduleState::new();
+        let timer = schedule.set(Duration::from_millis(1), ())?;
+        let once = schedule.set_once(Duration::from_millis(1), ())?;
+        let (_sender, mut receiver) = unbounded_channel();
+        let mut count = Count(0);
+        let shutdown = Shutdown::new();
+        tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                shutdown.trigger()
+            }
+        });
+        // the leaked timer is reported and skipped, without failing the loop
+        run_with_schedule(
+            &mut count,
+            &mut schedule,
+            &mut receiver,
+            |schedule| schedule,
+            &shutdown,
+        )
+        .await?;
+        anyhow::ensure!(count.0 > 3);
+        anyhow::ensure!(schedule.events.len() == 1);
+        // the fired one-shot timer is gone, while the periodic one is still there
+        anyhow::ensure!(ScheduleEvent::<()>::unset(&mut schedule, once).is_err());
+        ScheduleEvent::<()>::unset(&mut schedule, timer)
+    }
+
+    #[derive(Default)]
+    struct Collect {
+        events: Vec<u32>,
+        flushed: bool,
+    }
+
+    impl OnEvent<()> for Collect {
+        type Event = u32;
+
+        fn on_event(&mut self, event: u32, (): &mut ()) -> anyhow::Result<()> {
+            anyhow::ensure!(!self.flushed);
+            if event == 0 {
+                anyhow::bail!(Exit)
+            }
+            self.events.push(event);
+            Ok(())
+        }
+
+        fn on_shutdown(&mut self, (): &mut ()) -> anyhow::Result<()> {
+            self.flushed = true;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn shutdown() -> anyhow::Result<()> {
+        // the queued events are drained before the final flush
+        let (sender, mut receiver) = unbounded_channel();
+        let shutdown = Shutdown::new();
+        for event in 1..=3 {
+            sender.send(event)?
+        }
+        shutdown.trigger();
+        let mut state = Collect::default();
+        run(&mut state, &mut (), &mut receiver, &shutdown).await?;
+        anyhow::ensure!(state.events == [1, 2, 3] && state.flushed);
+
+        // while `Exit` stops the loop right away
+        for event in [4, 0, 5] {
+            sender.send(event)?
+        }
+        let mut state = Collect::default();
+        run(&mut state, &mut (), &mut receiver, &Shutdown::new()).await?;
+        anyhow::ensure!(state.events == [4] && state.flushed);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_with_queued_works() -> anyhow::Result<()> {
+        let (mut sender, mut receiver) = unbounded_channel();
+        let (result_sender, mut result_receiver) = unbounded_channel();
+        for index in 0..8usize {
+            Submit::<(), tokio::sync::mpsc::UnboundedSender<usize>>::submit(
+                &mut sender,
+                Box::new(move |(), context| context.send(index)),
+            )?
+        }
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        run_worker((), result_sender, &mut receiver, &shutdown).await?;
+        let mut indexes = Vec::new();
+        while let Some(index) = result_receiver.recv().await {
+            indexes.push(index)
+        }
+        indexes.sort();
+        anyhow::ensure!(indexes == (0..8).collect::<Vec<_>>());
+        Ok(())
+    }
 }
@@ -0,0 +1,362 @@
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering::SeqCst},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+};
+
+use derive_more::{Display, Error};
+use tokio::{pin, runtime::Handle, sync::Notify};
+
+use crate::event::{SendEvent, Submit, UntypedEvent, Work};
+
+// bounded event channel, as a replacement of tokio's unbounded one when the receiving side may fall
+// behind. what to do on a full channel is decided per channel: reject or drop the oldest with
+// `channel`, or block with `blocking_channel`
+//
+// tokio's bounded channel is not used because `SendEvent::send` is synchronous, so it could only
+// `try_send`, and there is no way to drop the oldest event from the sending side
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Overflow {
+    // fail the sending with `Rejected`
+    Reject,
+    // make room by dropping the oldest queued event, the sending always succeeds
+    DropOldest,
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "event rejected by full channel")]
+pub struct Rejected;
+
+// the load shedding outcomes, shared by all senders and the receiver of a channel
+#[derive(Debug, Default)]
+pub struct Stats {
+    pub sent: AtomicU64,
+    pub rejected: AtomicU64,
+    pub dropped: AtomicU64,
+    // the number of sendings that have been blocked, regardless of for how long
+    pub blocked: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Shared<M> {
+    queue: Mutex<VecDeque<M>>,
+    capacity: usize,
+    overflow: Overflow,
+    not_empty: Notify,
+    // for `Receiver::blocking_recv`
+    not_empty_blocking: Condvar,
+    // for the `BlockingSender`s, that wait on plain threads and in async context respectively
+    not_full: Condvar,
+    not_full_async: Notify,
+    num_sender: AtomicUsize,
+    closed: AtomicBool,
+    stats: Arc<Stats>,
+}
+
+#[derive(Debug)]
+pub struct Sender<M>(Arc<Shared<M>>);
+
+#[derive(Debug)]
+pub struct Receiver<M>(Arc<Shared<M>>);
+
+pub fn channel<M>(capacity: usize, overflow: Overflow) -> (Sender<M>, Receiver<M>) {
+    assert!(capacity > 0);
+    let shared = Arc::new(Shared {
+        // the capacity may be practically unbounded e.g. `usize::MAX`
+        queue: Mutex::new(VecDeque::with_capacity(capacity.min(1024))),
+        capacity,
+        overflow,
+        not_empty: Notify::new(),
+        not_empty_blocking: Condvar::new(),
+        not_full: Condvar::new(),
+        not_full_async: Notify::new(),
+        num_sender: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        stats: Default::default(),
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+impl<M> Clone for Sender<M> {
+    fn clone(&self) -> Self {
+        self.0.num_sender.fetch_add(1, SeqCst);
+        Self(self.0.clone())
+    }
+}
+
+impl<M> Drop for Sender<M> {
+    fn drop(&mut self) {
+        if self.0.num_sender.fetch_sub(1, SeqCst) == 1 {
+            self.0.not_empty.notify_one();
+            // under the lock, so a blocking receiver between its checking and waiting is not missed
+            let _queue = self.0.queue.lock().unwrap();
+            self.0.not_empty_blocking.notify_all()
+        }
+    }
+}
+
+impl<M> Drop for Receiver<M> {
+    fn drop(&mut self) {
+        self.0.closed.store(true, SeqCst);
+        self.0.not_full.notify_all();
+        self.0.not_full_async.notify_waiters()
+    }
+}
+
+impl<M> Shared<M> {
+    fn push(&self, mut queue: MutexGuard<'_, VecDeque<M>>, event: M) {
+        queue.push_back(event);
+        self.stats.sent.fetch_add(1, SeqCst);
+        drop(queue);
+        self.not_empty.notify_one();
+        self.not_empty_blocking.notify_one()
+    }
+}
+
+impl<M> Sender<M> {
+    pub fn stats(&self) -> Arc<Stats> {
+        self.0.stats.clone()
+    }
+}
+
+impl<M: Into<N>, N> SendEvent<M> for Sender<N> {
+    fn send(&mut self, event: M) -> anyhow::Result<()> {
+        let shared = &*self.0;
+        let mut queue = shared.queue.lock().unwrap();
+        if shared.closed.load(SeqCst) {
+            anyhow::bail!("unexpected send channel closed")
+        }
+        if queue.len() == shared.capacity {
+            match shared.overflow {
+                Overflow::Reject => {
+                    shared.stats.rejected.fetch_add(1, SeqCst);
+                    anyhow::bail!(Rejected)
+                }
+                Overflow::DropOldest => {
+                    queue.pop_front();
+                    shared.stats.dropped.fetch_add(1, SeqCst);
+                }
+            }
+        }
+        shared.push(queue, event.into());
+        Ok(())
+    }
+}
+
+impl<S, C> Submit<S, C> for Sender<UntypedEvent<S, C>> {
+    fn submit(&mut self, work: Work<S, C>) -> anyhow::Result<()> {
+        SendEvent::send(self, UntypedEvent(work))
+    }
+}
+
+impl<M> Receiver<M> {
+    pub fn stats(&self) -> Arc<Stats> {
+        self.0.stats.clone()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.queue.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn try_recv(&mut self) -> Option<M> {
+        let event = self.0.queue.lock().unwrap().pop_front()?;
+        self.0.not_full.notify_one();
+        self.0.not_full_async.notify_one();
+        Some(event)
+    }
+
+    // `None` after all senders are dropped and the queued events are drained
+    pub async fn recv(&mut self) -> Option<M> {
+        loop {
+            if let Some(event) = self.try_recv() {
+                return Some(event);
+            }
+            if self.0.num_sender.load(SeqCst) == 0 {
+                // a sending may have sneaked in before the last sender is dropped
+                return self.try_recv();
+            }
+            // a notification between the check above and here is kept as a permit, so it's not
+            // missed
+            self.0.not_empty.notified().await
+        }
+    }
+
+    // for the receivers on plain threads e.g. the workers of `pool`
+    pub fn blocking_recv(&mut self) -> Option<M> {
+        let shared = &*self.0;
+        let mut queue = shared.queue.lock().unwrap();
+        loop {
+            if let Some(event) = queue.pop_front() {
+                drop(queue);
+                shared.not_full.notify_one();
+                shared.not_full_async.notify_one();
+                return Some(event);
+            }
+            if shared.num_sender.load(SeqCst) == 0 {
+                return None;
+            }
+            queue = shared.not_empty_blocking.wait(queue).unwrap()
+        }
+    }
+}
+
+// the channel that makes the sending wait until there's room, instead of an `Overflow` policy
+//
+// blocking a thread that runs an event loop would stall it, or deadlock a single threaded runtime
+// that the receiver runs on as well. so the senders in async context wait with `send`, and the
+// `SendEvent` sending of `BlockingSender` is `blocking_send`, which is for plain threads e.g. the
+// contexts of pool workers, and fails in async context
+pub fn blocking_channel<M>(capacity: usize) -> (BlockingSender<M>, Receiver<M>) {
+    // the overflow policy is never applied, the sending waits for room instead
+    let (sender, receiver) = channel(capacity, Overflow::Reject);
+    (BlockingSender(sender), receiver)
+}
+
+#[derive(Debug)]
+pub struct BlockingSender<M>(Sender<M>);
+
+impl<M> Clone for BlockingSender<M> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<M> BlockingSender<M> {
+    pub fn stats(&self) -> Arc<Stats> {
+        self.0.stats()
+    }
+
+    pub async fn send(&mut self, event: M) -> anyhow::Result<()> {
+        let shared = &*self.0 .0;
+        let mut blocked = false;
+        loop {
+            // registered before checking, so the room made in between is not missed
+            let not_full = shared.not_full_async.notified();
+            pin!(not_full);
+            not_full.as_mut().enable();
+            {
+                let queue = shared.queue.lock().unwrap();
+                if shared.closed.load(SeqCst) {
+                    anyhow::bail!("unexpected send channel closed")
+                }
+                if queue.len() < shared.capacity {
+                    shared.push(queue, event);
+                    return Ok(());
+                }
+            }
+            if !blocked {
+                blocked = true;
+                shared.stats.blocked.fetch_add(1, SeqCst);
+            }
+            not_full.await
+        }
+    }
+
+    // fails instead of blocking a thread that runs an async runtime
+    pub fn blocking_send(&mut self, event: M) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            Handle::try_current().is_err(),
+            "blocking send in async context"
+        );
+        let shared = &*self.0 .0;
+        let mut queue = shared.queue.lock().unwrap();
+        if queue.len() == shared.capacity && !shared.closed.load(SeqCst) {
+            shared.stats.blocked.fetch_add(1, SeqCst);
+            queue = shared
+                .not_full
+                .wait_while(queue, |queue| {
+                    queue.len() == shared.capacity && !shared.closed.load(SeqCst)
+                })
+                .unwrap();
+        }
+        if shared.closed.load(SeqCst) {
+            anyhow::bail!("unexpected send channel closed")
+        }
+        shared.push(queue, event);
+        Ok(())
+    }
+}
+
+impl<M: Into<N>, N> SendEvent<M> for BlockingSender<N> {
+    fn send(&mut self, event: M) -> anyhow::Result<()> {
+        self.blocking_send(event.into())
+    }
+}
+
+impl<S, C> Submit<S, C> for BlockingSender<UntypedEvent<S, C>> {
+    fn submit(&mut self, work: Work<S, C>) -> anyhow::Result<()> {
+        SendEvent::send(self, UntypedEvent(work))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{thread, time::Duration};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn overflow() -> anyhow::Result<()> {
+        let (mut sender, mut receiver) = channel(2, Overflow::Reject);
+        sender.send(1)?;
+        sender.send(2)?;
+        anyhow::ensure!(sender.send(3).is_err_and(|err| err.is::<Rejected>()));
+        anyhow::ensure!(receiver.recv().await == Some(1));
+        sender.send(4)?;
+        anyhow::ensure!(receiver.stats().rejected.load(SeqCst) == 1);
+
+        let (mut sender, mut receiver) = channel(2, Overflow::DropOldest);
+        for i in 0..5 {
+            sender.send(i)?
+        }
+        drop(sender);
+        anyhow::ensure!(receiver.recv().await == Some(3));
+        anyhow::ensure!(receiver.recv().await == Some(4));
+        anyhow::ensure!(receiver.recv().await.is_none());
+        anyhow::ensure!(receiver.stats().dropped.load(SeqCst) == 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn blocking() -> anyhow::Result<()> {
+        let (mut sender, mut receiver) = blocking_channel(1);
+        let stats = sender.stats();
+        let mut thread_sender = sender.clone();
+        let sending =
+            thread::spawn(move || (0..3).try_for_each(|i| thread_sender.blocking_send(i)));
+        for i in 0..3 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            anyhow::ensure!(receiver.recv().await == Some(i))
+        }
+        sending.join().unwrap()?;
+        anyhow::ensure!(stats.blocked.load(SeqCst) > 0);
+        anyhow::ensure!(sender.blocking_send(3).is_err());
+
+        // the sending and the receiving on the same thread of the (current thread) runtime
+        let sending = async {
+            for i in 0..3 {
+                sender.send(i).await?
+            }
+            drop(sender);
+            anyhow::Ok(())
+        };
+        let receiving = async {
+            let mut received = Vec::new();
+            while let Some(i) = receiver.recv().await {
+                received.push(i)
+            }
+            received
+        };
+        let (result, received) = tokio::join!(sending, receiving);
+        result?;
+        anyhow::ensure!(received == [0, 1, 2]);
+        Ok(())
+    }
+}
@@ -0,0 +1,610 @@
+use std::{
+    any::type_name,
+    collections::BTreeMap,
+    fmt::Write as _,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering::Relaxed},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use derive_more::{Deref, DerefMut};
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt as _, AsyncWriteExt as _},
+    net::{TcpListener, TcpStream},
+    spawn,
+};
+use tracing::debug;
+
+use crate::event::{take_handling, OnEvent, Submit, Work};
+
+use super::Receive;
+
+// counters, gauges and latency histograms of event loops, shared by all loops of a process and
+// labeled by the loop's name, and by the event type where it applies. a loop is instrumented by
+// wrapping its state, receiver and worker submitter (e.g. `pool::Sender` or `work::Sender` of
+// `run_worker`) into `Instrumented` with the loop's `Scope`, by `ScheduleState::instrument` for
+// the timers, and by `codec::DecodeStats::instrumented` for the dropped incoming messages
+//
+// the erased events are told apart by their type names through `event::mark_handling`, while the
+// other events of typed states are accounted to the state's event type
+//
+// the registry is only locked when a scope meets a metric (of an event type) for the first time,
+// and on exporting. afterward the scope updates its cached atomics without any locking
+
+pub const EVENTS: &str = "neatworks_events_total";
+pub const EVENT_SECONDS: &str = "neatworks_event_seconds";
+pub const QUEUE_DEPTH: &str = "neatworks_queue_depth";
+pub const TIMERS_FIRED: &str = "neatworks_timers_fired_total";
+pub const TIMERS_ACTIVE: &str = "neatworks_timers_active";
+pub const WORKS: &str = "neatworks_works_total";
+pub const WORK_SECONDS: &str = "neatworks_work_seconds";
+pub const WORKS_QUEUED: &str = "neatworks_works_queued";
+// the received messages that are dropped by `codec::on_malformed`
+pub const MESSAGES_MALFORMED: &str = "neatworks_messages_malformed_total";
+pub const MESSAGES_REJECTED: &str = "neatworks_messages_rejected_total";
+pub const MESSAGES_QUARANTINED: &str = "neatworks_messages_quarantined_total";
+
+// the upper bound of the i-th bucket is 2^i microseconds, up to about 8 seconds, and the last
+// bucket is unbounded
+const NUM_BUCKET: usize = 24;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct Histogram {
+    // not cumulative
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum_seconds: f64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Self {
+            buckets: vec![0; NUM_BUCKET + 1],
+            count: 0,
+            sum_seconds: 0.,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct AtomicHistogram {
+    buckets: [AtomicU64; NUM_BUCKET + 1],
+    count: AtomicU64,
+    sum_nanos: AtomicU64,
+}
+
+impl Default for AtomicHistogram {
+    fn default() -> Self {
+        Self {
+            buckets: std::array::from_fn(|_| Default::default()),
+            count: Default::default(),
+            sum_nanos: Default::default(),
+        }
+    }
+}
+
+impl AtomicHistogram {
+    fn observe(&self, duration: Duration) {
+        let micros = duration.as_nanos().div_ceil(1000).max(1);
+        let index = micros.next_power_of_two().trailing_zeros() as usize;
+        self.buckets[index.min(NUM_BUCKET)].fetch_add(1, Relaxed);
+        self.count.fetch_add(1, Relaxed);
+        self.sum_nanos.fetch_add(duration.as_nanos() as _, Relaxed);
+    }
+
+    fn load(&self) -> Histogram {
+        Histogram {
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Relaxed))
+                .collect(),
+            count: self.count.load(Relaxed),
+            sum_seconds: Duration::from_nanos(self.sum_nanos.load(Relaxed)).as_secs_f64(),
+        }
+    }
+}
+
+impl Histogram {
+    fn upper_bound(index: usize) -> Option<f64> {
+        if index == NUM_BUCKET {
+            None
+        } else {
+            Some((1u64 << index) as f64 / 1e6)
+        }
+    }
+}
+
+type Key = (&'static str, Vec<(&'static str, String)>);
+
+#[derive(Debug, Default)]
+struct Registry {
+    counters: BTreeMap<Key, Arc<AtomicU64>>,
+    gauges: BTreeMap<Key, Arc<AtomicI64>>,
+    histograms: BTreeMap<Key, Arc<AtomicHistogram>>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Metrics(Arc<Mutex<Registry>>);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Sample<T> {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: T,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Snapshot {
+    pub counters: Vec<Sample<u64>>,
+    pub gauges: Vec<Sample<i64>>,
+    pub histograms: Vec<Sample<Histogram>>,
+}
+
+fn samples<T, U>(metrics: &BTreeMap<Key, Arc<T>>, load: impl Fn(&T) -> U) -> Vec<Sample<U>> {
+    metrics
+        .iter()
+        .map(|((name, labels), value)| Sample {
+            name: name.to_string(),
+            labels: labels
+                .iter()
+                .map(|(label, value)| (label.to_string(), value.clone()))
+                .collect(),
+            value: load(value),
+        })
+        .collect()
+}
+
+fn write_labels(out: &mut String, labels: &[(&str, String)], le: Option<&str>) {
+    let labels = labels
+        .iter()
+        .map(|(label, value)| (*label, value.as_str()))
+        .chain(le.map(|le| ("le", le)))
+        .map(|(label, value)| {
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace('\n', "\\n");
+            format!("{label}=\"{value}\"")
+        })
+        .collect::<Vec<_>>();
+    if !labels.is_empty() {
+        write!(out, "{{{}}}", labels.join(",")).unwrap()
+    }
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn scope(&self, name: impl Into<String>) -> Scope {
+        Scope(Arc::new(ScopeState {
+            metrics: self.clone(),
+            name: name.into(),
+            counters: Default::default(),
+            gauges: Default::default(),
+            histograms: Default::default(),
+        }))
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let registry = self.0.lock().unwrap();
+        Snapshot {
+            counters: samples(&registry.counters, |value| value.load(Relaxed)),
+            gauges: samples(&registry.gauges, |value| value.load(Relaxed)),
+            histograms: samples(&registry.histograms, AtomicHistogram::load),
+        }
+    }
+
+    // the text exposition format of Prometheus
+    pub fn prometheus(&self) -> String {
+        let registry = self.0.lock().unwrap();
+        let mut out = String::new();
+        let mut typed = None;
+        let mut write_type = |out: &mut String, name: &'static str, kind| {
+            if typed != Some(name) {
+                writeln!(out, "# TYPE {name} {kind}").unwrap();
+                typed = Some(name)
+            }
+        };
+        for ((name, labels), value) in &registry.counters {
+            write_type(&mut out, name, "counter");
+            out += name;
+            write_labels(&mut out, labels, None);
+            writeln!(out, " {}", value.load(Relaxed)).unwrap()
+        }
+        for ((name, labels), value) in &registry.gauges {
+            write_type(&mut out, name, "gauge");
+            out += name;
+            write_labels(&mut out, labels, None);
+            writeln!(out, " {}", value.load(Relaxed)).unwrap()
+        }
+        for ((name, labels), histogram) in &registry.histograms {
+            let histogram = histogram.load();
+            write_type(&mut out, name, "histogram");
+            let mut cumulative = 0;
+            for (index, count) in histogram.buckets.iter().enumerate() {
+                cumulative += count;
+                let le = Histogram::upper_bound(index)
+                    .map(|bound| bound.to_string())
+                    .unwrap_or("+Inf".into());
+                write!(out, "{name}_bucket").unwrap();
+                write_labels(&mut out, labels, Some(&le));
+                writeln!(out, " {cumulative}").unwrap()
+            }
+            write!(out, "{name}_sum").unwrap();
+            write_labels(&mut out, labels, None);
+            writeln!(out, " {}", histogram.sum_seconds).unwrap();
+            write!(out, "{name}_count").unwrap();
+            write_labels(&mut out, labels, None);
+            writeln!(out, " {}", histogram.count).unwrap()
+        }
+        out
+    }
+}
+
+// the metrics of a single event loop
+#[derive(Debug, Clone)]
+pub struct Scope(Arc<ScopeState>);
+
+#[derive(Debug)]
+struct ScopeState {
+    metrics: Metrics,
+    name: String,
+    counters: Cache<AtomicU64>,
+    gauges: Cache<AtomicI64>,
+    histograms: Cache<AtomicHistogram>,
+}
+
+// metric name and event type name -> the registered metric
+type Cache<T> = scc::HashMap<(&'static str, Option<&'static str>), Arc<T>>;
+
+impl ScopeState {
+    fn key(&self, name: &'static str, event: Option<&str>) -> Key {
+        let mut labels = vec![("loop", self.name.clone())];
+        if let Some(event) = event {
+            labels.push(("event", event.into()))
+        }
+        (name, labels)
+    }
+
+    fn with<T: Default, R>(
+        &self,
+        cache: &Cache<T>,
+        registered: fn(&mut Registry) -> &mut BTreeMap<Key, Arc<T>>,
+        name: &'static str,
+        event: Option<&'static str>,
+        f: impl FnOnce(&T) -> R,
+    ) -> R {
+        let mut f = Some(f);
+        if let Some(result) = cache.read(&(name, event), |_, metric| f.take().unwrap()(metric)) {
+            return result;
+        }
+        let metric = registered(&mut self.metrics.0.lock().unwrap())
+            .entry(self.key(name, event))
+            .or_default()
+            .clone();
+        let result = f.take().unwrap()(&metric);
+        // another thread may have cached the same metric meanwhile, which is fine
+        let _ = cache.insert((name, event), metric);
+        result
+    }
+}
+
+impl Scope {
+    pub fn add(&self, name: &'static str, event: Option<&'static str>, n: u64) {
+        let scope = &self.0;
+        scope.with(
+            &scope.counters,
+            |registry| &mut registry.counters,
+            name,
+            event,
+            |counter| counter.fetch_add(n, Relaxed),
+        );
+    }
+
+    pub fn set(&self, name: &'static str, value: i64) {
+        let scope = &self.0;
+        scope.with(
+            &scope.gauges,
+            |registry| &mut registry.gauges,
+            name,
+            None,
+            |gauge| gauge.store(value, Relaxed),
+        )
+    }
+
+    pub fn shift(&self, name: &'static str, delta: i64) {
+        let scope = &self.0;
+        scope.with(
+            &scope.gauges,
+            |registry| &mut registry.gauges,
+            name,
+            None,
+            |gauge| gauge.fetch_add(delta, Relaxed),
+        );
+    }
+
+    // count the occurrence into `counter` and its duration into `histogram` together
+    pub fn observe(
+        &self,
+        counter: &'static str,
+        histogram: &'static str,
+        event: Option<&'static str>,
+        duration: Duration,
+    ) {
+        self.add(counter, event, 1);
+        let scope = &self.0;
+        scope.with(
+            &scope.histograms,
+            |registry| &mut registry.histograms,
+            histogram,
+            event,
+            |histogram| histogram.observe(duration),
+        )
+    }
+}
+
+// instrumentation is optional, so deployments can always wrap, and only pay for the bookkeeping
+// when asked for, as `journal::Record` does
+#[derive(Debug, Clone, Deref, DerefMut)]
+pub struct Instrumented<T> {
+    #[deref]
+    #[deref_mut]
+    pub inner: T,
+    scope: Option<Scope>,
+}
+
+impl<T> Instrumented<T> {
+    pub fn new(inner: T, scope: Scope) -> Self {
+        Self {
+            inner,
+            scope: Some(scope),
+        }
+    }
+
+    pub fn disabled(inner: T) -> Self {
+        Self { inner, scope: None }
+    }
+
+    pub fn optional(inner: T, scope: Option<Scope>) -> Self {
+        Self { inner, scope }
+    }
+}
+
+impl<S: OnEvent<C>, C> OnEvent<C> for Instrumented<S> {
+    type Event = S::Event;
+
+    fn on_event(&mut self, event: Self::Event, context: &mut C) -> anyhow::Result<()> {
+        let Some(scope) = &self.scope else {
+            return self.inner.on_event(event, context);
+        };
+        // clear the mark left by events that are not handled by this loop e.g. handled inline
+        take_handling();
+        let start = Instant::now();
+        let result = self.inner.on_event(event, context);
+        let elapsed = start.elapsed();
+        let event = take_handling().unwrap_or(type_name::<S::Event>());
+        scope.observe(EVENTS, EVENT_SECONDS, Some(event), elapsed);
+        result
+    }
+
+    fn on_shutdown(&mut self, context: &mut C) -> anyhow::Result<()> {
+        self.inner.on_shutdown(context)
+    }
+}
+
+impl<R: Receive<M> + Send, M: Send> Receive<M> for Instrumented<R> {
+    async fn recv(&mut self) -> Option<M> {
+        let event = self.inner.recv().await;
+        self.record_depth();
+        event
+    }
+
+    fn try_recv(&mut self) -> Option<M> {
+        let event = self.inner.try_recv();
+        self.record_depth();
+        event
+    }
+
+    fn len(&self) -> usize {
+        self.inner.len()
+    }
+}
+
+impl<T> Instrumented<T> {
+    fn record_depth<M>(&self)
+    where
+        T: Receive<M>,
+    {
+        if let Some(scope) = &self.scope {
+            scope.set(QUEUE_DEPTH, self.inner.len() as _)
+        }
+    }
+}
+
+// the works are not typed, so they are accounted as a whole. the queued works are counted from
+// submitting to starting, which is the queue depth of the worker pool
+impl<T: Submit<S, C>, S: 'static, C: 'static> Submit<S, C> for Instrumented<T> {
+    fn submit(&mut self, work: Work<S, C>) -> anyhow::Result<()> {
+        let Some(scope) = &self.scope else {
+            return self.inner.submit(work);
+        };
+        scope.shift(WORKS_QUEUED, 1);
+        let scope = scope.clone();
+        self.inner.submit(Box::new(move |state, context| {
+            scope.shift(WORKS_QUEUED, -1);
+            let start = Instant::now();
+            let result = work(state, context);
+            scope.observe(WORKS, WORK_SECONDS, None, start.elapsed());
+            result
+        }))
+    }
+}
+
+// `/metrics` in the Prometheus text format, and `/metrics.json` as JSON `Snapshot`, over bare
+// HTTP/1.0 with one request per connection, for local scraping and debugging only
+//
+// the listener is bound by the caller, so binding errors surface before the serving is spawned
+pub async fn serve(metrics: Metrics, listener: TcpListener) -> anyhow::Result<()> {
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        spawn(async move {
+            if let Err(err) = respond(&mut stream, &metrics).await {
+                debug!("metrics request failed: {err}")
+            }
+        });
+    }
+}
+
+async fn respond(stream: &mut TcpStream, metrics: &Metrics) -> anyhow::Result<()> {
+    let mut buf = vec![0; 4096];
+    let mut len = 0;
+    while !buf[..len].windows(4).any(|window| window == b"\r\n\r\n") {
+        anyhow::ensure!(len < buf.len(), "request too large");
+        let num_read = stream.read(&mut buf[len..]).await?;
+        anyhow::ensure!(num_read > 0, "unexpected connection closed");
+        len += num_read
+    }
+    let path = std::str::from_utf8(&buf[..len])?.split_whitespace().nth(1);
+    let (status, content_type, body) = match path {
+        Some("/metrics") => ("200 OK", "text/plain; version=0.0.4", metrics.prometheus()),
+        Some("/metrics.json") => (
+            "200 OK",
+            "application/json",
+            serde_json::to_string(&metrics.snapshot())?,
+        ),
+        _ => ("404 Not Found", "text/plain", "not found\n".into()),
+    };
+    let response = format!(
+        "HTTP/1.0 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use crate::event::{
+        task::{run, run_worker, Shutdown},
+        Erase, Exit, OnErasedEvent, SendEvent, Untyped, UntypedEvent,
+    };
+
+    use super::*;
+
+    struct Sum(u32);
+
+    struct Add(u32);
+
+    impl<C> OnErasedEvent<Add, C> for Sum {
+        fn on_event(&mut self, Add(n): Add, _: &mut C) -> anyhow::Result<()> {
+            if n == 0 {
+                anyhow::bail!(Exit)
+            }
+            self.0 += n;
+            Ok(())
+        }
+    }
+
+    fn counter(snapshot: &Snapshot, name: &str, event: &str) -> Option<u64> {
+        snapshot
+            .counters
+            .iter()
+            .find(|sample| {
+                sample.name == name
+                    && sample
+                        .labels
+                        .get("event")
+                        .is_some_and(|label| label.ends_with(event))
+            })
+            .map(|sample| sample.value)
+    }
+
+    #[tokio::test]
+    async fn instrumented_loop() -> anyhow::Result<()> {
+        let metrics = Metrics::new();
+        let scope = metrics.scope("test");
+        let (sender, receiver) = unbounded_channel();
+        let mut sender = Erase::<Sum, (), _>::new(sender);
+        for n in [1, 2, 3, 0] {
+            sender.send(Add(n))?
+        }
+        let mut state = Instrumented::new(Untyped::new(Sum(0)), scope.clone());
+        let mut receiver = Instrumented::new(receiver, scope.clone());
+        run(&mut state, &mut (), &mut receiver, &Shutdown::new()).await?;
+        anyhow::ensure!(state.0 .0 == 6);
+
+        let (sender, mut receiver) = unbounded_channel::<UntypedEvent<Sum, ()>>();
+        let mut sender = Instrumented::new(sender, scope);
+        sender.submit(Box::new(|state, ()| {
+            state.0 = 0;
+            Ok(())
+        }))?;
+        anyhow::ensure!(metrics
+            .snapshot()
+            .gauges
+            .iter()
+            .any(|sample| sample.name == WORKS_QUEUED && sample.value == 1));
+        let UntypedEvent(work) = receiver.recv().await.unwrap();
+        work(&mut state.0, &mut ())?;
+
+        let snapshot = metrics.snapshot();
+        anyhow::ensure!(counter(&snapshot, EVENTS, "::Add") == Some(4));
+        anyhow::ensure!(snapshot
+            .gauges
+            .iter()
+            .all(|sample| sample.value == 0 && sample.labels["loop"] == "test"));
+        anyhow::ensure!(snapshot.counters.iter().any(|sample| sample.name == WORKS));
+        let text = metrics.prometheus();
+        anyhow::ensure!(text.contains("# TYPE neatworks_event_seconds histogram"));
+        anyhow::ensure!(text.contains("le=\"+Inf\"} 4"));
+        anyhow::ensure!(serde_json::to_string(&snapshot)?.contains(WORK_SECONDS));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn instrumented_worker() -> anyhow::Result<()> {
+        let metrics = Metrics::new();
+        let scope = metrics.scope("worker");
+        let (sender, receiver) = unbounded_channel::<UntypedEvent<(), ()>>();
+        let mut sender = Instrumented::new(sender, scope.clone());
+        for _ in 0..4 {
+            sender.submit(Box::new(|_, _| Ok(())))?
+        }
+        let mut receiver = Instrumented::new(receiver, scope);
+        let shutdown = Shutdown::new();
+        let worker = run_worker((), (), &mut receiver, &shutdown);
+        let check = async {
+            let works = |snapshot: Snapshot| {
+                let sample = snapshot
+                    .counters
+                    .into_iter()
+                    .find(|sample| sample.name == WORKS);
+                sample.map(|sample| sample.value)
+            };
+            while works(metrics.snapshot()).is_none_or(|count| count < 4) {
+                tokio::task::yield_now().await
+            }
+            shutdown.trigger()
+        };
+        tokio::join!(worker, check).0?;
+        let snapshot = metrics.snapshot();
+        anyhow::ensure!(snapshot
+            .gauges
+            .iter()
+            .all(|sample| sample.value == 0 && sample.labels["loop"] == "worker"));
+        anyhow::ensure!(snapshot
+            .histograms
+            .iter()
+            .any(|sample| sample.name == WORK_SECONDS && sample.value.count == 4));
+        Ok(())
+    }
+}
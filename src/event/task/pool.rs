@@ -0,0 +1,278 @@
+use std::{
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use derive_where::derive_where;
+use tokio::{
+    select,
+    sync::{mpsc::unbounded_channel, watch},
+};
+
+use crate::event::{SendEvent as _, Submit, Work};
+
+use super::{
+    bounded::{self, Overflow, Rejected, Stats},
+    deterministic, Shutdown,
+};
+
+// the worker pool on dedicated std threads, as an alternative of `run_worker` that runs works on
+// the runtime of the caller (which is single threaded in our binaries) i.e. shares the core with
+// the protocol
+//
+// every thread owns a clone of the worker state and context. the context is expected to be (or to
+// contain) `Erase` senders into the protocol's event channel, so the results get back to the
+// single threaded protocol state as events
+//
+// on the simulated runtime (see `super::deterministic`), the works run one by one on the caller's
+// runtime instead, so they take no (virtual) time and interleave with the event loops
+// deterministically
+//
+// on shutdown the pool lets every submitted work finish, so their results reach the protocol's
+// event channel before `run` returns. the protocol's loop is expected to be shut down (and drained)
+// only after that, e.g. with a separate `Shutdown` that is triggered once `run` returns. the works
+// submitted even later still run, by the threads that keep serving until all senders are dropped,
+// but their results are probably left behind
+
+// the queue of works is bounded with `bounded_channel`, so the works that the workers fall behind
+// of are shed by the `Overflow` policy, as if the messages that they are for were lost. the works
+// that are shed (and the results that are rejected by a full channel of the protocol) are counted
+// by the channels' `Stats` rather than failing the pool
+
+#[derive_where(Debug, Clone)]
+pub struct Sender<S, C>(bounded::Sender<Queued<S, C>>, Pending);
+
+#[derive_where(Debug)]
+pub struct Receiver<S, C>(bounded::Receiver<Queued<S, C>>, Pending);
+
+// the number of works that are submitted and not finished (or shed) yet
+type Pending = Arc<watch::Sender<usize>>;
+
+// a submitted work is pending until it is dropped, either after running or when it is shed
+struct Queued<S, C>(Work<S, C>, PendingGuard);
+
+struct PendingGuard(Pending);
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|pending| *pending -= 1)
+    }
+}
+
+impl<S, C> std::fmt::Debug for Queued<S, C> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("Queued").finish_non_exhaustive()
+    }
+}
+
+impl<S, C> Queued<S, C> {
+    fn run(self, state: &mut S, context: &mut C) -> anyhow::Result<()> {
+        let Self(work, pending) = self;
+        let result = work(state, context);
+        drop(pending);
+        match result {
+            Err(err) if err.is::<Rejected>() => Ok(()),
+            result => result,
+        }
+    }
+}
+
+pub fn channel<S, C>() -> (Sender<S, C>, Receiver<S, C>) {
+    bounded_channel(usize::MAX, Overflow::Reject)
+}
+
+pub fn bounded_channel<S, C>(
+    capacity: usize,
+    overflow: Overflow,
+) -> (Sender<S, C>, Receiver<S, C>) {
+    let (sender, receiver) = bounded::channel(capacity, overflow);
+    let pending = Arc::new(watch::channel(0).0);
+    (Sender(sender, pending.clone()), Receiver(receiver, pending))
+}
+
+impl<S, C> Sender<S, C> {
+    pub fn stats(&self) -> Arc<Stats> {
+        self.0.stats()
+    }
+}
+
+impl<S, C> Submit<S, C> for Sender<S, C> {
+    fn submit(&mut self, work: Work<S, C>) -> anyhow::Result<()> {
+        self.1.send_modify(|pending| *pending += 1);
+        match self.0.send(Queued(work, PendingGuard(self.1.clone()))) {
+            Err(err) if err.is::<Rejected>() => Ok(()),
+            result => result.map_err(|_| anyhow::format_err!("unexpected worker pool closed")),
+        }
+    }
+}
+
+async fn idle(pending: &Pending) {
+    // the sender is owned by the caller so waiting never fails
+    let _ = pending.subscribe().wait_for(|pending| *pending == 0).await;
+}
+
+// the threads are detached, and they exit when all senders are dropped. this returns as soon as any
+// of the works fails, or all senders are dropped, or on shutdown after all submitted works finish
+pub async fn run<S: Clone + Send + 'static, C: Clone + Send + 'static>(
+    state: S,
+    context: C,
+    Receiver(receiver, pending): Receiver<S, C>,
+    num_thread: usize,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()> {
+    anyhow::ensure!(num_thread > 0);
+    if deterministic() {
+        return run_inline(state, context, receiver, pending, shutdown).await;
+    }
+    let receiver = Arc::new(Mutex::new(receiver));
+    let (result_sender, mut result_receiver) = unbounded_channel();
+    for _ in 0..num_thread {
+        let mut state = state.clone();
+        let mut context = context.clone();
+        let receiver = receiver.clone();
+        let result_sender = result_sender.clone();
+        let shutdown = shutdown.clone();
+        thread::spawn(move || {
+            let mut run_thread = || loop {
+                // the lock is held while waiting, so idle threads queue up on the mutex and take
+                // works in turn
+                let work = receiver.lock().unwrap().blocking_recv();
+                let Some(work) = work else {
+                    if shutdown.is_triggered() {
+                        break Ok(());
+                    }
+                    anyhow::bail!("unexpected worker pool closed")
+                };
+                work.run(&mut state, &mut context)?
+            };
+            let _ = result_sender.send(run_thread());
+        });
+    }
+    drop(result_sender);
+    select! {
+        biased;
+        () = shutdown.triggered() => {}
+        result = result_receiver.recv() => return result.ok_or(anyhow::format_err!("no worker thread to run"))?,
+    }
+    select! {
+        biased;
+        () = idle(&pending) => Ok(()),
+        Some(result) = result_receiver.recv() => result,
+    }
+}
+
+async fn run_inline<S: Send + 'static, C: Send + 'static>(
+    mut state: S,
+    mut context: C,
+    mut receiver: bounded::Receiver<Queued<S, C>>,
+    pending: Pending,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()> {
+    loop {
+        let work = select! {
+            biased;
+            () = shutdown.triggered() => break,
+            work = receiver.recv() => work,
+        };
+        let Some(work) = work else {
+            anyhow::bail!("unexpected worker pool closed")
+        };
+        work.run(&mut state, &mut context)?
+    }
+    // the works run inline, so the queued ones are all that are pending
+    while let Some(work) = receiver.try_recv() {
+        work.run(&mut state, &mut context)?
+    }
+    idle(&pending).await;
+    // keep serving the late submissions in the background, same as the threads above
+    tokio::spawn(async move {
+        while let Some(work) = receiver.recv().await {
+            let _ = work.run(&mut state, &mut context);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::atomic::Ordering::SeqCst, thread::ThreadId, time::Duration};
+
+    use crate::event::SendEvent;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn multiple_threads() -> anyhow::Result<()> {
+        let (mut sender, receiver) = channel::<(), tokio::sync::mpsc::UnboundedSender<ThreadId>>();
+        let (result_sender, mut result_receiver) = unbounded_channel();
+        for _ in 0..8 {
+            sender.submit(Box::new(|(), context| {
+                thread::sleep(Duration::from_millis(50));
+                context.send(thread::current().id())
+            }))?
+        }
+        drop(sender);
+        let shutdown = Shutdown::new();
+        let pool = run((), result_sender, receiver, 4, &shutdown);
+        anyhow::ensure!(pool.await.is_err());
+        // wait for the works that are still running on the other threads
+        let mut ids = Vec::new();
+        while let Some(id) = result_receiver.recv().await {
+            ids.push(id)
+        }
+        anyhow::ensure!(ids.len() == 8);
+        ids.sort_by_key(|id| format!("{id:?}"));
+        ids.dedup();
+        anyhow::ensure!(ids.len() > 1 && !ids.contains(&thread::current().id()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shutdown_with_queued_works() -> anyhow::Result<()> {
+        let (mut sender, receiver) = channel::<(), tokio::sync::mpsc::UnboundedSender<usize>>();
+        let (result_sender, mut result_receiver) = unbounded_channel();
+        for index in 0..8usize {
+            sender.submit(Box::new(move |(), context| {
+                thread::sleep(Duration::from_millis(20));
+                context.send(index)
+            }))?
+        }
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        run((), result_sender, receiver, 2, &shutdown).await?;
+        // every result is delivered by the time the pool returns, so a protocol loop that is shut
+        // down afterward drains all of them
+        let mut indexes = Vec::new();
+        while let Ok(index) = result_receiver.try_recv() {
+            indexes.push(index)
+        }
+        indexes.sort();
+        anyhow::ensure!(indexes == (0..8).collect::<Vec<_>>());
+        // late submissions are still served
+        sender.submit(Box::new(|(), context| context.send(8usize)))?;
+        anyhow::ensure!(result_receiver.recv().await == Some(8));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn shed_oldest_works() -> anyhow::Result<()> {
+        type Context = tokio::sync::mpsc::UnboundedSender<usize>;
+        let (mut sender, receiver) = bounded_channel::<(), Context>(2, Overflow::DropOldest);
+        let (result_sender, mut result_receiver) = unbounded_channel();
+        for index in 0..4usize {
+            sender.submit(Box::new(move |(), context| context.send(index)))?
+        }
+        let shutdown = Shutdown::new();
+        shutdown.trigger();
+        // the shed works are not pending anymore, so the pool returns without them
+        run((), result_sender, receiver, 1, &shutdown).await?;
+        let mut indexes = Vec::new();
+        while let Ok(index) = result_receiver.try_recv() {
+            indexes.push(index)
+        }
+        anyhow::ensure!(indexes == [2, 3]);
+        let stats = sender.stats();
+        anyhow::ensure!(stats.sent.load(SeqCst) == 4 && stats.dropped.load(SeqCst) == 2);
+        Ok(())
+    }
+}
@@ -0,0 +1,119 @@
+use tokio::select;
+
+use super::Receive;
+
+// two input channels of an event loop, with the first one (e.g. the control plane) prioritized over
+// the second one (e.g. the data plane). the pending events of the first one are always received
+// first, except that after `max_burst` events in a row from the first one, the second one gets one
+// event if it has any, so it is never starved
+//
+// more lanes are built by nesting, e.g. `Prioritized<A, Prioritized<B, C>>`
+#[derive(Debug)]
+pub struct Prioritized<H, L> {
+    high: H,
+    low: L,
+    max_burst: usize,
+    burst: usize,
+}
+
+impl<H, L> Prioritized<H, L> {
+    pub fn new(high: H, low: L, max_burst: usize) -> Self {
+        assert!(max_burst > 0);
+        Self {
+            high,
+            low,
+            max_burst,
+            burst: 0,
+        }
+    }
+
+    fn try_recv_low<M>(&mut self) -> Option<M>
+    where
+        L: Receive<M>,
+    {
+        let event = self.low.try_recv()?;
+        self.burst = 0;
+        Some(event)
+    }
+}
+
+impl<H: Receive<M> + Send, L: Receive<M> + Send, M: Send> Receive<M> for Prioritized<H, L> {
+    async fn recv(&mut self) -> Option<M> {
+        if let Some(event) = self.try_recv() {
+            return Some(event);
+        }
+        // both are empty. whichever comes first, which is not a priority inversion
+        select! {
+            biased;
+            Some(event) = self.high.recv() => {
+                self.burst += 1;
+                Some(event)
+            }
+            Some(event) = self.low.recv() => {
+                self.burst = 0;
+                Some(event)
+            }
+            else => None,
+        }
+    }
+
+    fn try_recv(&mut self) -> Option<M> {
+        if self.burst >= self.max_burst {
+            if let Some(event) = self.try_recv_low() {
+                return Some(event);
+            }
+        }
+        if let Some(event) = self.high.try_recv() {
+            self.burst += 1;
+            return Some(event);
+        }
+        self.try_recv_low()
+    }
+
+    fn len(&self) -> usize {
+        self.high.len() + self.low.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::mpsc::unbounded_channel;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn starvation() -> anyhow::Result<()> {
+        let (high_sender, high) = unbounded_channel();
+        let (low_sender, low) = unbounded_channel();
+        for i in 0..5 {
+            high_sender.send(("high", i))?;
+            low_sender.send(("low", i))?
+        }
+        let mut receiver = Prioritized::new(high, low, 2);
+        let mut received = Vec::new();
+        for _ in 0..10 {
+            received.push(receiver.recv().await.unwrap())
+        }
+        anyhow::ensure!(
+            received
+                == [
+                    ("high", 0),
+                    ("high", 1),
+                    ("low", 0),
+                    ("high", 2),
+                    ("high", 3),
+                    ("low", 1),
+                    ("high", 4),
+                    ("low", 2),
+                    ("low", 3),
+                    ("low", 4),
+                ]
+        );
+        drop(high_sender);
+        low_sender.send(("low", 5))?;
+        anyhow::ensure!(receiver.recv().await == Some(("low", 5)));
+        drop(low_sender);
+        anyhow::ensure!(receiver.recv().await.is_none());
+        Ok(())
+    }
+}
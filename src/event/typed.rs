@@ -0,0 +1,293 @@
+use std::{any::Any, marker::PhantomData, time::Duration};
+
+use derive_more::{Deref, DerefMut};
+use derive_where::derive_where;
+
+use super::{
+    ActiveTimer, OnErasedEvent, OnEvent, ScheduleEvent, ScheduleEventFor, SendEvent, SendEventFor,
+};
+
+// the typed alternative of `Erase`/`Untyped`. events are passed as variants of an enum generated by
+// `event_enum!` that lists the events a state handles, instead of boxed closures, so sending does
+// not allocate and the queued events can be `Debug`, hashed, serialized, etc. as long as the enum
+// derives so
+//
+// the protocol code keeps working with `SendEventFor`/`ScheduleEventFor`, whose `send`/`set` are
+// generic over any event type the state handles. the enum cannot be required to cover `M` there,
+// so the conversion is checked at runtime, and sending event without variant fails
+
+pub trait EventEnum: Sized {
+    // takes the event out of `event: &mut Option<M>` if there is a variant of `M`
+    fn take(event: &mut dyn Any) -> Option<Self>;
+}
+
+pub trait Dispatch<S, C> {
+    fn dispatch(self, state: &mut S, context: &mut C) -> anyhow::Result<()>;
+}
+
+fn convert<E: EventEnum, M: 'static>(event: M) -> anyhow::Result<E> {
+    E::take(&mut Some(event)).ok_or(anyhow::format_err!(
+        "no variant of {} in {}",
+        std::any::type_name::<M>(),
+        std::any::type_name::<E>()
+    ))
+}
+
+// the sending (and scheduling) side, wrapping a channel sender (or schedule) of `E`
+#[derive_where(Debug, Clone; T)]
+#[derive(Deref, DerefMut)]
+pub struct Typed<E, T>(
+    #[deref]
+    #[deref_mut]
+    T,
+    PhantomData<E>,
+);
+
+impl<E, T> Typed<E, T> {
+    pub fn new(inner: T) -> Self {
+        Self(inner, Default::default())
+    }
+}
+
+impl<E, T: SendEvent<E>, M: Into<E>> SendEvent<M> for Typed<E, T> {
+    fn send(&mut self, event: M) -> anyhow::Result<()> {
+        self.0.send(event.into())
+    }
+}
+
+impl<E: EventEnum, T: SendEvent<E>, S, C: ?Sized> SendEventFor<S, C> for Typed<E, T> {
+    fn send<M: Send + 'static>(&mut self, event: M) -> anyhow::Result<()>
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        self.0.send(convert(event)?)
+    }
+}
+
+// the periodic events are converted once on setting, and the enum is cloned on every firing
+impl<E: EventEnum + Clone + Send + 'static, T: ScheduleEvent<E>, S, C> ScheduleEventFor<S, C>
+    for Typed<E, T>
+{
+    fn set<M: Clone + Send + 'static>(
+        &mut self,
+        period: Duration,
+        event: M,
+    ) -> anyhow::Result<ActiveTimer>
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        let event = convert::<E, _>(event)?;
+        self.0.set_internal(period, move || event.clone())
+    }
+
+    fn set_once<M: Clone + Send + 'static>(
+        &mut self,
+        delay: Duration,
+        event: M,
+    ) -> anyhow::Result<ActiveTimer>
+    where
+        S: OnErasedEvent<M, C>,
+    {
+        let event = convert::<E, _>(event)?;
+        self.0.set_internal_once(delay, move || event)
+    }
+
+    fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()> {
+        self.0.unset(id)
+    }
+
+    fn is_set(&self, id: &ActiveTimer) -> bool {
+        self.0.is_set(id)
+    }
+}
+
+// the receiving side, the counterpart of `Untyped`
+#[derive_where(Debug, Clone; S)]
+#[derive(Deref, DerefMut)]
+pub struct Dispatched<S, E>(
+    #[deref]
+    #[deref_mut]
+    S,
+    PhantomData<E>,
+);
+
+impl<S, E> Dispatched<S, E> {
+    pub fn new(state: S) -> Self {
+        Self(state, Default::default())
+    }
+}
+
+impl<S, E: Dispatch<S, C>, C> OnEvent<C> for Dispatched<S, E> {
+    type Event = E;
+
+    fn on_event(&mut self, event: Self::Event, context: &mut C) -> anyhow::Result<()> {
+        event.dispatch(&mut self.0, context)
+    }
+}
+
+// generates an event enum with one (tuple) variant per event type, which is `EventEnum`, converts
+// from every event type, and dispatches to any state that handles all of them
+//
+//   event_enum! {
+//       #[derive(Debug)]
+//       pub enum ServerEvent<A> {
+//           Recv(Recv<Request<A>>),
+//           RecvFrom(RecvFrom<A, Request<A>>),
+//       }
+//   }
+//
+// the event types must be distinct, and type parameters must be `'static`
+//
+// the coverage of the variants is not checked at compile time: a state may handle more events
+// than the enum lists, and `Typed` fails at runtime on sending (or setting timer of) the missing
+// ones. the enum needs to be `Clone` to be scheduled as periodic timers
+#[macro_export]
+macro_rules! event_enum {
+    // the type parameters are passed as a single token tree, so they can be repeated per event
+    (@from [$($param:ident),*] $name:ident $variant:ident $event:ty) => {
+        impl<$($param),*> From<$event> for $name<$($param),*> {
+            fn from(event: $event) -> Self {
+                Self::$variant(event)
+            }
+        }
+    };
+    (@from $params:tt $name:ident { $($variant:ident($event:ty)),* }) => {
+        $($crate::event_enum!(@from $params $name $variant $event);)*
+    };
+    (
+        $(#[$meta:meta])*
+        $vis:vis enum $name:ident $(<$($param:ident),* $(,)?>)? {
+            $($variant:ident($event:ty)),* $(,)?
+        }
+    ) => {
+        $(#[$meta])*
+        $vis enum $name $(<$($param),*>)? {
+            $($variant($event)),*
+        }
+
+        $crate::event_enum!(@from [$($($param),*)?] $name { $($variant($event)),* });
+
+        impl $(<$($param: 'static),*>)? $crate::event::typed::EventEnum
+            for $name $(<$($param),*>)?
+        {
+            fn take(event: &mut dyn ::std::any::Any) -> Option<Self> {
+                $(
+                    if let Some(event) = event.downcast_mut::<Option<$event>>() {
+                        return event.take().map(Self::$variant);
+                    }
+                )*
+                None
+            }
+        }
+
+        impl<S, C, $($($param),*)?> $crate::event::typed::Dispatch<S, C>
+            for $name $(<$($param),*>)?
+        where
+            $(S: $crate::event::OnErasedEvent<$event, C>,)*
+        {
+            fn dispatch(self, state: &mut S, context: &mut C) -> ::anyhow::Result<()> {
+                match self {
+                    $(
+                        Self::$variant(event) => {
+                            $crate::event::mark_handling::<$event>();
+                            $crate::event::OnErasedEvent::<$event, C>::on_event(state, event, context)
+                        }
+                    )*
+                }
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::event::{combinators::Transient, task::ScheduleState};
+
+    use super::*;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Add(u32);
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Reset;
+
+    #[derive(Debug, Clone, PartialEq)]
+    struct Unknown;
+
+    event_enum! {
+        #[derive(Debug, Clone, PartialEq)]
+        enum Event {
+            Add(Add),
+            Reset(Reset),
+        }
+    }
+
+    struct Sum(u32);
+
+    trait Context: Sized {
+        type Loopback: SendEventFor<Sum, Self>;
+        fn loopback(&mut self) -> &mut Self::Loopback;
+    }
+
+    impl<C: Context> OnErasedEvent<Add, C> for Sum {
+        fn on_event(&mut self, Add(n): Add, context: &mut C) -> anyhow::Result<()> {
+            self.0 += n;
+            if self.0 >= 10 {
+                context.loopback().send(Reset)?
+            }
+            Ok(())
+        }
+    }
+
+    impl<C: Context> OnErasedEvent<Reset, C> for Sum {
+        fn on_event(&mut self, Reset: Reset, _: &mut C) -> anyhow::Result<()> {
+            self.0 = 0;
+            Ok(())
+        }
+    }
+
+    impl<C> OnErasedEvent<Unknown, C> for Sum {
+        fn on_event(&mut self, Unknown: Unknown, _: &mut C) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    struct TestContext(Typed<Event, Transient<Event>>);
+
+    impl Context for TestContext {
+        type Loopback = Typed<Event, Transient<Event>>;
+        fn loopback(&mut self) -> &mut Self::Loopback {
+            &mut self.0
+        }
+    }
+
+    #[test]
+    fn dispatch() -> anyhow::Result<()> {
+        let mut state = Dispatched::<_, Event>::new(Sum(0));
+        let mut context = TestContext(Typed::new(Transient::new()));
+        for n in [3, 4, 5] {
+            state.on_event(Event::Add(Add(n)), &mut context)?
+        }
+        anyhow::ensure!(state.0 .0 == 12);
+        // the queued events are plain values
+        anyhow::ensure!(context.0[..] == [Event::Reset(Reset)]);
+        let event = context.0.pop().unwrap();
+        state.on_event(event, &mut context)?;
+        anyhow::ensure!(state.0 .0 == 0);
+
+        anyhow::ensure!(SendEventFor::<Sum, TestContext>::send(&mut context.0, Unknown).is_err());
+        let mut schedule = Typed::<Event, _>::new(ScheduleState::<Event>::new());
+        anyhow::ensure!(ScheduleEventFor::<Sum, TestContext>::set(
+            &mut schedule,
+            Duration::from_secs(1),
+            Unknown
+        )
+        .is_err());
+        let timer = ScheduleEventFor::<Sum, TestContext>::set(
+            &mut schedule,
+            Duration::from_secs(1),
+            Reset,
+        )?;
+        ScheduleEventFor::<Sum, TestContext>::unset(&mut schedule, timer)
+    }
+}
@@ -18,6 +18,7 @@ use derive_where::derive_where;
 use rand::{seq::IteratorRandom as _, thread_rng};
 use rustc_hash::FxHasher;
 use scc::HashMap;
+use tracing::{debug, trace};
 
 use crate::event::SendEvent;
 
@@ -172,7 +173,7 @@ where
         },
         search_finished,
     )?;
-    // println!("search internal done");
+    debug!("search internal done");
 
     let Some(result) = result else {
         return Ok(SearchResult::Timeout);
@@ -187,7 +188,7 @@ where
         SearchWorkerResult::GoalFound(state) => SearchResult::GoalFound(state),
         SearchWorkerResult::SpaceExhausted => SearchResult::SpaceExhausted,
     };
-    // println!("search exit");
+    debug!("search exit");
     Ok(result)
 }
 
@@ -290,13 +291,13 @@ where
     std::thread::sleep(Duration::from_millis(20));
     search_finished.2.store(true, SeqCst);
     search_finished.1.notify_all();
-    // println!("search finished");
+    debug!("search finished");
     for worker in worker_tasks {
         worker.join().map_err(error_from_panic)?;
     }
-    // println!("worker joined");
+    debug!("worker joined");
     status_worker.join().map_err(error_from_panic)?;
-    // println!("status worker joined");
+    debug!("status worker joined");
     Ok(result)
 }
 
@@ -372,12 +373,12 @@ fn breath_first_worker<S, I, G, P>(
         search_finished.1.notify_all()
     };
     for local_depth in 0.. {
-        // println!("start depth {local_depth}");
+        trace!("start depth {local_depth}");
         'depth: while let Some(state) = queue.pop() {
             // TODO check initial state
-            // println!("check events");
+            trace!("check events");
             for event in state.events() {
-                // println!("step {event:?}");
+                trace!("step");
                 let mut next_state = S::clone(&state);
                 if let Err(err) = step(&mut next_state, event.clone()) {
                     search_finish(SearchWorkerResult::Error(S::clone(&state), event, err));
@@ -394,11 +395,11 @@ fn breath_first_worker<S, I, G, P>(
                         depth: local_depth + 1,
                     }
                 });
-                // println!("dry state {next_dry_state:?} inserted {inserted}");
+                trace!("dry state inserted {inserted}");
                 if !inserted {
                     continue;
                 }
-                // println!("check invariant");
+                trace!("check invariant");
                 if let Err(err) = (settings.invariant)(&next_state) {
                     search_finish(SearchWorkerResult::InvariantViolation(
                         S::clone(&next_state),
@@ -406,7 +407,7 @@ fn breath_first_worker<S, I, G, P>(
                     ));
                     break 'depth;
                 }
-                // println!("check goal");
+                trace!("check goal");
                 if (settings.goal)(&next_state) {
                     search_finish(SearchWorkerResult::GoalFound(S::clone(&next_state)));
                     break 'depth;
@@ -421,17 +422,17 @@ fn breath_first_worker<S, I, G, P>(
                 break;
             }
         }
-        // println!("end depth {local_depth} pushed {}", pushing_queue.len());
+        trace!("end depth {local_depth} pushed {}", pushing_queue.len());
 
         // even if the above loop breaks, this wait always traps every worker
         // so that if some worker trap here first, then other worker `search_finish()`, the former
         // worker does not stuck here
         let wait_result = depth_barrier.wait();
-        // println!("barrier");
+        trace!("barrier");
         if search_finished.2.load(SeqCst) {
             break;
         }
-        // println!("continue on next depth");
+        trace!("continue on next depth");
 
         if wait_result.is_leader() {
             depth.store(local_depth + 1, SeqCst);
@@ -448,7 +449,7 @@ fn breath_first_worker<S, I, G, P>(
         std::thread::sleep(Duration::from_millis(10));
         (queue, pushing_queue) = (pushing_queue, queue)
     }
-    // println!("worker exit");
+    debug!("worker exit");
 }
 
 fn random_depth_first_worker<S, I, G, P>(
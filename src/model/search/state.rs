@@ -18,6 +18,7 @@ pub struct Schedule<M> {
 struct TimerEnvelop<M> {
     id: TimerId,
     period: Duration,
+    once: bool,
     event: M,
 }
 
@@ -31,34 +32,52 @@ impl<M> Schedule<M> {
 
 impl<M: Into<N>, N> ScheduleEvent<M> for Schedule<N> {
     fn set(&mut self, period: Duration, event: M) -> anyhow::Result<ActiveTimer> {
+        Ok(self.insert(period, false, event.into()))
+    }
+
+    fn set_once(&mut self, delay: Duration, event: M) -> anyhow::Result<ActiveTimer> {
+        Ok(self.insert(delay, true, event.into()))
+    }
+
+    fn unset(&mut self, timer: ActiveTimer) -> anyhow::Result<()> {
+        self.remove(timer.0)?;
+        Ok(())
+    }
+
+    fn is_set(&self, timer: &ActiveTimer) -> bool {
+        self.envelops.iter().any(|envelop| envelop.id == timer.0)
+    }
+}
+
+impl<M> Schedule<M> {
+    fn insert(&mut self, period: Duration, once: bool, event: M) -> ActiveTimer {
         self.count += 1;
         let id = self.count;
         let envelop = TimerEnvelop {
             id,
-            event: event.into(),
+            event,
             period,
+            once,
         };
         self.envelops.push(envelop);
-        Ok(ActiveTimer(id))
-    }
-
-    fn unset(&mut self, ActiveTimer(id): ActiveTimer) -> anyhow::Result<()> {
-        self.remove(id)?;
-        Ok(())
+        // leaks are not checked here. the explored states share guards with the states they are
+        // cloned from, so a leak would hardly be observed anyway
+        let (timer, _) = ActiveTimer::new(id);
+        timer
     }
-}
 
-impl<M> Schedule<M> {
     fn remove(&mut self, id: u32) -> anyhow::Result<TimerEnvelop<M>> {
         let Some(pos) = self.envelops.iter().position(|envelop| envelop.id == id) else {
-            anyhow::bail!("missing timer of {:?}", ActiveTimer(id))
+            anyhow::bail!("missing timer of id {id}")
         };
         Ok(self.envelops.remove(pos))
     }
 
     pub fn tick(&mut self, id: TimerId) -> anyhow::Result<()> {
         let ticked = self.remove(id)?;
-        self.envelops.push(ticked);
+        if !ticked.once {
+            self.envelops.push(ticked)
+        }
         Ok(())
     }
 }
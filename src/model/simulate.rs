@@ -7,7 +7,7 @@ use derive_more::{Display, Error};
 use derive_where::derive_where;
 
 use crate::{
-    event::{ActiveTimer, ScheduleEvent, SendEvent},
+    event::{ActiveTimer, GhostTimer, ScheduleEvent, SendEvent, TimerLiveness},
     net::events::Cast,
 };
 
@@ -29,7 +29,9 @@ pub struct Temporal<M> {
 struct TimerEnvelop<M> {
     event: M,
     period: Duration,
+    once: bool,
     at: Duration,
+    liveness: TimerLiveness,
 }
 
 impl<M> ScheduleEvent<M> for Temporal<M> {
@@ -37,18 +39,18 @@ impl<M> ScheduleEvent<M> for Temporal<M> {
     where
         M: Send + Clone + 'static,
     {
-        self.count += 1;
-        let id = self.count;
-        let at = self.now + period;
-        let envelop = TimerEnvelop { event, period, at };
-        let replaced = self.timers.insert(id, envelop);
-        assert!(replaced.is_none());
-        let inserted = self.timeline.insert((at, id));
-        assert!(inserted);
-        Ok(ActiveTimer(id))
+        Ok(self.insert(period, false, event))
+    }
+
+    fn set_once(&mut self, delay: Duration, event: M) -> anyhow::Result<ActiveTimer>
+    where
+        M: Send + Clone + 'static,
+    {
+        Ok(self.insert(delay, true, event))
     }
 
-    fn unset(&mut self, ActiveTimer(id): ActiveTimer) -> anyhow::Result<()> {
+    fn unset(&mut self, timer: ActiveTimer) -> anyhow::Result<()> {
+        let id = timer.0;
         let Some(envelop) = self.timers.remove(&id) else {
             anyhow::bail!("missing timer envelop")
         };
@@ -56,6 +58,10 @@ impl<M> ScheduleEvent<M> for Temporal<M> {
         assert!(removed);
         Ok(())
     }
+
+    fn is_set(&self, timer: &ActiveTimer) -> bool {
+        self.timers.contains_key(&timer.0)
+    }
 }
 
 impl<M> Temporal<M> {
@@ -63,6 +69,25 @@ impl<M> Temporal<M> {
         Self::default()
     }
 
+    fn insert(&mut self, period: Duration, once: bool, event: M) -> ActiveTimer {
+        self.count += 1;
+        let id = self.count;
+        let at = self.now + period;
+        let (timer, liveness) = ActiveTimer::new(id);
+        let envelop = TimerEnvelop {
+            event,
+            period,
+            once,
+            at,
+            liveness,
+        };
+        let replaced = self.timers.insert(id, envelop);
+        assert!(replaced.is_none());
+        let inserted = self.timeline.insert((at, id));
+        assert!(inserted);
+        timer
+    }
+
     pub fn pop(&mut self) -> anyhow::Result<M>
     where
         M: Clone,
@@ -74,6 +99,15 @@ impl<M> Temporal<M> {
         let Some(envelop) = self.timers.get_mut(&id) else {
             unreachable!()
         };
+        if envelop.liveness.is_leaked() {
+            anyhow::bail!(GhostTimer(id))
+        }
+        if envelop.once {
+            let Some(envelop) = self.timers.remove(&id) else {
+                unreachable!()
+            };
+            return Ok(envelop.event);
+        }
         envelop.at = self.now + envelop.period;
         let event = envelop.event.clone();
         let inserted = self.timeline.insert((envelop.at, id));
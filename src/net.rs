@@ -6,18 +6,30 @@ use serde::{de::DeserializeOwned, Serialize};
 use crate::event::SendEvent;
 
 pub mod combinators;
+pub mod fragment;
+pub mod reliable;
 pub mod task {
+    // the in-memory counterpart of `udp`, which is only picked by the deployments under test
+    #[cfg(feature = "simulate")]
+    pub mod sim;
     pub mod udp;
 }
 
 pub mod events {
+    use serde::{Deserialize, Serialize};
+
     // probably called `Send` in any sane codebase, but that terribly conflicts with
     // std::marker::Send
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct Cast<A, M>(pub A, pub M);
 
-    #[derive(Debug)]
+    #[derive(Debug, Serialize, Deserialize)]
     pub struct Recv<M>(pub M);
+
+    // the source address observed by the transport, as opposed to any address that may be
+    // claimed inside the message
+    #[derive(Debug, Serialize, Deserialize)]
+    pub struct RecvFrom<A, M>(pub A, pub M);
 }
 
 pub trait SendMessage<A, M> {
@@ -38,8 +50,8 @@ pub trait Addr:
 impl Addr for u8 {}
 impl Addr for SocketAddr {}
 
-pub fn send_bytes(
-    mut sender: impl SendEvent<events::Recv<Bytes>>,
-) -> impl FnMut(&[u8]) -> anyhow::Result<()> {
-    move |buf| sender.send(events::Recv(Bytes::copy_from_slice(buf)))
+pub fn send_bytes<A>(
+    mut sender: impl SendEvent<events::RecvFrom<A, Bytes>>,
+) -> impl FnMut(A, Bytes) -> anyhow::Result<()> {
+    move |remote, buf| sender.send(events::RecvFrom(remote, buf))
 }
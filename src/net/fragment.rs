@@ -0,0 +1,295 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    hash::Hash,
+    time::Duration,
+};
+
+use bytes::{BufMut as _, Bytes, BytesMut};
+use derive_more::{Display, Error};
+use tokio::time::Instant;
+use tracing::warn;
+
+use crate::{codec::Malformed, event::SendEvent};
+
+use super::events::Cast;
+
+// the layer between `Encode` and the transport that works around the datagram size limit
+// every outgoing message is prefixed with a header and split into numbered fragments, even if it
+// fits in a single datagram, so both sides of a link must agree on using this layer
+//
+// wire layout of a fragment, integers in little endian
+//   message id u64 | fragment index u16 | fragment count u16 | fragment payload
+// partial messages are keyed by the source address and message id. the message id is a random 32
+// bit prefix followed by a 32 bit counter, so a restarted sender on the same address is unlikely to
+// get its fragments mixed up with the ones from before restarting
+
+const HEADER_LEN: usize = 12;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Config {
+    // payload bytes carried by each datagram, excluding the header
+    pub max_fragment_len: usize,
+    // sending a message longer than this is an error, receiving such a message is dropped
+    pub max_message_len: usize,
+    // partially received messages are dropped after this long since the first fragment arrived
+    pub reassemble_timeout: Duration,
+    // upper bound of total bytes held by partially received messages; oldest partial messages
+    // get dropped to make room for new fragments
+    pub max_reassemble_len: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            // a single UDP datagram can carry at most 65507 bytes
+            max_fragment_len: (60 << 10) - HEADER_LEN,
+            max_message_len: 16 << 20,
+            reassemble_timeout: Duration::from_secs(1),
+            max_reassemble_len: 64 << 20,
+        }
+    }
+}
+
+#[derive(Debug, Display, Error)]
+#[display(fmt = "message of {len} bytes exceeds limit {limit}")]
+pub struct MessageTooLarge {
+    pub len: usize,
+    pub limit: usize,
+}
+
+#[derive(Debug)]
+pub struct Fragment<N> {
+    config: Config,
+    id_prefix: u32,
+    count: u32,
+    inner: N,
+}
+
+impl<N> Fragment<N> {
+    pub fn new(config: Config, inner: N) -> Self {
+        assert!(config.max_fragment_len > 0);
+        Self {
+            config,
+            id_prefix: rand::random(),
+            count: 0,
+            inner,
+        }
+    }
+}
+
+impl<A: Clone, N: SendEvent<Cast<A, Bytes>>> SendEvent<Cast<A, Bytes>> for Fragment<N> {
+    fn send(&mut self, Cast(remote, message): Cast<A, Bytes>) -> anyhow::Result<()> {
+        let limit = self
+            .config
+            .max_message_len
+            .min(self.config.max_fragment_len * u16::MAX as usize);
+        if message.len() > limit {
+            anyhow::bail!(MessageTooLarge {
+                len: message.len(),
+                limit
+            })
+        }
+        self.count = self.count.wrapping_add(1);
+        let id = (self.id_prefix as u64) << 32 | self.count as u64;
+        // an empty message still takes one (empty) fragment
+        let fragment_count = message.len().div_ceil(self.config.max_fragment_len).max(1);
+        for index in 0..fragment_count {
+            let start = index * self.config.max_fragment_len;
+            let end = (start + self.config.max_fragment_len).min(message.len());
+            let mut buf = BytesMut::with_capacity(HEADER_LEN + end - start);
+            buf.put_u64_le(id);
+            buf.put_u16_le(index as _);
+            buf.put_u16_le(fragment_count as _);
+            buf.put_slice(&message[start..end]);
+            self.inner.send(Cast(remote.clone(), buf.freeze()))?
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+struct Partial {
+    fragments: Vec<Option<Bytes>>,
+    num_received: usize,
+    len: usize,
+    start: Instant,
+}
+
+#[derive(Debug)]
+pub struct Reassemble<A> {
+    config: Config,
+    partials: HashMap<(A, u64), Partial>,
+    // in the order of first fragment arrival, which is also the order of `Partial::start`
+    // entries for removed partials are left in place and skipped lazily
+    arrivals: VecDeque<(Instant, (A, u64))>,
+    len: usize,
+}
+
+impl<A: Clone + Eq + Hash> Reassemble<A> {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            partials: Default::default(),
+            arrivals: Default::default(),
+            len: 0,
+        }
+    }
+
+    // returns the reassembled message if `buf` is the last missing fragment of it
+    // the fragments are sliced from the received `buf`s without copying, so a single fragment
+    // message is delivered without copying at all
+    pub fn insert(&mut self, remote: A, buf: Bytes) -> anyhow::Result<Option<Bytes>> {
+        anyhow::ensure!(
+            buf.len() >= HEADER_LEN,
+            Malformed("truncated fragment header".into())
+        );
+        let id = u64::from_le_bytes(buf[..8].try_into().unwrap());
+        let index = u16::from_le_bytes(buf[8..10].try_into().unwrap()) as usize;
+        let fragment_count = u16::from_le_bytes(buf[10..12].try_into().unwrap()) as usize;
+        let payload = buf.slice(HEADER_LEN..);
+        anyhow::ensure!(
+            index < fragment_count,
+            Malformed(format!(
+                "fragment index {index} out of count {fragment_count}"
+            ))
+        );
+        if fragment_count == 1 {
+            return Ok(Some(payload));
+        }
+
+        let now = Instant::now();
+        self.expire(now);
+        if fragment_count * self.config.max_fragment_len.max(payload.len())
+            > self.config.max_message_len
+        {
+            warn!("drop message of {fragment_count} fragments over max message length");
+            return Ok(None);
+        }
+        let key = (remote, id);
+        let partial = match self.partials.get_mut(&key) {
+            Some(partial) => partial,
+            None => {
+                self.arrivals.push_back((now, key.clone()));
+                self.partials.entry(key.clone()).or_insert(Partial {
+                    fragments: vec![None; fragment_count],
+                    num_received: 0,
+                    len: 0,
+                    start: now,
+                })
+            }
+        };
+        if partial.fragments.len() != fragment_count {
+            let partial = self.partials.remove(&key).unwrap();
+            self.len -= partial.len;
+            anyhow::bail!(Malformed(format!(
+                "inconsistent fragment count for message {id:#x}"
+            )))
+        }
+        if partial.fragments[index].is_some() {
+            return Ok(None);
+        }
+        partial.num_received += 1;
+        partial.len += payload.len();
+        self.len += payload.len();
+        partial.fragments[index] = Some(payload);
+        if partial.num_received == fragment_count {
+            let partial = self.partials.remove(&key).unwrap();
+            self.len -= partial.len;
+            let mut message = BytesMut::with_capacity(partial.len);
+            for fragment in partial.fragments {
+                message.put(fragment.unwrap())
+            }
+            return Ok(Some(message.freeze()));
+        }
+        // make room by dropping the oldest partial messages, which may be the current one
+        while self.len > self.config.max_reassemble_len {
+            let Some((_, key)) = self.arrivals.pop_front() else {
+                unreachable!("nonzero buffered length without partial message")
+            };
+            if let Some(partial) = self.partials.remove(&key) {
+                self.len -= partial.len
+            }
+        }
+        Ok(None)
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((start, key)) = self.arrivals.front() {
+            match self.partials.get(key) {
+                Some(partial) if partial.start == *start => {
+                    if now - *start < self.config.reassemble_timeout {
+                        break;
+                    }
+                    self.len -= partial.len;
+                    self.partials.remove(key);
+                }
+                _ => {}
+            }
+            self.arrivals.pop_front();
+        }
+    }
+}
+
+pub fn reassemble<A: Clone + Eq + Hash>(
+    config: Config,
+    mut on_buf: impl FnMut(A, Bytes) -> anyhow::Result<()>,
+) -> impl FnMut(A, Bytes) -> anyhow::Result<()> {
+    let mut reassemble = Reassemble::new(config);
+    move |remote: A, buf| {
+        if let Some(message) = reassemble.insert(remote.clone(), buf)? {
+            on_buf(remote, message)?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{seq::SliceRandom as _, thread_rng};
+
+    use crate::event::combinators::Transient;
+
+    use super::*;
+
+    #[test]
+    fn fragment_reassemble() -> anyhow::Result<()> {
+        let config = Config {
+            max_fragment_len: 100,
+            max_reassemble_len: 1000,
+            ..Default::default()
+        };
+        let mut fragments = Transient::new();
+        let mut fragment = Fragment::new(config.clone(), &mut fragments);
+        let message = (0..1000).map(|i| i as u8).collect::<Bytes>();
+        fragment.send(Cast((), message.clone()))?;
+        let error = fragment
+            .send(Cast((), vec![0; 100 << 20].into()))
+            .unwrap_err();
+        anyhow::ensure!(error.is::<MessageTooLarge>());
+
+        let mut fragments = fragments
+            .drain(..)
+            .map(|Cast((), buf)| buf)
+            .collect::<Vec<_>>();
+        anyhow::ensure!(fragments.len() == 10);
+        fragments.shuffle(&mut thread_rng());
+        let mut reassemble = Reassemble::new(config.clone());
+        let mut reassembled = None;
+        for (i, buf) in fragments.iter().enumerate() {
+            let result = reassemble.insert((), buf.clone())?;
+            anyhow::ensure!(result.is_some() == (i == 9));
+            reassembled = reassembled.or(result)
+        }
+        anyhow::ensure!(reassembled == Some(message));
+
+        // partial message that has been expired should never complete
+        let mut reassemble = Reassemble::new(Config {
+            reassemble_timeout: Duration::ZERO,
+            ..config
+        });
+        for buf in &fragments {
+            anyhow::ensure!(reassemble.insert((), buf.clone())?.is_none())
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::event::{ActiveTimer, OnErasedEvent, ScheduleEvent, SendEvent};
+
+use super::{
+    events::{Cast, RecvFrom},
+    Addr,
+};
+
+// optional reliable ordered delivery on top of best-effort `Cast`/`Recv`
+// this layer is a state machine on its own that runs in a dedicated event loop, as a protocol does.
+// the layer's `Erase` sender implements `SendEvent<Cast<A, M>>`, so it can be used as a net of
+// protocols. messages sent through the layer are delivered exactly once in the order of sending
+// (per peer), as long as both sides keep running
+//
+// `BestEffort` wraps message with the same wire format but bypasses sequencing, so reliable and
+// best-effort channels can share a transport and protocols may pick channel per message type
+
+// the sequence numbers are 64 bits so that they never wrap (in centuries at the highest possible
+// message rate), which the comparisons below rely on
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub enum Message<M> {
+    Data(u64, M),
+    // cumulative acknowledgement: all messages with sequence number less than this are received
+    Ack(u64),
+    BestEffort(M),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Config {
+    pub retransmit_interval: Duration,
+    // the retransmission interval doubles every time no acknowledgement comes in between, up to
+    pub max_retransmit_interval: Duration,
+    // messages arrived too early i.e. with sequence number this far ahead of the next expected one
+    // are dropped, bounding the memory held for out of order messages
+    pub max_window: u32,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            retransmit_interval: Duration::from_millis(100),
+            max_retransmit_interval: Duration::from_secs(5),
+            max_window: 1024,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct State<A, M> {
+    config: Config,
+    peers: BTreeMap<A, Peer<M>>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Peer<M> {
+    next_seq: u64,
+    unacked: BTreeMap<u64, M>,
+    // the timer, its period and its generation, which is the number of timers that have been set
+    // for the peer
+    retransmit: Option<(ActiveTimer, Duration, u64)>,
+    num_retransmit_timer: u64,
+
+    next_deliver: u64,
+    out_of_order: BTreeMap<u64, M>,
+}
+
+impl<M> Default for Peer<M> {
+    fn default() -> Self {
+        Self {
+            next_seq: 0,
+            unacked: Default::default(),
+            retransmit: None,
+            num_retransmit_timer: 0,
+            next_deliver: 0,
+            out_of_order: Default::default(),
+        }
+    }
+}
+
+impl<M> Peer<M> {
+    fn set_retransmit<A: Addr>(
+        &mut self,
+        remote: A,
+        period: Duration,
+        schedule: &mut impl ScheduleEvent<events::Retransmit<A>>,
+    ) -> anyhow::Result<()> {
+        self.num_retransmit_timer += 1;
+        let generation = self.num_retransmit_timer;
+        let timer = schedule.set(period, events::Retransmit(remote, generation))?;
+        self.retransmit = Some((timer, period, generation));
+        Ok(())
+    }
+}
+
+impl<A, M> State<A, M> {
+    pub fn new(config: Config) -> Self {
+        Self {
+            config,
+            peers: Default::default(),
+        }
+    }
+}
+
+pub mod events {
+    // the generation tells the events of the current timer from the stale ones, which are queued
+    // before the timer is unset
+    #[derive(Debug, Clone)]
+    pub struct Retransmit<A>(pub A, pub u64);
+}
+
+pub trait Context<A, M> {
+    type Net: SendEvent<Cast<A, Message<M>>>;
+    type Upcall: SendEvent<RecvFrom<A, M>>;
+    type Schedule: ScheduleEvent<events::Retransmit<A>>;
+    fn net(&mut self) -> &mut Self::Net;
+    fn upcall(&mut self) -> &mut Self::Upcall;
+    fn schedule(&mut self) -> &mut Self::Schedule;
+}
+
+impl<A: Addr, M: Clone, C: Context<A, M>> OnErasedEvent<Cast<A, M>, C> for State<A, M> {
+    fn on_event(
+        &mut self,
+        Cast(remote, message): Cast<A, M>,
+        context: &mut C,
+    ) -> anyhow::Result<()> {
+        let peer = self.peers.entry(remote.clone()).or_default();
+        let seq = peer.next_seq;
+        peer.next_seq += 1;
+        peer.unacked.insert(seq, message.clone());
+        if peer.retransmit.is_none() {
+            peer.set_retransmit(
+                remote.clone(),
+                self.config.retransmit_interval,
+                context.schedule(),
+            )?
+        }
+        context
+            .net()
+            .send(Cast(remote, Message::Data(seq, message)))
+    }
+}
+
+impl<A: Addr, M, C: Context<A, M>> OnErasedEvent<RecvFrom<A, Message<M>>, C> for State<A, M> {
+    fn on_event(
+        &mut self,
+        RecvFrom(remote, message): RecvFrom<A, Message<M>>,
+        context: &mut C,
+    ) -> anyhow::Result<()> {
+        match message {
+            Message::BestEffort(message) => context.upcall().send(RecvFrom(remote, message)),
+            Message::Data(seq, message) => {
+                let peer = self.peers.entry(remote.clone()).or_default();
+                if seq >= peer.next_deliver
+                    && seq - peer.next_deliver < self.config.max_window.into()
+                    && seq != peer.next_deliver
+                {
+                    peer.out_of_order.insert(seq, message);
+                    return Ok(());
+                }
+                if seq == peer.next_deliver {
+                    peer.next_deliver += 1;
+                    context.upcall().send(RecvFrom(remote.clone(), message))?;
+                    while let Some(message) = peer.out_of_order.remove(&peer.next_deliver) {
+                        peer.next_deliver += 1;
+                        context.upcall().send(RecvFrom(remote.clone(), message))?
+                    }
+                }
+                // acknowledge duplicated messages as well, in case the previous acknowledgement is
+                // lost
+                let ack = Message::Ack(peer.next_deliver);
+                context.net().send(Cast(remote, ack))
+            }
+            Message::Ack(next_deliver) => {
+                let Some(peer) = self.peers.get_mut(&remote) else {
+                    return Ok(());
+                };
+                let num_unacked = peer.unacked.len();
+                peer.unacked = peer.unacked.split_off(&next_deliver);
+                if peer.unacked.len() == num_unacked {
+                    return Ok(());
+                }
+                // progress is made, restart retransmission from the initial interval
+                if let Some((timer, ..)) = peer.retransmit.take() {
+                    context.schedule().unset(timer)?
+                }
+                if !peer.unacked.is_empty() {
+                    peer.set_retransmit(
+                        remote,
+                        self.config.retransmit_interval,
+                        context.schedule(),
+                    )?
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<A: Addr, M: Clone, C: Context<A, M>> OnErasedEvent<events::Retransmit<A>, C> for State<A, M> {
+    fn on_event(
+        &mut self,
+        events::Retransmit(remote, generation): events::Retransmit<A>,
+        context: &mut C,
+    ) -> anyhow::Result<()> {
+        // the stale event of the timer that is unset after it fired, e.g. on the acknowledgement
+        // that is received in between, even if another timer has been set since then
+        let Some(peer) = self.peers.get_mut(&remote) else {
+            return Ok(());
+        };
+        let (timer, period) = match peer.retransmit.take() {
+            Some((timer, period, current)) if current == generation => (timer, period),
+            retransmit => {
+                peer.retransmit = retransmit;
+                return Ok(());
+            }
+        };
+        for (seq, message) in &peer.unacked {
+            context
+                .net()
+                .send(Cast(remote.clone(), Message::Data(*seq, message.clone())))?
+        }
+        context.schedule().unset(timer)?;
+        let period = (period * 2).min(self.config.max_retransmit_interval);
+        peer.set_retransmit(remote, period, context.schedule())
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BestEffort<N>(pub N);
+
+impl<A, M, N: SendEvent<Cast<A, Message<M>>>> SendEvent<Cast<A, M>> for BestEffort<N> {
+    fn send(&mut self, Cast(remote, message): Cast<A, M>) -> anyhow::Result<()> {
+        self.0.send(Cast(remote, Message::BestEffort(message)))
+    }
+}
+
+// the layer's own envelope, so a datagram of the upper layer's protocol that is sent without this
+// layer (or a stray one) is rejected instead of being taken as sequenced data
+pub mod codec {
+    use std::{fmt, marker::PhantomData};
+
+    use bytes::Bytes;
+    use serde::de::{DeserializeSeed, Deserializer, EnumAccess, VariantAccess as _, Visitor};
+
+    use crate::codec::{
+        envelope::{self, Protocol},
+        seed::{self, Slice},
+        Encode, Limits, Payload,
+    };
+
+    use super::*;
+
+    pub const PROTOCOL: Protocol = Protocol::new(0x0001, 1);
+
+    pub fn encode<N>(net: N) -> Encode<Message<Bytes>, N> {
+        Encode::new(|message| envelope::encode(&PROTOCOL, message), net)
+    }
+
+    // the upper layer's messages are sliced from the received buffer rather than copied
+    pub fn decode<'a, A>(
+        limits: Limits,
+        mut sender: impl SendEvent<RecvFrom<A, Message<Bytes>>> + 'a,
+    ) -> impl FnMut(A, Bytes) -> anyhow::Result<()> + 'a {
+        move |remote, buf| {
+            let message = match envelope::decode_seed(&PROTOCOL, &limits, &buf, MessageSeed(&buf))?
+            {
+                Message::Data(seq, Payload(message)) => Message::Data(seq, message),
+                Message::Ack(seq) => Message::Ack(seq),
+                Message::BestEffort(Payload(message)) => Message::BestEffort(message),
+            };
+            sender.send(RecvFrom(remote, message))
+        }
+    }
+
+    // the seed of `Message<Payload>` with the payload sliced from `buf`, in the form of bincode
+    #[derive(Clone, Copy)]
+    struct MessageSeed<'a>(&'a Bytes);
+
+    #[derive(Deserialize)]
+    #[serde(variant_identifier)]
+    enum MessageVariant {
+        Data,
+        Ack,
+        BestEffort,
+    }
+
+    impl<'de> DeserializeSeed<'de> for MessageSeed<'_> {
+        type Value = Message<Payload>;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_enum("Message", &["Data", "Ack", "BestEffort"], self)
+        }
+    }
+
+    impl<'de> Visitor<'de> for MessageSeed<'_> {
+        type Value = Message<Payload>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "enum Message")
+        }
+
+        fn visit_enum<E: EnumAccess<'de>>(self, data: E) -> Result<Self::Value, E::Error> {
+            let (variant, access) = data.variant()?;
+            let message = match variant {
+                MessageVariant::Data => {
+                    let (seq, message) =
+                        access.tuple_variant(2, seed::Pair(PhantomData, Slice(self.0)))?;
+                    Message::Data(seq, message)
+                }
+                MessageVariant::Ack => Message::Ack(access.newtype_variant()?),
+                MessageVariant::BestEffort => {
+                    Message::BestEffort(access.newtype_variant_seed(Slice(self.0))?)
+                }
+            };
+            Ok(message)
+        }
+    }
+
+    // the upcall that passes delivered messages into the decoder of the upper layer
+    pub struct OnBuf<F>(pub F);
+
+    impl<A, F: FnMut(A, Bytes) -> anyhow::Result<()>> SendEvent<RecvFrom<A, Bytes>> for OnBuf<F> {
+        fn send(&mut self, RecvFrom(remote, buf): RecvFrom<A, Bytes>) -> anyhow::Result<()> {
+            (self.0)(remote, buf)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+
+    use crate::{
+        codec::{envelope, Limits, Rejected},
+        event::combinators::Transient,
+        model::search::state::{Schedule, TimerId},
+    };
+
+    use super::*;
+
+    type M = &'static str;
+
+    struct TestContext {
+        net: Transient<Cast<u8, Message<M>>>,
+        upcall: Transient<RecvFrom<u8, M>>,
+        schedule: Schedule<events::Retransmit<u8>>,
+    }
+
+    impl Context<u8, M> for TestContext {
+        type Net = Transient<Cast<u8, Message<M>>>;
+        type Upcall = Transient<RecvFrom<u8, M>>;
+        type Schedule = Schedule<events::Retransmit<u8>>;
+        fn net(&mut self) -> &mut Self::Net {
+            &mut self.net
+        }
+        fn upcall(&mut self) -> &mut Self::Upcall {
+            &mut self.upcall
+        }
+        fn schedule(&mut self) -> &mut Self::Schedule {
+            &mut self.schedule
+        }
+    }
+
+    impl TestContext {
+        fn new() -> Self {
+            Self {
+                net: Transient::new(),
+                upcall: Transient::new(),
+                schedule: Schedule::new(),
+            }
+        }
+
+        fn timer(&self) -> Option<(TimerId, events::Retransmit<u8>)> {
+            self.schedule.events().next()
+        }
+    }
+
+    #[test]
+    fn lossy_delivery() -> anyhow::Result<()> {
+        let mut sender = State::new(Config::default());
+        let mut sender_context = TestContext::new();
+        let mut receiver = State::new(Config::default());
+        let mut receiver_context = TestContext::new();
+
+        for message in ["foo", "bar", "baz"] {
+            sender.on_event(Cast(1, message), &mut sender_context)?
+        }
+        // the first message is lost, the third one is duplicated
+        let mut sent = sender_context.net.drain(..).collect::<Vec<_>>();
+        sent.remove(0);
+        sent.push(Cast(1, Message::Data(2, "baz")));
+        for Cast(_, message) in sent {
+            receiver.on_event(RecvFrom(0, message), &mut receiver_context)?
+        }
+        anyhow::ensure!(receiver_context.upcall.is_empty());
+
+        let Some((id, retransmit)) = sender_context.timer() else {
+            anyhow::bail!("missing retransmission timer")
+        };
+        sender_context.schedule.tick(id)?;
+        sender.on_event(retransmit, &mut sender_context)?;
+        let Some((_, stale)) = sender_context.timer() else {
+            anyhow::bail!("missing retransmission timer")
+        };
+        for Cast(_, message) in sender_context.net.drain(..).collect::<Vec<_>>() {
+            receiver.on_event(RecvFrom(0, message), &mut receiver_context)?
+        }
+        let delivered = receiver_context
+            .upcall
+            .drain(..)
+            .map(|RecvFrom(_, message)| message)
+            .collect::<Vec<_>>();
+        anyhow::ensure!(delivered == ["foo", "bar", "baz"]);
+
+        for Cast(_, message) in receiver_context.net.drain(..).collect::<Vec<_>>() {
+            sender.on_event(RecvFrom(1, message), &mut sender_context)?
+        }
+        anyhow::ensure!(sender_context.timer().is_none());
+        // the retransmission that fires along with the acknowledgement
+        sender.on_event(stale.clone(), &mut sender_context)?;
+        anyhow::ensure!(sender_context.net.is_empty());
+
+        // and the one that is delivered after a newer timer is set
+        sender.on_event(Cast(1, "qux"), &mut sender_context)?;
+        sender_context.net.clear();
+        let Some((id, retransmit)) = sender_context.timer() else {
+            anyhow::bail!("missing retransmission timer")
+        };
+        sender.on_event(stale, &mut sender_context)?;
+        anyhow::ensure!(sender_context.net.is_empty());
+        anyhow::ensure!(sender_context
+            .timer()
+            .is_some_and(|(current, _)| current == id));
+        sender.on_event(retransmit, &mut sender_context)?;
+        anyhow::ensure!(matches!(
+            &sender_context.net[..],
+            [Cast(1, Message::Data(3, "qux"))]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn codec() -> anyhow::Result<()> {
+        let mut net = Transient::<Cast<u8, Bytes>>::new();
+        let mut encode = codec::encode(&mut net);
+        encode.send(Cast(1, Message::Data(0, Bytes::from_static(b"foo"))))?;
+        encode.send(Cast(1, Message::Ack(1)))?;
+        encode.send(Cast(1, Message::BestEffort(Bytes::from_static(b"bar"))))?;
+        let mut received = Transient::<RecvFrom<u8, Message<Bytes>>>::new();
+        let bufs = net.drain(..).map(|Cast(_, buf)| buf).collect::<Vec<_>>();
+        {
+            let mut decode = codec::decode(Limits::default(), &mut received);
+            for buf in &bufs {
+                decode(0, buf.clone())?
+            }
+        }
+        anyhow::ensure!(matches!(
+            &received[..],
+            [
+                RecvFrom(0, Message::Data(0, foo)),
+                RecvFrom(0, Message::Ack(1)),
+                RecvFrom(0, Message::BestEffort(bar)),
+            ] if foo[..] == *b"foo" && bar[..] == *b"bar"
+        ));
+        // sliced from the received buffers rather than copied
+        anyhow::ensure!(matches!(
+            &received[..],
+            [RecvFrom(_, Message::Data(_, foo)), _, RecvFrom(_, Message::BestEffort(bar))]
+                if bufs[0].as_ptr_range().contains(&foo.as_ptr())
+                    && bufs[2].as_ptr_range().contains(&bar.as_ptr())
+        ));
+        // the upper layer's message that bypasses this layer
+        let upper = envelope::encode(&envelope::Protocol::new(0x0101, 1), &"foo")?;
+        let mut decode = codec::decode(Limits::default(), &mut received);
+        anyhow::ensure!(decode(0, upper).is_err_and(|err| err.is::<Rejected>()));
+        Ok(())
+    }
+}
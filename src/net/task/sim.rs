@@ -0,0 +1,283 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+use tokio::{
+    runtime, spawn,
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+        Mutex as AsyncMutex,
+    },
+    time::{sleep, Instant},
+};
+
+pub use super::udp::{SendReceiver, Sender};
+
+use crate::event::task::{set_deterministic, Shutdown};
+
+// the in-memory counterpart of `udp`, with the same interface. a deployment that is wired against a
+// `transport` module alias, which refers to `udp` in the binary and to this module under test, runs
+// with the exact same wiring in a test as
+//
+//   Network::new(seed, Config::default()).block_on(async { ... })
+//
+// which runs on a single threaded runtime with paused clock i.e. the time only advances (and
+// instantly) when every task is idle. the delay and the loss of every datagram are drawn from the
+// seeded generator of the network, and only the tasks on the runtime run deterministically i.e. the
+// worker pool runs inline and the event loops poll their inputs in fixed order, so a run is
+// reproduced by its seed. enabling the feature changes nothing outside of the runtime
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub min_delay: Duration,
+    pub max_delay: Duration,
+    pub drop_rate: f64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            min_delay: Duration::from_micros(100),
+            max_delay: Duration::from_millis(2),
+            drop_rate: 0.,
+        }
+    }
+}
+
+// a delivered datagram, for telling whether two runs are the same
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Delivery {
+    // since the network starts running
+    pub at: Duration,
+    pub from: SocketAddr,
+    pub to: SocketAddr,
+    pub len: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Network(Arc<Mutex<NetworkState>>);
+
+#[derive(Debug)]
+struct NetworkState {
+    config: Config,
+    rng: StdRng,
+    sockets: HashMap<SocketAddr, UnboundedSender<(SocketAddr, Bytes)>>,
+    next_port: u16,
+    start: Option<Instant>,
+    trace: Vec<Delivery>,
+}
+
+thread_local! {
+    static CURRENT: RefCell<Option<Network>> = const { RefCell::new(None) };
+}
+
+impl Network {
+    pub fn new(seed: u64, config: Config) -> Self {
+        assert!(config.min_delay <= config.max_delay);
+        Self(Arc::new(Mutex::new(NetworkState {
+            config,
+            rng: StdRng::seed_from_u64(seed),
+            sockets: Default::default(),
+            next_port: 49152,
+            start: None,
+            trace: Default::default(),
+        })))
+    }
+
+    pub fn block_on<F: Future>(&self, future: F) -> anyhow::Result<F::Output> {
+        let runtime = runtime::Builder::new_current_thread()
+            .enable_all()
+            .start_paused(true)
+            .build()?;
+        CURRENT.with(|current| current.replace(Some(self.clone())));
+        set_deterministic(true);
+        let output = runtime.block_on(async {
+            self.0.lock().unwrap().start = Some(Instant::now());
+            future.await
+        });
+        CURRENT.with(|current| current.take());
+        set_deterministic(false);
+        Ok(output)
+    }
+
+    pub fn trace(&self) -> Vec<Delivery> {
+        self.0.lock().unwrap().trace.clone()
+    }
+
+    fn current() -> anyhow::Result<Self> {
+        CURRENT
+            .with(|current| current.borrow().clone())
+            .ok_or(anyhow::format_err!("not running in a simulated network"))
+    }
+
+    fn deliver(&self, local: SocketAddr, remote: SocketAddr, buf: Bytes) {
+        let mut state = self.0.lock().unwrap();
+        let drop_rate = state.config.drop_rate;
+        if state.rng.gen_bool(drop_rate) {
+            return;
+        }
+        let delay = state.config.min_delay..=state.config.max_delay;
+        let delay = state.rng.gen_range(delay);
+        let network = self.clone();
+        spawn(async move {
+            sleep(delay).await;
+            let mut state = network.0.lock().unwrap();
+            // datagram to nowhere is silently lost, as it is on the wire
+            let Some(sender) = state.sockets.get(&remote) else {
+                return;
+            };
+            if sender.send((local, buf.clone())).is_ok() {
+                let at = state.start.map(|start| start.elapsed()).unwrap_or_default();
+                state.trace.push(Delivery {
+                    at,
+                    from: local,
+                    to: remote,
+                    len: buf.len(),
+                })
+            }
+        });
+    }
+}
+
+#[derive(Debug)]
+pub struct UdpSocket {
+    addr: SocketAddr,
+    receiver: AsyncMutex<UnboundedReceiver<(SocketAddr, Bytes)>>,
+    network: Network,
+}
+
+impl UdpSocket {
+    // port 0 is assigned an unused port, as the kernel does
+    pub async fn bind(mut addr: SocketAddr) -> anyhow::Result<Self> {
+        let network = Network::current()?;
+        let mut state = network.0.lock().unwrap();
+        if addr.port() == 0 {
+            while state
+                .sockets
+                .contains_key(&SocketAddr::new(addr.ip(), state.next_port))
+            {
+                state.next_port += 1
+            }
+            addr.set_port(state.next_port)
+        }
+        anyhow::ensure!(
+            !state.sockets.contains_key(&addr),
+            "address {addr} already in use"
+        );
+        let (sender, receiver) = unbounded_channel();
+        state.sockets.insert(addr, sender);
+        drop(state);
+        Ok(Self {
+            addr,
+            receiver: AsyncMutex::new(receiver),
+            network,
+        })
+    }
+
+    pub fn from_std(_: std::net::UdpSocket) -> anyhow::Result<Self> {
+        anyhow::bail!("unimplemented for simulation")
+    }
+
+    pub fn local_addr(&self) -> anyhow::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        self.network.0.lock().unwrap().sockets.remove(&self.addr);
+    }
+}
+
+pub async fn run_sender(socket: &UdpSocket, receiver: &mut SendReceiver) -> anyhow::Result<()> {
+    while let Some((remote, buf)) = receiver.recv().await {
+        socket.network.deliver(socket.addr, remote, buf)
+    }
+    anyhow::bail!("unexpected send channel closed")
+}
+
+pub async fn run(
+    socket: &UdpSocket,
+    mut on_buf: impl FnMut(SocketAddr, Bytes) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let mut receiver = socket
+        .receiver
+        .try_lock()
+        .map_err(|_| anyhow::format_err!("socket is being received by another task"))?;
+    while let Some((remote, buf)) = receiver.recv().await {
+        on_buf(remote, buf)?
+    }
+    anyhow::bail!("unexpected socket closed")
+}
+
+// a single receiving socket (and thread) per address on the simulated network, so the deployments
+// under test are configured with one receiver
+pub fn bind_reuse_port(_: SocketAddr, _: usize) -> anyhow::Result<Vec<std::net::UdpSocket>> {
+    anyhow::bail!("unimplemented for simulation")
+}
+
+pub async fn run_sharded<F>(
+    _: Vec<std::net::UdpSocket>,
+    _: impl FnMut() -> F,
+    _: &Shutdown,
+) -> anyhow::Result<()>
+where
+    F: FnMut(SocketAddr, Bytes) -> anyhow::Result<()> + Send + 'static,
+{
+    anyhow::bail!("unimplemented for simulation")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{event::SendEvent, net::events::Cast};
+
+    use super::*;
+
+    fn ping_pong(seed: u64) -> anyhow::Result<Vec<Delivery>> {
+        let network = Network::new(seed, Config::default());
+        network.block_on(async {
+            let ping = UdpSocket::bind(([127, 0, 0, 1], 0).into()).await?;
+            let pong = UdpSocket::bind(([127, 0, 0, 2], 0).into()).await?;
+            let pong_addr = pong.local_addr()?;
+            let (mut ping_sender, mut ping_receiver) = Sender::new();
+            let (mut pong_sender, mut pong_receiver) = Sender::new();
+            // the first message is sent before the receiving starts, and is received nevertheless
+            ping_sender.send(Cast(pong_addr, Bytes::from_static(b"ping")))?;
+            let mut count = 0;
+            let ping_task = run(&ping, |remote, buf| {
+                count += 1;
+                if count == 10 {
+                    anyhow::bail!("done")
+                }
+                ping_sender.send(Cast(remote, buf))
+            });
+            let pong_task = run(&pong, |remote, buf| pong_sender.send(Cast(remote, buf)));
+            let result = tokio::select! {
+                biased;
+                result = ping_task => result,
+                result = pong_task => result,
+                result = run_sender(&ping, &mut ping_receiver) => result,
+                result = run_sender(&pong, &mut pong_receiver) => result,
+            };
+            anyhow::ensure!(result.is_err_and(|err| err.to_string() == "done"));
+            anyhow::Ok(())
+        })??;
+        Ok(network.trace())
+    }
+
+    #[test]
+    fn reproducible() -> anyhow::Result<()> {
+        let trace = ping_pong(42)?;
+        anyhow::ensure!(trace.len() == 20);
+        anyhow::ensure!(ping_pong(42)? == trace);
+        anyhow::ensure!(ping_pong(43)? != trace);
+        Ok(())
+    }
+}
@@ -1,29 +1,471 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, sync::Arc, thread};
 
-use bytes::Bytes;
-use tokio::{net::UdpSocket, spawn};
+use bytes::{Bytes, BytesMut};
+use socket2::{Domain, Protocol, Socket, Type};
+pub use tokio::net::UdpSocket;
+use tokio::{
+    io::Interest,
+    runtime, select, spawn,
+    sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
+};
 
-use crate::{event::SendEvent, net::events::Cast};
+use tracing::warn;
 
+use crate::{
+    event::{task::Shutdown, SendEvent},
+    net::events::Cast,
+};
+
+// the number of datagrams handled by a single syscall at most, for both sending and receiving
+const MAX_BATCH: usize = 32;
+const MAX_DATAGRAM_LEN: usize = 64 << 10;
+// the received datagrams at least this long are split from their receive buffers and delivered
+// without copying. the shorter ones are copied, so that they do not pin a mostly unused buffer of
+// `MAX_DATAGRAM_LEN` (as long as they are alive), and the buffer is reused for the next datagram
+//
+// the cost of the splitting is that a split datagram keeps its whole buffer allocated until the
+// datagram and every `Payload` sliced from it (with `codec::seed::Slice`) are dropped, so the
+// retained memory is up to 4x of the datagram length. the bound holds per datagram rather than per
+// payload: a short `Payload` that outlives the rest of a long datagram e.g. a request of a batch
+// that stays in a replica's log keeps the whole `MAX_DATAGRAM_LEN` alive, and the retained memory
+// of such payloads is bounded by the number of split datagrams that they come from
+const MIN_SPLIT_LEN: usize = MAX_DATAGRAM_LEN / 4;
+
+// the unbatched sending path, one task and one syscall per message
+// prefer `Sender` when the message rate is high
 impl SendEvent<Cast<SocketAddr, Bytes>> for Arc<UdpSocket> {
     fn send(&mut self, Cast(remote, message): Cast<SocketAddr, Bytes>) -> anyhow::Result<()> {
         let socket = self.clone();
         spawn(async move {
-            if socket.send_to(&message, remote).await.is_err() {
-                // TODO log
+            if let Err(err) = socket.send_to(&message, remote).await {
+                warn!(%remote, "send failed: {err}")
             }
         });
         Ok(())
     }
 }
 
+// queue outgoing messages for the flushing task `run_sender`, which sends them in batches
+#[derive(Debug, Clone)]
+pub struct Sender(UnboundedSender<(SocketAddr, Bytes)>);
+
+pub type SendReceiver = UnboundedReceiver<(SocketAddr, Bytes)>;
+
+impl Sender {
+    pub fn new() -> (Self, SendReceiver) {
+        let (sender, receiver) = unbounded_channel();
+        (Self(sender), receiver)
+    }
+}
+
+impl SendEvent<Cast<SocketAddr, Bytes>> for Sender {
+    fn send(&mut self, Cast(remote, message): Cast<SocketAddr, Bytes>) -> anyhow::Result<()> {
+        self.0
+            .send((remote, message))
+            .map_err(|_| anyhow::format_err!("unexpected send channel closed"))
+    }
+}
+
+pub async fn run_sender(socket: &UdpSocket, receiver: &mut SendReceiver) -> anyhow::Result<()> {
+    let mut messages = Vec::with_capacity(MAX_BATCH);
+    loop {
+        if receiver.recv_many(&mut messages, MAX_BATCH).await == 0 {
+            anyhow::bail!("unexpected send channel closed")
+        }
+        while !messages.is_empty() {
+            socket.writable().await?;
+            match socket.try_io(Interest::WRITABLE, || mmsg::send(socket, &messages)) {
+                Ok(num_sent) => {
+                    messages.drain(..num_sent);
+                }
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => {}
+                // the failure is about the first message in the batch, skip it and keep going
+                Err(err) => {
+                    let (remote, _) = messages.remove(0);
+                    warn!(%remote, "send failed: {err}")
+                }
+            }
+        }
+    }
+}
+
+// deliver all datagrams that are available on every wakeup, up to `MAX_BATCH` per syscall
+// an error of `on_buf` ends the receiving, wrap it with `codec::on_malformed` to survive garbage
+// input from the network
 pub async fn run(
     socket: &UdpSocket,
-    mut on_buf: impl FnMut(&[u8]) -> anyhow::Result<()>,
+    mut on_buf: impl FnMut(SocketAddr, Bytes) -> anyhow::Result<()>,
 ) -> anyhow::Result<()> {
-    let mut buf = vec![0; 64 << 10];
+    let mut bufs = (0..MAX_BATCH)
+        .map(|_| BytesMut::with_capacity(MAX_DATAGRAM_LEN))
+        .collect::<Vec<_>>();
+    let mut received = Vec::with_capacity(MAX_BATCH);
     loop {
-        let (len, _) = socket.recv_from(&mut buf).await?;
-        on_buf(&buf[..len])?
+        socket.readable().await?;
+        loop {
+            match socket.try_io(Interest::READABLE, || {
+                mmsg::recv(socket, &mut bufs, &mut received)
+            }) {
+                Ok(()) => {}
+                Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => break,
+                Err(err) => Err(err)?,
+            }
+            for (buf, remote) in bufs.iter_mut().zip(received.drain(..)) {
+                let datagram = if buf.len() < MIN_SPLIT_LEN {
+                    let datagram = Bytes::copy_from_slice(buf);
+                    buf.clear();
+                    datagram
+                } else {
+                    let datagram = buf.split().freeze();
+                    // the rest of the buffer is shared with the datagram, so this allocates anew
+                    buf.reserve(MAX_DATAGRAM_LEN);
+                    datagram
+                };
+                on_buf(remote, datagram)?
+            }
+        }
+    }
+}
+
+// bind `num_socket` sockets to the same address with SO_REUSEPORT. the kernel shards incoming
+// datagrams among the sockets by hashing the source address, so the messages from the same remote
+// stay in order
+pub fn bind_reuse_port(
+    addr: SocketAddr,
+    num_socket: usize,
+) -> anyhow::Result<Vec<std::net::UdpSocket>> {
+    anyhow::ensure!(num_socket > 0);
+    (0..num_socket)
+        .map(|_| {
+            let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+            socket.set_reuse_port(true)?;
+            socket.set_nonblocking(true)?;
+            socket.bind(&addr.into())?;
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+// run the receive loop of each socket on a dedicated thread, with its own single threaded runtime
+// every loop gets its own `on_buf` e.g. decoder that sends into the (shared) event channel of the
+// protocol state, so decoding scales with the number of sockets while the protocol state machine
+// stays on a single thread
+//
+// all loops stop on `shutdown`, or as soon as any of them fails, and the threads are joined before
+// returning
+pub async fn run_sharded<F>(
+    sockets: Vec<std::net::UdpSocket>,
+    mut new_on_buf: impl FnMut() -> F,
+    shutdown: &Shutdown,
+) -> anyhow::Result<()>
+where
+    F: FnMut(SocketAddr, Bytes) -> anyhow::Result<()> + Send + 'static,
+{
+    anyhow::ensure!(!sockets.is_empty(), "no socket to run");
+    let stop = Shutdown::new();
+    let (result_sender, mut result_receiver) = unbounded_channel();
+    let threads = sockets
+        .into_iter()
+        .map(|socket| {
+            let on_buf = new_on_buf();
+            let stop = stop.clone();
+            let result_sender = result_sender.clone();
+            thread::spawn(move || {
+                let run_thread = || {
+                    let runtime = runtime::Builder::new_current_thread()
+                        .enable_all()
+                        .build()?;
+                    runtime.block_on(async move {
+                        let socket = UdpSocket::from_std(socket)?;
+                        select! {
+                            () = stop.triggered() => Ok(()),
+                            result = run(&socket, on_buf) => result,
+                        }
+                    })
+                };
+                let _ = result_sender.send(run_thread());
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(result_sender);
+    let result = select! {
+        () = shutdown.triggered() => Ok(()),
+        // the receive loops never end by themselves
+        Some(result) = result_receiver.recv() => {
+            result.and(Err(anyhow::format_err!("unexpected termination of infinite task")))
+        }
+    };
+    stop.trigger();
+    // the channel is closed as the threads exit, so the joining below does not block for long
+    while result_receiver.recv().await.is_some() {}
+    for thread in threads {
+        thread
+            .join()
+            .map_err(|_| anyhow::format_err!("receiving thread panicked"))?
+    }
+    result
+}
+
+#[cfg(target_os = "linux")]
+mod mmsg {
+    use std::{
+        io,
+        mem::{size_of_val, zeroed},
+        net::SocketAddr,
+        os::fd::AsRawFd,
+        ptr::null_mut,
+    };
+
+    use bytes::{BufMut as _, Bytes, BytesMut};
+    use socket2::SockAddr;
+
+    pub fn send(socket: &impl AsRawFd, messages: &[(SocketAddr, Bytes)]) -> io::Result<usize> {
+        let addrs = messages
+            .iter()
+            .map(|(remote, _)| SockAddr::from(*remote))
+            .collect::<Vec<_>>();
+        let mut iovecs = messages
+            .iter()
+            .map(|(_, message)| libc::iovec {
+                iov_base: message.as_ptr() as _,
+                iov_len: message.len(),
+            })
+            .collect::<Vec<_>>();
+        let mut headers = addrs
+            .iter()
+            .zip(&mut iovecs)
+            .map(|(addr, iovec)| {
+                // zeroed instead of struct literal because of private padding fields on some
+                // targets
+                let mut header = unsafe { zeroed::<libc::mmsghdr>() };
+                header.msg_hdr.msg_name = addr.as_ptr() as _;
+                header.msg_hdr.msg_namelen = addr.len();
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect::<Vec<_>>();
+        let num_sent = unsafe {
+            libc::sendmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                0,
+            )
+        };
+        if num_sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(num_sent as _)
+    }
+
+    // receive into the spare capacity of the empty `bufs`, and extend each of them to cover the
+    // received datagram
+    pub fn recv(
+        socket: &impl AsRawFd,
+        bufs: &mut [BytesMut],
+        received: &mut Vec<SocketAddr>,
+    ) -> io::Result<()> {
+        let mut addrs = vec![unsafe { zeroed::<libc::sockaddr_storage>() }; bufs.len()];
+        let mut iovecs = bufs
+            .iter_mut()
+            .map(|buf| {
+                let spare = buf.spare_capacity_mut();
+                libc::iovec {
+                    iov_base: spare.as_mut_ptr() as _,
+                    iov_len: spare.len(),
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut headers = addrs
+            .iter_mut()
+            .zip(&mut iovecs)
+            .map(|(addr, iovec)| {
+                let mut header = unsafe { zeroed::<libc::mmsghdr>() };
+                header.msg_hdr.msg_name = addr as *mut _ as _;
+                header.msg_hdr.msg_namelen = size_of_val(addr) as _;
+                header.msg_hdr.msg_iov = iovec;
+                header.msg_hdr.msg_iovlen = 1;
+                header
+            })
+            .collect::<Vec<_>>();
+        let num_received = unsafe {
+            libc::recvmmsg(
+                socket.as_raw_fd(),
+                headers.as_mut_ptr(),
+                headers.len() as _,
+                libc::MSG_DONTWAIT as _,
+                null_mut(),
+            )
+        };
+        if num_received < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        for ((header, addr), buf) in headers.iter().zip(&addrs).zip(bufs).take(num_received as _) {
+            let addr = unsafe { SockAddr::new(*addr, header.msg_hdr.msg_namelen) };
+            let remote = addr
+                .as_socket()
+                .expect("UDP socket always receives from IP address");
+            // the kernel has initialized the first `msg_len` bytes of the spare capacity
+            unsafe { buf.advance_mut(header.msg_len as _) }
+            received.push(remote)
+        }
+        Ok(())
+    }
+}
+
+// fallback to one syscall per datagram
+#[cfg(not(target_os = "linux"))]
+mod mmsg {
+    use std::{io, net::SocketAddr};
+
+    use bytes::{Bytes, BytesMut};
+    use tokio::net::UdpSocket;
+
+    pub fn send(socket: &UdpSocket, messages: &[(SocketAddr, Bytes)]) -> io::Result<usize> {
+        let (remote, message) = &messages[0];
+        socket.try_send_to(message, *remote)?;
+        Ok(1)
+    }
+
+    pub fn recv(
+        socket: &UdpSocket,
+        bufs: &mut [BytesMut],
+        received: &mut Vec<SocketAddr>,
+    ) -> io::Result<()> {
+        let (_, remote) = socket.try_recv_buf_from(&mut bufs[0])?;
+        received.push(remote);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::{HashMap, HashSet},
+        marker::PhantomData,
+    };
+
+    use crate::codec::{envelope, seed, Payload};
+
+    use super::*;
+
+    const PROTOCOL: envelope::Protocol = envelope::Protocol::new(0x0001, 1);
+
+    #[tokio::test]
+    async fn zero_copy() -> anyhow::Result<()> {
+        let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = socket.local_addr()?;
+        let (sender, mut receiver) = unbounded_channel();
+        let receive_task = run(&socket, |_, buf| {
+            let seed = seed::Pair(PhantomData::<u32>, seed::Slice(&buf));
+            let (_, payload) = envelope::decode_seed(&PROTOCOL, &Default::default(), &buf, seed)?;
+            let _ = sender.send((buf, payload));
+            Ok(())
+        });
+        let send_task = async {
+            let remote = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+            for len in [MIN_SPLIT_LEN, 1, MAX_DATAGRAM_LEN / 2] {
+                let payload = Payload(vec![len as u8; len].into());
+                remote
+                    .send_to(&envelope::encode(&PROTOCOL, &(0u32, payload))?, addr)
+                    .await?;
+                let (buf, payload) = receiver.recv().await.unwrap();
+                anyhow::ensure!(payload.len() == len && payload.iter().all(|&b| b == len as u8));
+                // the payloads of the split datagrams and of the copied short ones alike are sliced
+                // from the delivered datagram
+                anyhow::ensure!(buf.as_ptr_range().contains(&payload.as_ptr()));
+            }
+            anyhow::Ok(())
+        };
+        select! {
+            result = receive_task => result?,
+            result = send_task => return result,
+        }
+        anyhow::bail!("unexpected termination of infinite task")
+    }
+
+    #[tokio::test]
+    async fn batches() -> anyhow::Result<()> {
+        const NUM_MESSAGE: usize = MAX_BATCH * 3 + 1;
+        let socket = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let addr = socket.local_addr()?;
+        let (received_sender, mut received_receiver) = unbounded_channel();
+        let receive_task = run(&socket, |_, buf| {
+            let _ = received_sender.send(buf);
+            Ok(())
+        });
+        let remote = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+        let (mut sender, mut send_receiver) = Sender::new();
+        // queued all at once, so `run_sender` sends them in batches of `MAX_BATCH`
+        for i in 0..NUM_MESSAGE {
+            sender.send(Cast(addr, Bytes::from((i as u32).to_le_bytes().to_vec())))?
+        }
+        let send_task = run_sender(&remote, &mut send_receiver);
+        let check_task = async {
+            let mut received = Vec::new();
+            while received.len() < NUM_MESSAGE {
+                let buf = received_receiver.recv().await.unwrap();
+                received.push(u32::from_le_bytes(buf[..].try_into()?) as usize)
+            }
+            received.sort();
+            anyhow::ensure!(received.into_iter().eq(0..NUM_MESSAGE));
+            anyhow::Ok(())
+        };
+        select! {
+            result = receive_task => result?,
+            result = send_task => result?,
+            result = check_task => return result,
+        }
+        anyhow::bail!("unexpected termination of infinite task")
+    }
+
+    #[tokio::test]
+    async fn sharded() -> anyhow::Result<()> {
+        const NUM_REMOTE: usize = 16;
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        // bound to a port first, so that all sockets share it
+        let sockets = bind_reuse_port(addr, 1)?;
+        let addr = sockets[0].local_addr()?;
+        drop(sockets);
+        let sockets = bind_reuse_port(addr, 4)?;
+        let (received_sender, mut received_receiver) = unbounded_channel();
+        let mut shard = 0;
+        // moved in, so the channel is closed once all threads exit
+        let new_on_buf = move || {
+            let received_sender = received_sender.clone();
+            shard += 1;
+            let shard = shard;
+            move |remote, _| {
+                let _ = received_sender.send((shard, remote));
+                Ok(())
+            }
+        };
+        let shutdown = Shutdown::new();
+        let receive_task = run_sharded(sockets, new_on_buf, &shutdown);
+        let send_task = async {
+            // the datagrams are sharded by source address
+            let mut remotes = Vec::new();
+            for _ in 0..NUM_REMOTE {
+                let remote = UdpSocket::bind(SocketAddr::from(([127, 0, 0, 1], 0))).await?;
+                remote.send_to(b"hello", addr).await?;
+                remotes.push(remote.local_addr()?)
+            }
+            let mut received = HashMap::new();
+            while received.len() < NUM_REMOTE {
+                let (shard, remote) = received_receiver.recv().await.unwrap();
+                received.insert(remote, shard);
+            }
+            anyhow::ensure!(remotes.iter().all(|remote| received.contains_key(remote)));
+            anyhow::ensure!(received.values().collect::<HashSet<_>>().len() > 1);
+            shutdown.trigger();
+            anyhow::Ok(())
+        };
+        let (result, send_result) = tokio::join!(receive_task, send_task);
+        send_result?;
+        // returns on shutdown, after all threads are joined
+        result?;
+        anyhow::ensure!(received_receiver.recv().await.is_none());
+        Ok(())
     }
 }
@@ -1,11 +1,13 @@
 use std::collections::BTreeMap;
 
 use bytes::Bytes;
+use tracing::{trace, warn};
 
 use crate::{
     codec::Payload,
-    event::{ActiveTimer, OnErasedEvent, ScheduleEvent, SendEvent},
+    event::{OnErasedEvent, ScheduleEvent, SendEvent},
     net::{combinators::All, events::Recv, Addr, SendMessage},
+    timer::Timer,
     workload::events::{Invoke, InvokeOk},
 };
 
@@ -23,13 +25,13 @@ pub struct State<A> {
     seq: u32,
     outstanding: Option<Outstanding>,
     view_num: u32,
+    resend_timer: Timer<events::Resend>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash)]
 struct Outstanding {
     op: Payload,
     replies: BTreeMap<u8, Reply>,
-    timer: ActiveTimer,
 }
 
 impl<A> State<A> {
@@ -37,6 +39,11 @@ impl<A> State<A> {
         Self {
             id,
             addr,
+            resend_timer: Timer::with_backoff(
+                config.client_resend_interval,
+                config.client_resend_backoff.clone(),
+                id as _,
+            ),
             config,
 
             seq: 0,
@@ -65,12 +72,10 @@ impl<A: Addr, C: Context<A>> OnErasedEvent<Invoke<Bytes>, C> for State<A> {
         self.seq += 1;
         let replaced = self.outstanding.replace(Outstanding {
             op: Payload(op),
-            timer: context
-                .schedule()
-                .set(self.config.client_resend_interval, events::Resend)?,
             replies: Default::default(),
         });
         anyhow::ensure!(replaced.is_none());
+        self.resend_timer.set(events::Resend, context.schedule())?;
         self.send_request(
             (self.view_num as usize % self.config.num_replica) as u8,
             context,
@@ -80,7 +85,14 @@ impl<A: Addr, C: Context<A>> OnErasedEvent<Invoke<Bytes>, C> for State<A> {
 
 impl<A: Addr, C: Context<A>> OnErasedEvent<events::Resend, C> for State<A> {
     fn on_event(&mut self, events::Resend: events::Resend, context: &mut C) -> anyhow::Result<()> {
-        // warn!("Resend timeout on seq {}", self.seq);
+        warn!(
+            id = self.id,
+            view = self.view_num,
+            seq = self.seq,
+            "resend timeout"
+        );
+        self.resend_timer
+            .retry(events::Resend, context.schedule())?;
         self.send_request(All, context)
     }
 }
@@ -94,7 +106,12 @@ impl<A, C: Context<A>> OnErasedEvent<Recv<Reply>, C> for State<A> {
             return Ok(());
         };
         invoke.replies.insert(reply.replica_id, reply.clone());
-        // println!("{:?}", invoke.replies);
+        trace!(
+            id = self.id,
+            seq = self.seq,
+            num_reply = invoke.replies.len(),
+            "reply"
+        );
         if invoke
             .replies
             .values()
@@ -107,9 +124,8 @@ impl<A, C: Context<A>> OnErasedEvent<Recv<Reply>, C> for State<A> {
         // paper is not saying what does it mean by "what it believes is the current primary"
         // either taking min or max of the view numbers seems wrong, so i choose to design nothing
         self.view_num = reply.view_num;
-        context
-            .schedule()
-            .unset(self.outstanding.take().unwrap().timer)?;
+        self.outstanding = None;
+        self.resend_timer.unset(context.schedule())?;
         let Payload(result) = reply.result;
         context.upcall().send(InvokeOk(result))
     }
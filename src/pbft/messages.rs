@@ -76,27 +76,313 @@ pub struct QueryNewView {
 pub type Quorum<M> = BTreeMap<u8, Verifiable<M>>;
 
 pub mod codec {
+    use std::{fmt, marker::PhantomData};
+
+    use bytes::Bytes;
     use derive_more::From;
-    use serde::{Deserialize, Serialize};
+    use derive_where::derive_where;
+    use serde::{
+        de::{DeserializeSeed, Deserializer, EnumAccess, SeqAccess, VariantAccess as _, Visitor},
+        Deserialize, Serialize,
+    };
 
     use crate::{
-        codec::{bincode, Encode},
+        codec::{
+            envelope::{self, Protocol},
+            seed::{self, Map, Pair, Seq, Slice},
+            Encode,
+        },
+        crypto::VerifiableSeed,
         event::SendEvent,
         net::{events::Recv, Addr},
+        pbft::PublicParameters,
     };
 
     use super::*;
 
+    pub const TO_REPLICA: Protocol = Protocol::new(0x0201, 1);
+    pub const TO_CLIENT: Protocol = Protocol::new(0x0202, 1);
+
+    // the decoding bounds beyond the generic ones. the request batches and the quorums are bounded
+    // by the protocol, while the logs carried by view changes are only bounded by
+    // `max_collection_len` (until checkpoint is implemented to truncate them). all of them are
+    // applied while decoding, so an over-long collection is rejected before the rest of it is
+    // decoded
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+    pub struct Limits {
+        pub base: crate::codec::Limits,
+        pub max_batch_size: usize,
+        pub num_replica: usize,
+    }
+
+    impl Limits {
+        pub fn new(config: &PublicParameters) -> Self {
+            Self {
+                base: Default::default(),
+                max_batch_size: config.max_batch_size,
+                num_replica: config.num_replica,
+            }
+        }
+
+        fn decode<A: Addr>(&self, buf: &Bytes) -> anyhow::Result<ToReplica<A>> {
+            envelope::decode_seed(
+                &TO_REPLICA,
+                &self.base,
+                buf,
+                ToReplicaSeed(self, buf, PhantomData),
+            )
+        }
+
+        fn prepares(&self) -> Map<u8, PhantomData<Verifiable<Prepare>>> {
+            Map::new("prepares", self.num_replica, PhantomData)
+        }
+    }
+
+    // the seeds that apply the limits while decoding, in the sequence form of bincode, and slice
+    // the `Payload`s from the received buffer. they must be kept in sync with the derived
+    // `Deserialize` of the messages, which is exercised by the fuzz tests that decode every kind of
+    // valid message
+    #[derive_where(Clone, Copy)]
+    struct ToReplicaSeed<'a, A>(&'a Limits, &'a Bytes, PhantomData<A>);
+
+    #[derive_where(Clone, Copy)]
+    struct RequestSeed<'a, A>(&'a Bytes, PhantomData<A>);
+
+    #[derive(Clone, Copy)]
+    struct ReplySeed<'a>(&'a Bytes);
+
+    #[derive(Clone, Copy)]
+    struct ViewChangeSeed<'a>(&'a Limits);
+
+    #[derive(Clone, Copy)]
+    struct NewViewSeed<'a>(&'a Limits);
+
+    #[derive(Deserialize)]
+    #[serde(variant_identifier)]
+    enum ToReplicaVariant {
+        Request,
+        PrePrepare,
+        Prepare,
+        Commit,
+        ViewChange,
+        NewView,
+        QueryNewView,
+    }
+
+    const TO_REPLICA_VARIANTS: &[&str] = &[
+        "Request",
+        "PrePrepare",
+        "Prepare",
+        "Commit",
+        "ViewChange",
+        "NewView",
+        "QueryNewView",
+    ];
+
+    impl<'de, A: Addr> DeserializeSeed<'de> for ToReplicaSeed<'_, A> {
+        type Value = ToReplica<A>;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_enum("ToReplica", TO_REPLICA_VARIANTS, self)
+        }
+    }
+
+    impl<'de, A: Addr> Visitor<'de> for ToReplicaSeed<'_, A> {
+        type Value = ToReplica<A>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "enum ToReplica")
+        }
+
+        fn visit_enum<E: EnumAccess<'de>>(self, data: E) -> Result<Self::Value, E::Error> {
+            let Self(limits, buf, _) = self;
+            let (variant, access) = data.variant()?;
+            let message = match variant {
+                ToReplicaVariant::Request => {
+                    ToReplica::Request(access.newtype_variant_seed(RequestSeed(buf, PhantomData))?)
+                }
+                ToReplicaVariant::PrePrepare => {
+                    let batch = Seq {
+                        what: "batch",
+                        max: limits.max_batch_size,
+                        element: RequestSeed(buf, PhantomData),
+                    };
+                    let (pre_prepare, requests) =
+                        access.tuple_variant(2, Pair(PhantomData, batch))?;
+                    ToReplica::PrePrepare(pre_prepare, requests)
+                }
+                ToReplicaVariant::Prepare => ToReplica::Prepare(access.newtype_variant()?),
+                ToReplicaVariant::Commit => ToReplica::Commit(access.newtype_variant()?),
+                ToReplicaVariant::ViewChange => ToReplica::ViewChange(
+                    access.newtype_variant_seed(VerifiableSeed(ViewChangeSeed(limits)))?,
+                ),
+                ToReplicaVariant::NewView => ToReplica::NewView(
+                    access.newtype_variant_seed(VerifiableSeed(NewViewSeed(limits)))?,
+                ),
+                ToReplicaVariant::QueryNewView => {
+                    ToReplica::QueryNewView(access.newtype_variant()?)
+                }
+            };
+            Ok(message)
+        }
+    }
+
+    impl<'de, A: Addr> DeserializeSeed<'de> for RequestSeed<'_, A> {
+        type Value = Request<A>;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_struct(
+                "Request",
+                &["seq", "op", "client_id", "client_addr"],
+                self,
+            )
+        }
+    }
+
+    impl<'de, A: Addr> Visitor<'de> for RequestSeed<'_, A> {
+        type Value = Request<A>;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "struct Request")
+        }
+
+        fn visit_seq<S: SeqAccess<'de>>(self, mut seq: S) -> Result<Self::Value, S::Error> {
+            Ok(Request {
+                seq: seed::next(&mut seq, 0, PhantomData, &self)?,
+                op: seed::next(&mut seq, 1, Slice(self.0), &self)?,
+                client_id: seed::next(&mut seq, 2, PhantomData, &self)?,
+                client_addr: seed::next(&mut seq, 3, PhantomData, &self)?,
+            })
+        }
+    }
+
+    impl<'de> DeserializeSeed<'de> for ReplySeed<'_> {
+        type Value = Reply;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_struct(
+                "Reply",
+                &["seq", "result", "view_num", "replica_id"],
+                self,
+            )
+        }
+    }
+
+    impl<'de> Visitor<'de> for ReplySeed<'_> {
+        type Value = Reply;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "struct Reply")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            Ok(Reply {
+                seq: seed::next(&mut seq, 0, PhantomData, &self)?,
+                result: seed::next(&mut seq, 1, Slice(self.0), &self)?,
+                view_num: seed::next(&mut seq, 2, PhantomData, &self)?,
+                replica_id: seed::next(&mut seq, 3, PhantomData, &self)?,
+            })
+        }
+    }
+
+    impl<'de> DeserializeSeed<'de> for ViewChangeSeed<'_> {
+        type Value = ViewChange;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_struct("ViewChange", &["view_num", "log", "replica_id"], self)
+        }
+    }
+
+    impl<'de> Visitor<'de> for ViewChangeSeed<'_> {
+        type Value = ViewChange;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "struct ViewChange")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let Self(limits) = self;
+            let log = Seq {
+                what: "view change log",
+                max: limits.base.max_collection_len,
+                element: Pair(PhantomData, limits.prepares()),
+            };
+            Ok(ViewChange {
+                view_num: seed::next(&mut seq, 0, PhantomData, &self)?,
+                log: seed::next(&mut seq, 1, log, &self)?,
+                replica_id: seed::next(&mut seq, 2, PhantomData, &self)?,
+            })
+        }
+    }
+
+    impl<'de> DeserializeSeed<'de> for NewViewSeed<'_> {
+        type Value = NewView;
+
+        fn deserialize<D: Deserializer<'de>>(
+            self,
+            deserializer: D,
+        ) -> Result<Self::Value, D::Error> {
+            deserializer.deserialize_struct(
+                "NewView",
+                &["view_num", "view_changes", "pre_prepares"],
+                self,
+            )
+        }
+    }
+
+    impl<'de> Visitor<'de> for NewViewSeed<'_> {
+        type Value = NewView;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            write!(formatter, "struct NewView")
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+            let Self(limits) = self;
+            let view_changes = Map::new(
+                "view changes",
+                limits.num_replica,
+                VerifiableSeed(ViewChangeSeed(limits)),
+            );
+            let pre_prepares = Seq {
+                what: "pre-prepares",
+                max: limits.base.max_collection_len,
+                element: PhantomData,
+            };
+            Ok(NewView {
+                view_num: seed::next(&mut seq, 0, PhantomData, &self)?,
+                view_changes: seed::next(&mut seq, 1, view_changes, &self)?,
+                pre_prepares: seed::next(&mut seq, 2, pre_prepares, &self)?,
+            })
+        }
+    }
+
     pub type ToClient = Reply;
 
     pub fn to_client_encode<N>(net: N) -> Encode<ToClient, N> {
-        Encode::bincode(net)
+        Encode::new(|message| envelope::encode(&TO_CLIENT, message), net)
     }
 
-    pub fn to_client_decode<'a>(
+    pub fn to_client_decode<'a, A>(
+        limits: Limits,
         mut sender: impl SendEvent<Recv<Reply>> + 'a,
-    ) -> impl FnMut(&[u8]) -> anyhow::Result<()> + 'a {
-        move |buf| sender.send(Recv(bincode::decode(buf)?))
+    ) -> impl FnMut(A, Bytes) -> anyhow::Result<()> + 'a {
+        move |_, buf| {
+            let reply = envelope::decode_seed(&TO_CLIENT, &limits.base, &buf, ReplySeed(&buf))?;
+            sender.send(Recv(reply))
+        }
     }
 
     #[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize, From)]
@@ -111,28 +397,76 @@ pub mod codec {
     }
 
     pub fn to_replica_encode<A: Addr, N>(net: N) -> Encode<ToReplica<A>, N> {
-        Encode::bincode(net)
+        Encode::new(|message| envelope::encode(&TO_REPLICA, message), net)
     }
 
     pub fn to_replica_decode<'a, A: Addr>(
-        mut sender: impl SendEvent<Recv<Request<A>>>
-            + SendEvent<Recv<(Verifiable<PrePrepare>, Vec<Request<A>>)>>
+        limits: Limits,
+        mut sender: impl SendEvent<Recv<Request<A>>> + ConsensusSender<A> + 'a,
+    ) -> impl FnMut(A, Bytes) -> anyhow::Result<()> + 'a {
+        // the source address is not checked against the claimed `client_addr` and `replica_id`
+        // `Request`s may be relayed by backup replicas, and the other messages are signed
+        move |_, buf| {
+            if let Some(request) = send_consensus(limits.decode(&buf)?, &mut sender)? {
+                sender.send(Recv(request))?
+            }
+            Ok(())
+        }
+    }
+
+    // the decoding for prioritized event loop, that routes the consensus messages into a separate
+    // (high priority) lane from client requests, so that a flood of requests does not delay
+    // consensus progress
+    pub fn to_replica_decode_lanes<'a, A: Addr>(
+        limits: Limits,
+        mut consensus_sender: impl ConsensusSender<A> + 'a,
+        mut request_sender: impl SendEvent<Recv<Request<A>>> + 'a,
+    ) -> impl FnMut(A, Bytes) -> anyhow::Result<()> + 'a {
+        move |_, buf| {
+            if let Some(request) = send_consensus(limits.decode(&buf)?, &mut consensus_sender)? {
+                request_sender.send(Recv(request))?
+            }
+            Ok(())
+        }
+    }
+
+    // the receiver of every message other than client requests
+    pub trait ConsensusSender<A>:
+        SendEvent<Recv<(Verifiable<PrePrepare>, Vec<Request<A>>)>>
+        + SendEvent<Recv<Verifiable<Prepare>>>
+        + SendEvent<Recv<Verifiable<Commit>>>
+        + SendEvent<Recv<Verifiable<ViewChange>>>
+        + SendEvent<Recv<Verifiable<NewView>>>
+        + SendEvent<Recv<QueryNewView>>
+    {
+    }
+
+    impl<T, A> ConsensusSender<A> for T where
+        T: SendEvent<Recv<(Verifiable<PrePrepare>, Vec<Request<A>>)>>
             + SendEvent<Recv<Verifiable<Prepare>>>
             + SendEvent<Recv<Verifiable<Commit>>>
             + SendEvent<Recv<Verifiable<ViewChange>>>
             + SendEvent<Recv<Verifiable<NewView>>>
             + SendEvent<Recv<QueryNewView>>
-            + 'a,
-    ) -> impl FnMut(&[u8]) -> anyhow::Result<()> + 'a {
+    {
+    }
+
+    // send the message to `sender` unless it is a client request, which is returned for the caller
+    // to route
+    fn send_consensus<A>(
+        message: ToReplica<A>,
+        sender: &mut impl ConsensusSender<A>,
+    ) -> anyhow::Result<Option<Request<A>>> {
         use ToReplica::*;
-        move |buf| match bincode::decode(buf)? {
-            Request(message) => sender.send(Recv(message)),
-            PrePrepare(message, requests) => sender.send(Recv((message, requests))),
-            Prepare(message) => sender.send(Recv(message)),
-            Commit(message) => sender.send(Recv(message)),
-            ViewChange(message) => sender.send(Recv(message)),
-            NewView(message) => sender.send(Recv(message)),
-            QueryNewView(message) => sender.send(Recv(message)),
+        match message {
+            Request(message) => return Ok(Some(message)),
+            PrePrepare(message, requests) => sender.send(Recv((message, requests)))?,
+            Prepare(message) => sender.send(Recv(message))?,
+            Commit(message) => sender.send(Recv(message))?,
+            ViewChange(message) => sender.send(Recv(message))?,
+            NewView(message) => sender.send(Recv(message))?,
+            QueryNewView(message) => sender.send(Recv(message))?,
         }
+        Ok(None)
     }
 }
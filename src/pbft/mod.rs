@@ -1,5 +1,7 @@
 use std::time::Duration;
 
+use crate::timer::Backoff;
+
 pub mod client;
 pub mod messages;
 pub mod replica;
@@ -15,9 +17,11 @@ pub struct PublicParameters {
     pub max_batch_size: usize,
 
     pub client_resend_interval: Duration,
+    pub client_resend_backoff: Backoff,
     pub progress_prepare_interval: Duration,
     pub view_change_delay: Duration,
     pub progress_view_change_interval: Duration,
+    pub progress_view_change_backoff: Backoff,
     pub state_transfer_delay: Duration,
 }
 
@@ -25,6 +29,11 @@ impl PublicParameters {
     pub fn durations(client_resend_interval: Duration) -> Self {
         Self {
             client_resend_interval,
+            // jitter so clients that time out together (e.g. on overloaded replicas) do not keep
+            // resending in lockstep
+            client_resend_backoff: Backoff::DecorrelatedJitter {
+                max: client_resend_interval * 10,
+            },
             progress_prepare_interval: client_resend_interval / 5,
             // keep track of the timing of start sending ViewChange for a view, do not repeat; alarm
             // (at most) once for each view
@@ -36,6 +45,11 @@ impl PublicParameters {
             // it is ok this is shorter than `ProgressPrepare`, as this is not enabled before
             // `DoViewChange` timeout, which is longer than `ProgressPrepare`
             progress_view_change_interval: client_resend_interval / 10,
+            // a view change that keeps not being completed is probably blocked by something that
+            // takes time to recover e.g. network partition
+            progress_view_change_backoff: Backoff::Exponential {
+                max: client_resend_interval,
+            },
             state_transfer_delay: client_resend_interval * 10, // TODO
 
             num_replica: Default::default(),
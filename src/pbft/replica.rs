@@ -1,4 +1,9 @@
-use std::collections::BTreeMap;
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering::Relaxed},
+};
+
+use tracing::{debug, info, trace, warn};
 
 use crate::{
     codec::Payload,
@@ -6,9 +11,9 @@ use crate::{
         events::{Signed, Verified},
         Crypto, DigestHash, Verifiable, H256,
     },
-    event::{OnErasedEvent, ScheduleEvent, SendEventFor, Submit},
+    event::{combinators::journal::Register, OnErasedEvent, SendEventFor, Submit},
     net::{combinators::All, events::Recv, Addr, SendMessage},
-    timer::Timer,
+    timer::{self, Timer},
     workload::App,
 };
 
@@ -19,6 +24,17 @@ use super::{
     PublicParameters,
 };
 
+// faulty peers can send invalid signatures at any rate, so each of them is only logged at debug
+// level, and the number of them so far (of all replicas in the process) is warned on powers of two
+static NUM_INVALID_SIGNATURE: AtomicU64 = AtomicU64::new(0);
+
+fn count_invalid_signature() {
+    let count = NUM_INVALID_SIGNATURE.fetch_add(1, Relaxed) + 1;
+    if count.is_power_of_two() {
+        warn!(count, "invalid signatures")
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct State<S, A> {
     id: u8,
@@ -87,8 +103,12 @@ impl<S, A> State<S, A> {
             id,
             app,
 
-            do_view_change_timer: Timer::new(config.view_change_delay),
-            progress_view_change_timer: Timer::new(config.progress_view_change_interval),
+            do_view_change_timer: Timer::new_once(config.view_change_delay),
+            progress_view_change_timer: Timer::with_backoff(
+                config.progress_view_change_interval,
+                config.progress_view_change_backoff.clone(),
+                id as _,
+            ),
             config,
 
             replies,
@@ -107,16 +127,18 @@ impl<S, A> State<S, A> {
 }
 
 pub mod events {
-    #[derive(Debug, Clone)]
+    use serde::{Deserialize, Serialize};
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct DoViewChange(pub u32);
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct ProgressPrepare(pub u32); // op number
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct ProgressViewChange;
 
-    #[derive(Debug, Clone)]
+    #[derive(Debug, Clone, Serialize, Deserialize)]
     pub struct StateTransfer(pub u32);
 }
 
@@ -125,7 +147,12 @@ pub trait Context<S, A> {
     type DownlinkNet: SendMessage<A, Reply>;
     type CryptoWorker: Submit<Crypto, Self::CryptoContext>;
     type CryptoContext: SendEventFor<S, Self>;
-    type Schedule: Schedule;
+    // `timer::Typed` for typed schedules, or `timer::Erased<_, Self>` for erased ones
+    type ScheduleKind;
+    type Schedule: timer::Schedule<events::ProgressPrepare, Self::ScheduleKind>
+        + timer::Schedule<events::DoViewChange, Self::ScheduleKind>
+        + timer::Schedule<events::ProgressViewChange, Self::ScheduleKind>
+        + timer::Schedule<events::StateTransfer, Self::ScheduleKind>;
     fn peer_net(&mut self) -> &mut Self::PeerNet;
     fn downlink_net(&mut self) -> &mut Self::DownlinkNet;
     fn crypto_worker(&mut self) -> &mut Self::CryptoWorker;
@@ -154,22 +181,6 @@ impl<
 {
 }
 
-pub trait Schedule:
-    ScheduleEvent<events::ProgressPrepare>
-    + ScheduleEvent<events::DoViewChange>
-    + ScheduleEvent<events::ProgressViewChange>
-    + ScheduleEvent<events::StateTransfer>
-{
-}
-impl<
-        T: ScheduleEvent<events::ProgressPrepare>
-            + ScheduleEvent<events::DoViewChange>
-            + ScheduleEvent<events::ProgressViewChange>
-            + ScheduleEvent<events::StateTransfer>,
-    > Schedule for T
-{
-}
-
 trait ContextExt<S, A>: Context<S, A> {
     fn submit_sign<M: DigestHash + Send + 'static>(&mut self, message: M) -> anyhow::Result<()>
     where
@@ -183,6 +194,34 @@ trait ContextExt<S, A>: Context<S, A> {
 }
 impl<C: Context<S, A>, S, A> ContextExt<S, A> for C {}
 
+// every event the replica handles, for recording and replaying replica with
+// `event::combinators::journal`
+pub fn register_events<S: App, A: Addr, C: Context<State<S, A>, A>>(
+    registry: &mut impl Register<State<S, A>, C>,
+) {
+    registry.register::<Recv<Request<A>>>("pbft.recv-request");
+    registry.register::<Recv<(Verifiable<PrePrepare>, Vec<Request<A>>)>>("pbft.recv-pre-prepare");
+    registry.register::<Recv<Verifiable<Prepare>>>("pbft.recv-prepare");
+    registry.register::<Recv<Verifiable<Commit>>>("pbft.recv-commit");
+    registry.register::<Recv<Verifiable<ViewChange>>>("pbft.recv-view-change");
+    registry.register::<Recv<Verifiable<NewView>>>("pbft.recv-new-view");
+    registry.register::<Recv<QueryNewView>>("pbft.recv-query-new-view");
+    registry.register::<(Signed<PrePrepare>, Vec<Request<A>>)>("pbft.signed-pre-prepare");
+    registry.register::<(Verified<PrePrepare>, Vec<Request<A>>)>("pbft.verified-pre-prepare");
+    registry.register::<Signed<Prepare>>("pbft.signed-prepare");
+    registry.register::<Verified<Prepare>>("pbft.verified-prepare");
+    registry.register::<Signed<Commit>>("pbft.signed-commit");
+    registry.register::<Verified<Commit>>("pbft.verified-commit");
+    registry.register::<Signed<ViewChange>>("pbft.signed-view-change");
+    registry.register::<Verified<ViewChange>>("pbft.verified-view-change");
+    registry.register::<Signed<NewView>>("pbft.signed-new-view");
+    registry.register::<Verified<NewView>>("pbft.verified-new-view");
+    registry.register::<events::ProgressPrepare>("pbft.progress-prepare");
+    registry.register::<events::DoViewChange>("pbft.do-view-change");
+    registry.register::<events::ProgressViewChange>("pbft.progress-view-change");
+    registry.register::<events::StateTransfer>("pbft.state-transfer");
+}
+
 impl<S, A> State<S, A> {
     fn is_primary(&self) -> bool {
         (self.view_num as usize % self.config.num_replica) == self.id as usize
@@ -280,7 +319,12 @@ impl<S: App, A: Addr, C: Context<Self, A>> OnErasedEvent<(Signed<PrePrepare>, Ve
         (Signed(pre_prepare), requests): (Signed<PrePrepare>, Vec<Request<A>>),
         context: &mut C,
     ) -> anyhow::Result<()> {
-        // println!("Signed {pre_prepare:?}");
+        trace!(
+            id = self.id,
+            view = pre_prepare.view_num,
+            op_num = pre_prepare.op_num,
+            "signed PrePrepare"
+        );
         if pre_prepare.view_num != self.view_num {
             return Ok(());
         }
@@ -328,7 +372,12 @@ impl<S, A: Addr, C: Context<Self, A>> OnErasedEvent<events::ProgressPrepare, C>
         events::ProgressPrepare(op_num): events::ProgressPrepare,
         context: &mut C,
     ) -> anyhow::Result<()> {
-        // warn!("progress prepared {op_num}");
+        debug!(
+            id = self.id,
+            view = self.view_num,
+            op_num,
+            "resend PrePrepare that has not been prepared"
+        );
         let entry = &self.log[op_num as usize];
         let pre_prepare = entry
             .pre_prepare
@@ -386,6 +435,13 @@ impl<S: App, A: Addr, C: Context<Self, A>>
                 {
                     context.send((Verified(pre_prepare), requests))
                 } else {
+                    count_invalid_signature();
+                    debug!(
+                        replica_id,
+                        view = pre_prepare.view_num,
+                        op_num = pre_prepare.op_num,
+                        "invalid PrePrepare"
+                    );
                     Ok(())
                 }
             }))
@@ -409,7 +465,12 @@ impl<S: App, A: Addr, C: Context<Self, A>> OnErasedEvent<(Verified<PrePrepare>,
         }
         if let Some(prepared) = &self.log[pre_prepare.op_num as usize].pre_prepare {
             if **prepared != *pre_prepare {
-                // println!("! PrePrepare not match the prepared one");
+                warn!(
+                    id = self.id,
+                    view = self.view_num,
+                    op_num = pre_prepare.op_num,
+                    "PrePrepare not match the prepared one"
+                );
                 return Ok(());
             }
         }
@@ -523,6 +584,13 @@ impl<S: App, A: Addr> State<S, A> {
                 if crypto.verify(prepare.replica_id, &prepare).is_ok() {
                     context.send(Verified(prepare))
                 } else {
+                    count_invalid_signature();
+                    debug!(
+                        replica_id = prepare.replica_id,
+                        view = prepare.view_num,
+                        op_num = prepare.op_num,
+                        "invalid Prepare"
+                    );
                     Ok(())
                 }
             }))?;
@@ -566,12 +634,14 @@ impl<S: App, A: Addr> State<S, A> {
     ) -> anyhow::Result<()> {
         let prepare_quorum = self.prepare_quorums.entry(prepare.op_num).or_default();
         prepare_quorum.insert(prepare.replica_id, prepare.clone());
-        // println!(
-        //     "{} PrePrepare {} Prepare {}",
-        //     prepare.op_num,
-        //     self.log.get(prepare.op_num as usize).is_some(),
-        //     prepare_quorum.len()
-        // );
+        trace!(
+            id = self.id,
+            view = self.view_num,
+            op_num = prepare.op_num,
+            pre_prepared = self.log.get(prepare.op_num as usize).is_some(),
+            num_prepare = prepare_quorum.len(),
+            "insert Prepare"
+        );
         if prepare_quorum.len() + 1 < self.config.num_replica - self.config.num_faulty {
             return Ok(());
         }
@@ -662,6 +732,13 @@ impl<S: App, A: Addr> State<S, A> {
                 if crypto.verify(commit.replica_id, &commit).is_ok() {
                     context.send(Verified(commit))
                 } else {
+                    count_invalid_signature();
+                    debug!(
+                        replica_id = commit.replica_id,
+                        view = commit.view_num,
+                        op_num = commit.op_num,
+                        "invalid Commit"
+                    );
                     Ok(())
                 }
             }))?;
@@ -705,13 +782,14 @@ impl<S: App, A: Addr> State<S, A> {
     ) -> anyhow::Result<()> {
         let commit_quorum = self.commit_quorums.entry(commit.op_num).or_default();
         commit_quorum.insert(commit.replica_id, commit.clone());
-        // println!(
-        //     "[{}] {} PrePrepare {} Commit {}",
-        //     self.id,
-        //     commit.op_num,
-        //     self.log.get(commit.op_num as usize).is_some(),
-        //     commit_quorum.len()
-        // );
+        trace!(
+            id = self.id,
+            view = self.view_num,
+            op_num = commit.op_num,
+            pre_prepared = self.log.get(commit.op_num as usize).is_some(),
+            num_commit = commit_quorum.len(),
+            "insert Commit"
+        );
 
         if commit_quorum.len() < self.config.num_replica - self.config.num_faulty {
             return Ok(());
@@ -727,7 +805,12 @@ impl<S: App, A: Addr> State<S, A> {
 
         log_entry.commits = self.commit_quorums.remove(&commit.op_num).unwrap();
         self.pending_commits.remove(&commit.op_num);
-        // println!("[{}] Commit {}", self.id, commit.op_num);
+        debug!(
+            id = self.id,
+            view = self.view_num,
+            op_num = commit.op_num,
+            "committed"
+        );
         if is_primary {
             log_entry.progress_timer.unset(context.schedule())?;
         } else {
@@ -743,13 +826,23 @@ impl<S: App, A: Addr> State<S, A> {
                 break;
             }
             self.commit_num += 1;
-            // println!("[{}] Execute {}", self.id, self.commit_num);
+            trace!(
+                id = self.id,
+                view = self.view_num,
+                op_num = self.commit_num,
+                "execute"
+            );
             log_entry
                 .state_transfer_timer
                 .ensure_unset(context.schedule())?;
 
             for request in &log_entry.requests {
-                // println!("Execute {request:?}");
+                trace!(
+                    id = self.id,
+                    client_id = request.client_id,
+                    seq = request.seq,
+                    "execute request"
+                );
                 let reply = Reply {
                     seq: request.seq,
                     result: Payload(self.app.execute(&request.op)?),
@@ -823,12 +916,15 @@ impl<S: App, A: Addr, C: Context<Self, A>> OnErasedEvent<events::DoViewChange, C
         events::DoViewChange(view_num): events::DoViewChange,
         context: &mut C,
     ) -> anyhow::Result<()> {
-        // warn!("[{}] do view change for view {view_num}", self.id);
+        warn!(
+            id = self.id,
+            view = self.view_num,
+            op_num = self.op_num(),
+            "do view change into view {view_num}"
+        );
         assert!(view_num >= self.view_num);
         self.view_num = view_num;
-        // let DoViewChange(also_view_num) =
-        self.do_view_change_timer.unset(context.schedule())?;
-        // anyhow::ensure!(also_view_num == view_num);
+        // the one-shot `do_view_change_timer` has become unset by firing
         self.progress_view_change_timer
             .ensure_set(events::ProgressViewChange, context.schedule())?;
         // self.progress_view_change_timer.reset(timer)?; // not really necessary just feels more correct :)
@@ -844,6 +940,8 @@ impl<S: App, A: Addr, C: Context<Self, A>> OnErasedEvent<events::ProgressViewCha
         events::ProgressViewChange: events::ProgressViewChange,
         context: &mut C,
     ) -> anyhow::Result<()> {
+        self.progress_view_change_timer
+            .retry(events::ProgressViewChange, context.schedule())?;
         self.do_view_change(context)
     }
 }
@@ -921,6 +1019,12 @@ impl<S: App, A: Addr, C: Context<Self, A>> OnErasedEvent<Recv<Verifiable<ViewCha
                 if verify_view_change(crypto, &view_change, num_replica, num_faulty).is_ok() {
                     context.send(Verified(view_change))
                 } else {
+                    count_invalid_signature();
+                    debug!(
+                        replica_id = view_change.replica_id,
+                        view = view_change.view_num,
+                        "invalid ViewChange"
+                    );
                     Ok(())
                 }
             }))
@@ -999,12 +1103,13 @@ impl<S: App, A: Addr> State<S, A> {
         }
 
         view_change_quorum.insert(view_change.replica_id, view_change.clone());
-        // println!(
-        //     "[{}] view {} ViewChange {}",
-        //     self.id,
-        //     view_change.view_num,
-        //     quorum.len()
-        // );
+        debug!(
+            id = self.id,
+            view = self.view_num,
+            num_view_change = view_change_quorum.len(),
+            "ViewChange of view {}",
+            view_change.view_num
+        );
         if view_change_quorum.len() == self.config.num_replica - self.config.num_faulty {
             // it is possible that i'm working on view change into view v while collecting a
             // majority that working on view change into view v' > v
@@ -1059,6 +1164,12 @@ impl<S: App, A: Addr> State<S, A> {
     ) -> anyhow::Result<()> {
         assert!(!self.have_entered(new_view.view_num));
         self.view_num = new_view.view_num;
+        info!(
+            id = self.id,
+            view = self.view_num,
+            op_num = self.op_num(),
+            "enter view"
+        );
         assert!(self.view_change());
         for pre_prepare in &new_view.pre_prepares {
             // somehow duplicating `impl OnErasedEvent<(Verified<PrePrepare>, Vec<Request<M::A>>)>`
@@ -1087,7 +1198,12 @@ impl<S: App, A: Addr> State<S, A> {
                     context.schedule(),
                 )?
             } else {
-                // println!("[{}] Redo Prepare {}", self.id, pre_prepare.op_num);
+                debug!(
+                    id = self.id,
+                    view = self.view_num,
+                    op_num = pre_prepare.op_num,
+                    "redo Prepare"
+                );
                 let prepare = Prepare {
                     view_num: self.view_num,
                     op_num: pre_prepare.op_num,
@@ -1159,8 +1275,12 @@ impl<S: App, A: Addr, C: Context<Self, A>> OnErasedEvent<Recv<Verifiable<NewView
                     }
                     anyhow::Ok(())
                 };
-                if do_verify().is_ok() {
-                    context.send(Verified(new_view))?
+                match do_verify() {
+                    Ok(()) => context.send(Verified(new_view))?,
+                    Err(err) => {
+                        count_invalid_signature();
+                        debug!(view = new_view.view_num, "invalid NewView: {err}")
+                    }
                 }
                 Ok(())
             }))
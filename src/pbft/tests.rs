@@ -264,12 +264,16 @@ pub struct ReplicaContext<'a, N, T> {
 impl<'a, N, T> replica::Context<ReplicaState, Addr> for ReplicaContext<'a, N, T>
 where
     N: PeerNet<Addr> + SendMessage<Addr, Reply>,
-    T: replica::Schedule,
+    T: ScheduleEvent<replica::events::ProgressPrepare>
+        + ScheduleEvent<replica::events::DoViewChange>
+        + ScheduleEvent<replica::events::ProgressViewChange>
+        + ScheduleEvent<replica::events::StateTransfer>,
 {
     type PeerNet = N;
     type DownlinkNet = N;
     type CryptoWorker = Transient<Work<Crypto, Self::CryptoContext>>;
     type CryptoContext = EraseTransient<ReplicaState, Self>;
+    type ScheduleKind = crate::timer::Typed;
     type Schedule = T;
     fn peer_net(&mut self) -> &mut Self::PeerNet {
         &mut self.net
@@ -484,9 +488,25 @@ mod simulate {
                 .set(period, super::Event::Timer(self.addr, (), event.into()))
         }
 
+        fn set_once(
+            &mut self,
+            delay: std::time::Duration,
+            event: M,
+        ) -> anyhow::Result<crate::event::ActiveTimer>
+        where
+            M: Send + Clone + 'static,
+        {
+            self.temporal
+                .set_once(delay, super::Event::Timer(self.addr, (), event.into()))
+        }
+
         fn unset(&mut self, id: crate::event::ActiveTimer) -> anyhow::Result<()> {
             self.temporal.unset(id)
         }
+
+        fn is_set(&self, id: &crate::event::ActiveTimer) -> bool {
+            self.temporal.is_set(id)
+        }
     }
 
     impl<W: Workload<Op = Bytes, Result = Bytes>, N> State<W, N>
@@ -545,3 +565,239 @@ mod simulate {
         }
     }
 }
+
+mod fuzz {
+    use std::{
+        collections::BTreeMap,
+        sync::{atomic::Ordering::SeqCst, Arc},
+        time::Duration,
+    };
+
+    use arbtest::{
+        arbitrary::{self, Unstructured},
+        arbtest,
+    };
+    use bytes::Bytes;
+
+    use crate::{
+        codec::{envelope, fuzz, on_malformed, DecodeStats, ErrorPolicy, Malformed, Payload},
+        crypto::{Crypto, CryptoFlavor, Verifiable, H256},
+        pbft::{
+            messages::{
+                codec::{to_replica_decode, Limits, ToReplica, TO_REPLICA},
+                Commit, NewView, PrePrepare, Prepare, QueryNewView, Request, ViewChange,
+            },
+            PublicParameters,
+        },
+    };
+
+    use super::Addr;
+
+    // every kind of message within the limits of 4 replicas and batches of 10, so the valid ones
+    // always decode, and their corrupted variants reach deep into the decoding of each kind
+    struct Seeds<'a, 'b>(&'a mut Unstructured<'b>, &'a Crypto);
+
+    impl Seeds<'_, '_> {
+        fn request(&mut self) -> arbitrary::Result<Request<Addr>> {
+            Ok(Request {
+                seq: self.0.arbitrary()?,
+                op: Payload(Bytes::from(self.0.arbitrary::<Vec<u8>>()?)),
+                client_id: self.0.arbitrary()?,
+                client_addr: Addr::Client(self.0.arbitrary()?),
+            })
+        }
+
+        fn digest(&mut self) -> arbitrary::Result<H256> {
+            Ok(H256(self.0.arbitrary()?))
+        }
+
+        fn pre_prepare(&mut self) -> arbitrary::Result<Verifiable<PrePrepare>> {
+            Ok(self.1.sign(PrePrepare {
+                view_num: self.0.arbitrary()?,
+                op_num: self.0.arbitrary()?,
+                digest: self.digest()?,
+            }))
+        }
+
+        fn prepare(&mut self, replica_id: u8) -> arbitrary::Result<Verifiable<Prepare>> {
+            Ok(self.1.sign(Prepare {
+                view_num: self.0.arbitrary()?,
+                op_num: self.0.arbitrary()?,
+                digest: self.digest()?,
+                replica_id,
+            }))
+        }
+
+        fn view_change(&mut self) -> arbitrary::Result<Verifiable<ViewChange>> {
+            let mut log = Vec::new();
+            for _ in 0..self.0.int_in_range(0..=3)? {
+                let pre_prepare = self.pre_prepare()?;
+                let mut prepares = BTreeMap::new();
+                for replica_id in 0..self.0.int_in_range(0..=4)? {
+                    prepares.insert(replica_id, self.prepare(replica_id)?);
+                }
+                log.push((pre_prepare, prepares))
+            }
+            Ok(self.1.sign(ViewChange {
+                view_num: self.0.arbitrary()?,
+                log,
+                replica_id: self.0.int_in_range(0..=3)?,
+            }))
+        }
+
+        fn message(&mut self) -> arbitrary::Result<ToReplica<Addr>> {
+            let message = match self.0.int_in_range(0..=6)? {
+                0 => ToReplica::Request(self.request()?),
+                1 => {
+                    let pre_prepare = self.pre_prepare()?;
+                    let mut requests = Vec::new();
+                    for _ in 0..self.0.int_in_range(0..=10)? {
+                        requests.push(self.request()?)
+                    }
+                    ToReplica::PrePrepare(pre_prepare, requests)
+                }
+                2 => {
+                    let replica_id = self.0.arbitrary()?;
+                    ToReplica::Prepare(self.prepare(replica_id)?)
+                }
+                3 => ToReplica::Commit(self.1.sign(Commit {
+                    view_num: self.0.arbitrary()?,
+                    op_num: self.0.arbitrary()?,
+                    digest: self.digest()?,
+                    replica_id: self.0.arbitrary()?,
+                })),
+                4 => ToReplica::ViewChange(self.view_change()?),
+                5 => {
+                    let mut view_changes = BTreeMap::new();
+                    for replica_id in 0..self.0.int_in_range(0..=4)? {
+                        view_changes.insert(replica_id, self.view_change()?);
+                    }
+                    let mut pre_prepares = Vec::new();
+                    for _ in 0..self.0.int_in_range(0..=3)? {
+                        pre_prepares.push(self.pre_prepare()?)
+                    }
+                    ToReplica::NewView(self.1.sign(NewView {
+                        view_num: self.0.arbitrary()?,
+                        view_changes,
+                        pre_prepares,
+                    }))
+                }
+                _ => ToReplica::QueryNewView(QueryNewView {
+                    view_num: self.0.arbitrary()?,
+                    replica_id: self.0.arbitrary()?,
+                }),
+            };
+            Ok(message)
+        }
+    }
+
+    #[test]
+    fn to_replica_decode_garbage() {
+        let limits = Limits::new(&PublicParameters {
+            num_replica: 4,
+            num_faulty: 1,
+            max_batch_size: 10,
+            ..PublicParameters::durations(Duration::from_millis(100))
+        });
+        let crypto = Crypto::new_hardcoded(4, 0usize, CryptoFlavor::Plain).unwrap();
+        let stats = Arc::<DecodeStats>::default();
+        arbtest(|u| {
+            let message = Seeds(u, &crypto).message()?;
+            let valid = envelope::encode(&TO_REPLICA, &message).unwrap();
+            let buf = fuzz::input(u, &TO_REPLICA, &valid)?;
+            let mut sink = fuzz::Sink::default();
+            on_malformed(
+                ErrorPolicy::Drop,
+                stats.clone(),
+                to_replica_decode(limits, &mut sink),
+            )(Addr::Client(0), buf)
+            .unwrap();
+            assert!(sink.0 <= 1);
+            to_replica_decode(limits, &mut sink)(Addr::Client(0), valid).unwrap();
+            Ok(())
+        });
+        assert!(stats.malformed.load(SeqCst) > 0)
+    }
+
+    #[test]
+    fn to_replica_decode_limits() -> anyhow::Result<()> {
+        let limits = Limits::new(&PublicParameters {
+            num_replica: 4,
+            num_faulty: 1,
+            max_batch_size: 2,
+            ..PublicParameters::durations(Duration::from_millis(100))
+        });
+        let crypto = Crypto::new_hardcoded(4, 0usize, CryptoFlavor::Plain)?;
+        let decode = |message: ToReplica<Addr>| {
+            let mut sink = fuzz::Sink::default();
+            let buf = envelope::encode(&TO_REPLICA, &message)?;
+            to_replica_decode(limits, &mut sink)(Addr::Client(0), buf)?;
+            anyhow::ensure!(sink.0 == 1);
+            anyhow::Ok(())
+        };
+        let over_limit = |message| decode(message).is_err_and(|err| err.is::<Malformed>());
+
+        let request = |seq| Request {
+            seq,
+            op: Payload(Bytes::new()),
+            client_id: 0,
+            client_addr: Addr::Client(0),
+        };
+        let pre_prepare = crypto.sign(PrePrepare {
+            view_num: 0,
+            op_num: 1,
+            digest: Default::default(),
+        });
+        decode(ToReplica::PrePrepare(
+            pre_prepare.clone(),
+            (1..=2).map(request).collect(),
+        ))?;
+        anyhow::ensure!(over_limit(ToReplica::PrePrepare(
+            pre_prepare.clone(),
+            (1..=3).map(request).collect(),
+        )));
+
+        let view_change = |num_prepare| {
+            let prepares = (0..num_prepare)
+                .map(|replica_id| {
+                    let prepare = Prepare {
+                        view_num: 0,
+                        op_num: 1,
+                        digest: Default::default(),
+                        replica_id,
+                    };
+                    (replica_id, crypto.sign(prepare))
+                })
+                .collect();
+            crypto.sign(ViewChange {
+                view_num: 1,
+                log: vec![(pre_prepare.clone(), prepares)],
+                replica_id: 0,
+            })
+        };
+        decode(ToReplica::ViewChange(view_change(4)))?;
+        anyhow::ensure!(over_limit(ToReplica::ViewChange(view_change(5))));
+
+        let new_view = |view_changes: Vec<_>| {
+            crypto.sign(NewView {
+                view_num: 1,
+                view_changes: view_changes
+                    .into_iter()
+                    .enumerate()
+                    .map(|(id, view_change)| (id as _, view_change))
+                    .collect(),
+                pre_prepares: vec![pre_prepare.clone()],
+            })
+        };
+        decode(ToReplica::NewView(new_view(vec![view_change(4); 4])))?;
+        anyhow::ensure!(over_limit(ToReplica::NewView(new_view(vec![
+            view_change(4);
+            5
+        ]))));
+        // the limits apply to the view changes nested in the new view as well
+        anyhow::ensure!(over_limit(ToReplica::NewView(new_view(vec![view_change(
+            5
+        )]))));
+        Ok(())
+    }
+}
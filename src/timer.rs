@@ -1,36 +1,202 @@
-use std::{marker::PhantomData, time::Duration};
+use std::{
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    time::Duration,
+};
 
 use derive_where::derive_where;
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
 
-use crate::event::{ScheduleEvent, ActiveTimer};
+use crate::event::{ActiveTimer, OnErasedEvent, ScheduleEvent, ScheduleEventFor};
 
 #[derive_where(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Timer<M> {
     id: Option<ActiveTimer>,
     period: Duration,
+    mode: Mode,
     _m: PhantomData<M>,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Mode {
+    Periodic,
+    Once,
+    Backoff {
+        policy: Backoff,
+        delay: Duration,
+        // seeded per timer, and cloned along with the owning state, so the delays are
+        // deterministic for model checking as long as the seed is
+        rng: Generator,
+    },
+}
+
+// ignored when comparing and hashing alike, the states are told apart by the delays drawn so far
+#[derive(Debug, Clone)]
+struct Generator(Box<StdRng>);
+
+impl PartialEq for Generator {
+    fn eq(&self, _: &Self) -> bool {
+        true
+    }
+}
+
+impl Eq for Generator {}
+
+impl Hash for Generator {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+// how the delay grows between retries, starting from the `period` of the timer
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Backoff {
+    Fixed,
+    // double the delay on every retry
+    Exponential { max: Duration },
+    // the "decorrelated jitter" i.e. the next delay is uniformly chosen between the period and
+    // three times of the previous delay. desynchronizes the retries of different timer owners
+    DecorrelatedJitter { max: Duration },
+}
+
+// the schedules that `Timer` works with, i.e. both `ScheduleEvent<M>` and `ScheduleEventFor<S, C>`
+// with `S: OnErasedEvent<M, C>`. the kind `K` tells the two apart so the blanket impls do not
+// overlap, and it is always inferred at call sites as long as the schedule type is only known to be
+// one of them, which is the case inside generic protocol code
+pub trait Schedule<M, K> {
+    fn set(&mut self, period: Duration, event: M) -> anyhow::Result<ActiveTimer>;
+
+    fn set_once(&mut self, delay: Duration, event: M) -> anyhow::Result<ActiveTimer>;
+
+    fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()>;
+
+    fn is_set(&self, id: &ActiveTimer) -> bool;
+}
+
+pub struct Typed;
+
+#[allow(clippy::type_complexity)]
+pub struct Erased<S, C: ?Sized>(PhantomData<(fn(S), fn(&C))>);
+
+impl<T: ScheduleEvent<M>, M: Clone + Send + 'static> Schedule<M, Typed> for T {
+    fn set(&mut self, period: Duration, event: M) -> anyhow::Result<ActiveTimer> {
+        ScheduleEvent::set(self, period, event)
+    }
+
+    fn set_once(&mut self, delay: Duration, event: M) -> anyhow::Result<ActiveTimer> {
+        ScheduleEvent::set_once(self, delay, event)
+    }
+
+    fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()> {
+        ScheduleEvent::unset(self, id)
+    }
+
+    fn is_set(&self, id: &ActiveTimer) -> bool {
+        ScheduleEvent::is_set(self, id)
+    }
+}
+
+impl<T: ScheduleEventFor<S, C>, S: OnErasedEvent<M, C>, C, M: Clone + Send + 'static>
+    Schedule<M, Erased<S, C>> for T
+{
+    fn set(&mut self, period: Duration, event: M) -> anyhow::Result<ActiveTimer> {
+        ScheduleEventFor::set(self, period, event)
+    }
+
+    fn set_once(&mut self, delay: Duration, event: M) -> anyhow::Result<ActiveTimer> {
+        ScheduleEventFor::set_once(self, delay, event)
+    }
+
+    fn unset(&mut self, id: ActiveTimer) -> anyhow::Result<()> {
+        ScheduleEventFor::unset(self, id)
+    }
+
+    fn is_set(&self, id: &ActiveTimer) -> bool {
+        ScheduleEventFor::is_set(self, id)
+    }
+}
+
 impl<M> Timer<M> {
     pub fn new(period: Duration) -> Self {
         Self {
             period,
             id: None,
+            mode: Mode::Periodic,
             _m: PhantomData,
         }
     }
 
-    // TODO support ScheduleEventFor
-    pub fn set(&mut self, event: M, context: &mut impl ScheduleEvent<M>) -> anyhow::Result<()>
-    where
-        M: Clone + Send + 'static,
-    {
-        let replaced = self.id.replace(context.set(self.period, event)?);
+    // the timer fires at most once per `set`, with `period` as the delay, and becomes unset by
+    // then, so it can be set again
+    pub fn new_once(delay: Duration) -> Self {
+        Self {
+            mode: Mode::Once,
+            ..Self::new(delay)
+        }
+    }
+
+    // the timer fires once per `set` or `retry`. the first delay is `period`, and the following
+    // ones are decided by `policy`. the owner may `retry` when handling the event, otherwise the
+    // timer becomes unset after firing
+    pub fn with_backoff(period: Duration, policy: Backoff, seed: u64) -> Self {
+        Self {
+            mode: Mode::Backoff {
+                policy,
+                delay: period,
+                rng: Generator(Box::new(StdRng::seed_from_u64(seed))),
+            },
+            ..Self::new(period)
+        }
+    }
+
+    // a one-shot (or backoff) timer is unset by the schedule on firing, which is only observed by
+    // the owner of the id i.e. here, so the fired id is forgotten before every operation
+    fn forget_fired<K>(&mut self, context: &impl Schedule<M, K>) {
+        if self.mode != Mode::Periodic && matches!(&self.id, Some(id) if !context.is_set(id)) {
+            self.id = None
+        }
+    }
+
+    pub fn set<K>(&mut self, event: M, context: &mut impl Schedule<M, K>) -> anyhow::Result<()> {
+        self.forget_fired(context);
+        let id = match &mut self.mode {
+            Mode::Periodic => context.set(self.period, event)?,
+            Mode::Once => context.set_once(self.period, event)?,
+            Mode::Backoff { delay, .. } => {
+                *delay = self.period;
+                context.set_once(self.period, event)?
+            }
+        };
+        let replaced = self.id.replace(id);
         anyhow::ensure!(replaced.is_none());
         Ok(())
     }
 
-    pub fn unset(&mut self, context: &mut impl ScheduleEvent<M>) -> anyhow::Result<()> {
+    // re-arm the fired backoff timer with the next delay
+    pub fn retry<K>(&mut self, event: M, context: &mut impl Schedule<M, K>) -> anyhow::Result<()> {
+        self.forget_fired(context);
+        anyhow::ensure!(self.id.is_none(), "retry on timer that has not fired");
+        let Mode::Backoff { policy, delay, rng } = &mut self.mode else {
+            anyhow::bail!("retry on timer without backoff")
+        };
+        *delay = match policy {
+            Backoff::Fixed => self.period,
+            Backoff::Exponential { max } => (*delay * 2).min(*max),
+            Backoff::DecorrelatedJitter { max } => {
+                let upper = (*delay * 3).max(self.period);
+                rng.0.gen_range(self.period..=upper).min(*max)
+            }
+        };
+        self.id = Some(context.set_once(*delay, event)?);
+        Ok(())
+    }
+
+    // a fired one-shot timer is already unset, and unsetting it again is a no-op
+    pub fn unset<K>(&mut self, context: &mut impl Schedule<M, K>) -> anyhow::Result<()> {
+        if self.mode != Mode::Periodic && self.id.is_some() {
+            self.forget_fired(context);
+            if self.id.is_none() {
+                return Ok(());
+            }
+        }
         context.unset(
             self.id
                 .take()
@@ -38,24 +204,187 @@ impl<M> Timer<M> {
         )
     }
 
-    pub fn ensure_set(
+    pub fn is_set<K>(&self, context: &impl Schedule<M, K>) -> bool {
+        matches!(&self.id, Some(id) if self.mode == Mode::Periodic || context.is_set(id))
+    }
+
+    pub fn ensure_set<K>(
         &mut self,
         event: M,
-        context: &mut impl ScheduleEvent<M>,
-    ) -> anyhow::Result<()>
-    where
-        M: Clone + Send + 'static,
-    {
+        context: &mut impl Schedule<M, K>,
+    ) -> anyhow::Result<()> {
+        self.forget_fired(context);
         if self.id.is_none() {
             self.set(event, context)?
         }
         Ok(())
     }
 
-    pub fn ensure_unset(&mut self, context: &mut impl ScheduleEvent<M>) -> anyhow::Result<()> {
+    pub fn ensure_unset<K>(&mut self, context: &mut impl Schedule<M, K>) -> anyhow::Result<()> {
+        self.forget_fired(context);
         if self.id.is_some() {
             self.unset(context)?
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{BuildHasher as _, RandomState};
+
+    use crate::{
+        event::{
+            task::{self, run_with_schedule},
+            Erase, Exit, Untyped,
+        },
+        model::search::state,
+    };
+
+    use super::*;
+
+    fn delays(mut timer: Timer<()>) -> anyhow::Result<Vec<Duration>> {
+        let mut schedule = state::Schedule::<()>::new();
+        timer.set((), &mut schedule)?;
+        let mut delays = Vec::new();
+        for _ in 0..8 {
+            let Some((id, ())) = schedule.events().next() else {
+                anyhow::bail!("missing timer event")
+            };
+            schedule.tick(id)?;
+            timer.retry((), &mut schedule)?;
+            let Mode::Backoff { delay, .. } = &timer.mode else {
+                unreachable!()
+            };
+            delays.push(*delay)
+        }
+        Ok(delays)
+    }
+
+    #[test]
+    fn backoff() -> anyhow::Result<()> {
+        let period = Duration::from_millis(100);
+        let max = Duration::from_secs(1);
+        let exponential = delays(Timer::with_backoff(period, Backoff::Exponential { max }, 0))?;
+        anyhow::ensure!(exponential[..3] == [period * 2, period * 4, period * 8]);
+        anyhow::ensure!(exponential[3..].iter().all(|delay| *delay == max));
+
+        let jitter = |seed| {
+            delays(Timer::with_backoff(
+                period,
+                Backoff::DecorrelatedJitter { max },
+                seed,
+            ))
+        };
+        let jitter0 = jitter(0)?;
+        anyhow::ensure!(jitter0 == jitter(0)?);
+        anyhow::ensure!(jitter0 != jitter(1)?);
+        anyhow::ensure!(jitter0.iter().all(|delay| (period..=max).contains(delay)));
+
+        // the generators are not compared, consistently with hashing
+        let timer =
+            |seed| Timer::<()>::with_backoff(period, Backoff::DecorrelatedJitter { max }, seed);
+        let state = RandomState::new();
+        let hash = |timer: &Timer<()>| state.hash_one(timer);
+        anyhow::ensure!(timer(0) == timer(1));
+        anyhow::ensure!(hash(&timer(0)) == hash(&timer(1)));
+        Ok(())
+    }
+
+    #[test]
+    fn once_forgets_fired() -> anyhow::Result<()> {
+        let mut schedule = state::Schedule::<()>::new();
+        let mut timer = Timer::new_once(Duration::from_millis(100));
+        timer.set((), &mut schedule)?;
+        let Some((id, ())) = schedule.events().next() else {
+            anyhow::bail!("missing timer event")
+        };
+        schedule.tick(id)?;
+        // no manual bookkeeping after firing. the timer can be unset (as a no-op) and set again
+        timer.ensure_unset(&mut schedule)?;
+        anyhow::ensure!(timer.id.is_none());
+        timer.set((), &mut schedule)?;
+        let Some((id, ())) = schedule.events().next() else {
+            anyhow::bail!("missing timer event")
+        };
+        schedule.tick(id)?;
+        timer.set((), &mut schedule)?;
+        timer.unset(&mut schedule)?;
+        anyhow::ensure!(schedule.events().next().is_none());
+        Ok(())
+    }
+
+    // a state machine that holds `Timer` with erased schedule, no per-protocol typed timer trait
+    struct Ping {
+        timer: Timer<Tick>,
+        count: usize,
+    }
+
+    #[derive(Clone)]
+    struct Tick;
+
+    trait Context: Sized {
+        type Schedule: ScheduleEventFor<Ping, Self>;
+        fn schedule(&mut self) -> &mut Self::Schedule;
+    }
+
+    impl Ping {
+        fn start(&mut self, context: &mut impl Context) -> anyhow::Result<()> {
+            self.timer.set(Tick, context.schedule())
+        }
+    }
+
+    impl<C: Context> OnErasedEvent<Tick, C> for Ping {
+        fn on_event(&mut self, Tick: Tick, context: &mut C) -> anyhow::Result<()> {
+            self.count += 1;
+            if self.count < 3 {
+                return self.timer.retry(Tick, context.schedule());
+            }
+            anyhow::bail!(Exit)
+        }
+    }
+
+    struct PingContext {
+        schedule: task::erase::ScheduleState<Ping, Self>,
+    }
+
+    impl Context for PingContext {
+        type Schedule = task::erase::ScheduleState<Ping, Self>;
+        fn schedule(&mut self) -> &mut Self::Schedule {
+            &mut self.schedule
+        }
+    }
+
+    #[tokio::test]
+    async fn erased_schedule() -> anyhow::Result<()> {
+        let mut ping = Untyped::new(Ping {
+            timer: Timer::with_backoff(Duration::from_millis(1), Backoff::Fixed, 0),
+            count: 0,
+        });
+        let mut context = PingContext {
+            schedule: Erase::new(task::ScheduleState::new()),
+        };
+        ping.start(&mut context)?;
+        let (_sender, mut receiver) = tokio::sync::mpsc::unbounded_channel();
+        let result = tokio::time::timeout(
+            Duration::from_secs(1),
+            run_with_schedule(
+                &mut ping,
+                &mut context,
+                &mut receiver,
+                |context| &mut context.schedule,
+                &task::Shutdown::new(),
+            ),
+        )
+        .await?;
+        // `Exit` ends the event loop gracefully
+        result?;
+        anyhow::ensure!(
+            ping.count == 3
+                && !ping
+                    .timer
+                    .is_set::<Erased<Ping, PingContext>>(&context.schedule)
+        );
+        Ok(())
+    }
+}
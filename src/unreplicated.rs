@@ -2,14 +2,16 @@ use std::{collections::BTreeMap, time::Duration};
 
 use bytes::Bytes;
 use serde::{Deserialize, Serialize};
+use tracing::warn;
 
 use crate::{
     codec::Payload,
-    event::{ActiveTimer, OnErasedEvent, ScheduleEvent, SendEvent},
+    event::{OnErasedEvent, ScheduleEvent, SendEvent},
     net::{
-        events::{Cast, Recv},
+        events::{Cast, Recv, RecvFrom},
         Addr,
     },
+    timer::{Backoff, Timer},
     workload::{
         events::{Invoke, InvokeOk},
         App,
@@ -36,12 +38,12 @@ pub struct ClientState<A> {
     addr: A,
     seq: u32,
     outstanding: Option<Outstanding>,
+    resend_timer: Timer<client::Resend>,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 struct Outstanding {
     op: Payload,
-    timer: ActiveTimer,
 }
 
 impl<A> ClientState<A> {
@@ -51,6 +53,13 @@ impl<A> ClientState<A> {
             addr,
             seq: 0,
             outstanding: Default::default(),
+            resend_timer: Timer::with_backoff(
+                Duration::from_millis(100),
+                Backoff::DecorrelatedJitter {
+                    max: Duration::from_secs(1),
+                },
+                id as _,
+            ),
         }
     }
 }
@@ -72,13 +81,9 @@ pub trait ClientContext<A> {
 impl<A: Addr, C: ClientContext<A>> OnErasedEvent<Invoke<Bytes>, C> for ClientState<A> {
     fn on_event(&mut self, Invoke(op): Invoke<Bytes>, context: &mut C) -> anyhow::Result<()> {
         self.seq += 1;
-        let replaced = self.outstanding.replace(Outstanding {
-            op: Payload(op),
-            timer: context
-                .schedule()
-                .set(Duration::from_millis(100), client::Resend)?,
-        });
+        let replaced = self.outstanding.replace(Outstanding { op: Payload(op) });
         anyhow::ensure!(replaced.is_none());
+        self.resend_timer.set(client::Resend, context.schedule())?;
         self.send_request(context)
     }
 }
@@ -105,10 +110,10 @@ impl<A, C: ClientContext<A>> OnErasedEvent<Recv<Reply>, C> for ClientState<A> {
         if reply.seq != self.seq {
             return Ok(());
         }
-        let Some(outstanding) = self.outstanding.take() else {
+        if self.outstanding.take().is_none() {
             return Ok(());
-        };
-        context.schedule().unset(outstanding.timer)?;
+        }
+        self.resend_timer.unset(context.schedule())?;
         let Payload(result) = reply.result;
         context.upcall().send(InvokeOk(result))
     }
@@ -116,7 +121,9 @@ impl<A, C: ClientContext<A>> OnErasedEvent<Recv<Reply>, C> for ClientState<A> {
 
 impl<A: Addr, C: ClientContext<A>> OnErasedEvent<client::Resend, C> for ClientState<A> {
     fn on_event(&mut self, client::Resend: client::Resend, context: &mut C) -> anyhow::Result<()> {
-        // TODO log
+        warn!(id = self.id, seq = self.seq, "resend timeout");
+        self.resend_timer
+            .retry(client::Resend, context.schedule())?;
         self.send_request(context)
     }
 }
@@ -141,6 +148,15 @@ pub trait ServerContext<A> {
     fn net(&mut self) -> &mut Self::Net;
 }
 
+// for running the server with `event::typed` instead of `Untyped`
+crate::event_enum! {
+    #[derive(Debug)]
+    pub enum ServerEvent<A> {
+        Recv(Recv<Request<A>>),
+        RecvFrom(RecvFrom<A, Request<A>>),
+    }
+}
+
 impl<S: App, A, C: ServerContext<A>> OnErasedEvent<Recv<Request<A>>, C> for ServerState<S> {
     fn on_event(&mut self, Recv(request): Recv<Request<A>>, context: &mut C) -> anyhow::Result<()> {
         match self.replies.get(&request.client_id) {
@@ -159,29 +175,68 @@ impl<S: App, A, C: ServerContext<A>> OnErasedEvent<Recv<Request<A>>, C> for Serv
     }
 }
 
+// reply to where the request actually comes from instead of the self-reported `client_addr`
+impl<S: App, A, C: ServerContext<A>> OnErasedEvent<RecvFrom<A, Request<A>>, C> for ServerState<S> {
+    fn on_event(
+        &mut self,
+        RecvFrom(remote, mut request): RecvFrom<A, Request<A>>,
+        context: &mut C,
+    ) -> anyhow::Result<()> {
+        request.client_addr = remote;
+        self.on_event(Recv(request), context)
+    }
+}
+
+// the message format `F` of the envelopes is up to the deployment, e.g. `envelope::Cbor` for the
+// tools that are not written in Rust. both sides must agree on it
 pub mod codec {
-    use crate::codec::{bincode, Encode};
+    use crate::codec::{
+        envelope::{self, Format, Protocol},
+        Encode, Limits,
+    };
 
     use super::*;
 
-    pub fn client_encode<A: Addr, N>(net: N) -> Encode<Request<A>, N> {
-        Encode::bincode(net)
+    // the messages are decoded in any `Format`, so the `Payload`s are copied rather than sliced
+    // from the received buffer as in the bincode only decoding of pbft
+    pub const TO_SERVER: Protocol = Protocol::new(0x0101, 1);
+    pub const TO_CLIENT: Protocol = Protocol::new(0x0102, 1);
+
+    pub fn client_encode<F: Format, A: Addr, N>(net: N) -> Encode<Request<A>, N> {
+        Encode::new(
+            |message| envelope::encode_as::<F, _>(&TO_SERVER, message),
+            net,
+        )
     }
 
-    pub fn client_decode<'a>(
+    pub fn client_decode<'a, F: Format, A>(
+        limits: Limits,
         mut sender: impl SendEvent<Recv<Reply>> + 'a,
-    ) -> impl FnMut(&[u8]) -> anyhow::Result<()> + 'a {
-        move |buf| sender.send(Recv(bincode::decode(buf)?))
+    ) -> impl FnMut(A, Bytes) -> anyhow::Result<()> + 'a {
+        move |_, buf| {
+            sender.send(Recv(envelope::decode_as::<F, _>(
+                &TO_CLIENT, &limits, &buf,
+            )?))
+        }
     }
 
-    pub fn server_encode<N>(net: N) -> Encode<Reply, N> {
-        Encode::bincode(net)
+    pub fn server_encode<F: Format, N>(net: N) -> Encode<Reply, N> {
+        Encode::new(
+            |message| envelope::encode_as::<F, _>(&TO_CLIENT, message),
+            net,
+        )
     }
 
-    pub fn server_decode<'a, A: Addr>(
-        mut sender: impl SendEvent<Recv<Request<A>>> + 'a,
-    ) -> impl FnMut(&[u8]) -> anyhow::Result<()> + 'a {
-        move |buf| sender.send(Recv(bincode::decode(buf)?))
+    pub fn server_decode<'a, F: Format, A: Addr>(
+        limits: Limits,
+        mut sender: impl SendEvent<RecvFrom<A, Request<A>>> + 'a,
+    ) -> impl FnMut(A, Bytes) -> anyhow::Result<()> + 'a {
+        move |remote, buf| {
+            sender.send(RecvFrom(
+                remote,
+                envelope::decode_as::<F, _>(&TO_SERVER, &limits, &buf)?,
+            ))
+        }
     }
 }
 
@@ -383,3 +438,156 @@ pub mod model {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::SocketAddr,
+        sync::{atomic::Ordering::SeqCst, Arc},
+    };
+
+    use arbtest::arbtest;
+
+    use crate::{
+        codec::{
+            envelope::{self, Bincode, Cbor, Format, Json, Msgpack},
+            fuzz, on_malformed, DecodeStats, ErrorPolicy, Limits, Rejected,
+        },
+        event::combinators::Transient,
+        net::events::Cast,
+    };
+
+    use super::{codec::*, *};
+
+    fn round_trip<F: Format>() -> anyhow::Result<()> {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 10000));
+        let request = Request {
+            seq: 1,
+            op: Payload(Bytes::from_static(b"op")),
+            client_id: 2,
+            client_addr: remote,
+        };
+        let mut net = Transient::<Cast<SocketAddr, Bytes>>::new();
+        client_encode::<F, _, _>(&mut net).send(Cast(remote, request.clone()))?;
+        server_encode::<F, _>(&mut net).send(Cast(
+            remote,
+            Reply {
+                seq: 1,
+                result: Payload(Bytes::from_static(b"result")),
+            },
+        ))?;
+        let [Cast(_, request_buf), Cast(_, reply_buf)] = &net[..] else {
+            anyhow::bail!("unexpected messages")
+        };
+
+        let mut requests = Transient::<RecvFrom<SocketAddr, Request<SocketAddr>>>::new();
+        server_decode::<F, _>(Limits::default(), &mut requests)(remote, request_buf.clone())?;
+        anyhow::ensure!(matches!(&requests[..], [RecvFrom(_, decoded)] if *decoded == request));
+        let mut replies = Transient::<Recv<Reply>>::new();
+        client_decode::<F, _>(Limits::default(), &mut replies)(remote, reply_buf.clone())?;
+        anyhow::ensure!(matches!(&replies[..], [Recv(reply)] if reply.result[..] == *b"result"));
+
+        // a receiver of another format rejects the message rather than decoding garbage
+        if F::ID != Bincode::ID {
+            let mut decode = server_decode::<Bincode, _>(Limits::default(), &mut requests);
+            let err = decode(remote, request_buf.clone()).unwrap_err();
+            anyhow::ensure!(err.is::<Rejected>())
+        }
+        // the limits apply to every format
+        let small = Limits {
+            max_size: 4,
+            ..Limits::default()
+        };
+        let err =
+            server_decode::<F, _>(small, &mut requests)(remote, request_buf.clone()).unwrap_err();
+        anyhow::ensure!(err.is::<crate::codec::Malformed>());
+        // a valid header followed by a body that is not in the format
+        let mut garbage = request_buf[..envelope::HEADER_LEN].to_vec();
+        garbage[envelope::HEADER_LEN - 4..].copy_from_slice(&3u32.to_le_bytes());
+        garbage.extend_from_slice(&[0xff; 3]);
+        let err = server_decode::<F, _>(Limits::default(), &mut requests)(remote, garbage.into())
+            .unwrap_err();
+        anyhow::ensure!(err.is::<crate::codec::Malformed>());
+        Ok(())
+    }
+
+    #[test]
+    fn formats() -> anyhow::Result<()> {
+        round_trip::<Bincode>()?;
+        round_trip::<Json>()?;
+        round_trip::<Cbor>()?;
+        round_trip::<Msgpack>()
+    }
+
+    #[test]
+    fn reply_to_observed_addr() -> anyhow::Result<()> {
+        struct Context(Transient<Cast<u8, Reply>>);
+        impl ServerContext<u8> for Context {
+            type Net = Transient<Cast<u8, Reply>>;
+            fn net(&mut self) -> &mut Self::Net {
+                &mut self.0
+            }
+        }
+        let mut server = ServerState::new(crate::workload::Null);
+        let mut context = Context(Transient::new());
+        let request = Request {
+            seq: 1,
+            op: Payload(Bytes::from_static(b"op")),
+            client_id: 2,
+            // spoofed, or the client's view of its own address behind a NAT
+            client_addr: 1,
+        };
+        server.on_event(RecvFrom(3, request.clone()), &mut context)?;
+        // the resent request from another address gets the cached reply at the new address
+        server.on_event(RecvFrom(4, request), &mut context)?;
+        anyhow::ensure!(matches!(
+            &context.0[..],
+            [Cast(3, Reply { seq: 1, .. }), Cast(4, Reply { seq: 1, .. })]
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn decode_garbage() {
+        let remote = SocketAddr::from(([127, 0, 0, 1], 10000));
+        let stats = Arc::<DecodeStats>::default();
+        arbtest(|u| {
+            let op = Payload(Bytes::from(u.arbitrary::<Vec<u8>>()?));
+            let mut sink = fuzz::Sink::default();
+            if u.arbitrary()? {
+                let request = Request {
+                    seq: u.arbitrary()?,
+                    op,
+                    client_id: u.arbitrary()?,
+                    client_addr: remote,
+                };
+                let valid = envelope::encode(&TO_SERVER, &request).unwrap();
+                let buf = fuzz::input(u, &TO_SERVER, &valid)?;
+                on_malformed(
+                    ErrorPolicy::Drop,
+                    stats.clone(),
+                    server_decode::<Bincode, _>(Limits::default(), &mut sink),
+                )(remote, buf)
+                .unwrap();
+                server_decode::<Bincode, _>(Limits::default(), &mut sink)(remote, valid).unwrap()
+            } else {
+                let reply = Reply {
+                    seq: u.arbitrary()?,
+                    result: op,
+                };
+                let valid = envelope::encode(&TO_CLIENT, &reply).unwrap();
+                let buf = fuzz::input(u, &TO_CLIENT, &valid)?;
+                on_malformed(
+                    ErrorPolicy::Drop,
+                    stats.clone(),
+                    client_decode::<Bincode, _>(Limits::default(), &mut sink),
+                )(remote, buf)
+                .unwrap();
+                client_decode::<Bincode, _>(Limits::default(), &mut sink)(remote, valid).unwrap()
+            }
+            assert!((1..=2).contains(&sink.0));
+            Ok(())
+        });
+        assert!(stats.malformed.load(SeqCst) > 0)
+    }
+}